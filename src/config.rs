@@ -1,7 +1,10 @@
 use crate::msg::InstantiateMsg;
-use crate::{error::ContractError, msg::Outpost};
+use crate::{
+    error::ContractError,
+    msg::{DenomInfo, FinPair, Outpost, RebalanceParams, RemoteChain},
+};
 use cosmwasm_schema::cw_serde;
-use cosmwasm_std::{Addr, Api, StdResult, Storage};
+use cosmwasm_std::{ensure, Addr, Api, Coin, Decimal, StdResult, Storage, Uint128};
 use cw_storage_plus::Item;
 use serde::{Deserialize, Serialize};
 
@@ -10,6 +13,29 @@ pub struct Config {
     pub owner: Addr,
     pub outposts: Vec<Outpost>,
     pub cw_ica_controller_code_id: u64,
+    /// Seconds a NAV refresh waits for all outposts before it settles with the answers it has
+    pub nav_refresh_timeout: u64,
+    /// Chains withdrawals can be paid out to through Kujira
+    pub remote_chains: Vec<RemoteChain>,
+    /// Whitelisted denoms with their decimals and oracle symbols
+    pub denoms: Vec<DenomInfo>,
+    /// FIN pairs used to swap deposits into the base denom of their outpost
+    pub fin_pairs: Vec<FinPair>,
+    /// Length in seconds of the epochs deposits and withdrawals are batched in, none processes them at once
+    pub epoch_length: Option<u64>,
+    /// Share of the TVL kept idle on Kujira to pay withdrawals at once, none disables the buffer
+    pub buffer_ratio: Option<Decimal>,
+    /// Limits of the rebalancer, none disables it
+    pub rebalance: Option<RebalanceParams>,
+    /// Paid from the bounty pool to the caller of a crank that did any work, none pays nothing
+    pub crank_bounty: Option<Coin>,
+    /// Address besides the owner allowed to retry failed operations
+    pub keeper: Option<Addr>,
+    /// Seconds a failed operation can be retried before it is rolled back, none never rolls back
+    pub operation_expiry: Option<u64>,
+    /// Smallest USD value a deposit or withdrawal may have, so epochs are not flooded with dust
+    /// requests. None accepts any amount.
+    pub min_request_value: Option<Uint128>,
 }
 
 pub const CONFIG: Item<Config> = Item::new("config");
@@ -20,6 +46,17 @@ impl Config {
             owner: msg.owner,
             outposts: msg.outposts,
             cw_ica_controller_code_id: msg.cw_ica_controller_code_id,
+            nav_refresh_timeout: msg.nav_refresh_timeout,
+            remote_chains: msg.remote_chains,
+            denoms: msg.denoms,
+            fin_pairs: msg.fin_pairs,
+            epoch_length: msg.epoch_length,
+            buffer_ratio: msg.buffer_ratio,
+            rebalance: msg.rebalance,
+            crank_bounty: msg.crank_bounty,
+            keeper: msg.keeper,
+            operation_expiry: msg.operation_expiry,
+            min_request_value: msg.min_request_value,
         }
     }
 
@@ -35,6 +72,9 @@ impl Config {
 
     pub fn validate(&self, api: &dyn Api) -> Result<(), ContractError> {
         api.addr_validate(self.owner.as_str())?;
+        if let Some(keeper) = &self.keeper {
+            api.addr_validate(keeper.as_str())?;
+        }
 
         // Validate each contract address in the outposts
         for outpost in &self.outposts {
@@ -42,6 +82,48 @@ impl Config {
             api.addr_validate(&outpost.cw_ica_controller_contract)?;
         }
 
+        let weights = self
+            .outposts
+            .iter()
+            .filter_map(|outpost| outpost.target_weight)
+            .fold(Decimal::zero(), |sum, weight| sum + weight);
+        ensure!(
+            weights <= Decimal::one()
+                && self.outposts.iter().all(|outpost| {
+                    outpost.target_weight.unwrap_or_default() <= Decimal::one()
+                        && outpost.max_exposure.unwrap_or_default() <= Decimal::one()
+                }),
+            ContractError::InvalidWeights {}
+        );
+
+        if let Some(buffer_ratio) = self.buffer_ratio {
+            ensure!(
+                buffer_ratio <= Decimal::one(),
+                ContractError::InvalidBufferRatio {}
+            );
+        }
+
+        for timeouts in self
+            .outposts
+            .iter()
+            .filter_map(|outpost| outpost.ica_timeouts.as_ref())
+        {
+            ensure!(
+                [
+                    timeouts.default,
+                    timeouts.query,
+                    timeouts.lend,
+                    timeouts.withdraw,
+                    timeouts.payout,
+                    timeouts.move_funds,
+                    timeouts.epoch,
+                ]
+                .iter()
+                .all(|timeout| *timeout != Some(0)),
+                ContractError::InvalidTimeout {}
+            );
+        }
+
         Ok(())
     }
 
@@ -58,10 +140,110 @@ impl Config {
             self.cw_ica_controller_code_id = cw_ica_controller_code_id;
         }
 
+        if let Some(nav_refresh_timeout) = msg.nav_refresh_timeout {
+            self.nav_refresh_timeout = nav_refresh_timeout;
+        }
+
+        if let Some(remote_chains) = msg.remote_chains {
+            self.remote_chains = remote_chains;
+        }
+
+        if let Some(denoms) = msg.denoms {
+            self.denoms = denoms;
+        }
+
+        if let Some(fin_pairs) = msg.fin_pairs {
+            self.fin_pairs = fin_pairs;
+        }
+
+        if let Some(epoch_length) = msg.epoch_length {
+            self.epoch_length = Some(epoch_length);
+        }
+        if msg.clear_epoch_length {
+            self.epoch_length = None;
+        }
+
+        if let Some(buffer_ratio) = msg.buffer_ratio {
+            self.buffer_ratio = Some(buffer_ratio);
+        }
+        if msg.clear_buffer_ratio {
+            self.buffer_ratio = None;
+        }
+
+        if let Some(rebalance) = msg.rebalance {
+            self.rebalance = Some(rebalance);
+        }
+        if msg.clear_rebalance {
+            self.rebalance = None;
+        }
+
+        if let Some(crank_bounty) = msg.crank_bounty {
+            self.crank_bounty = Some(crank_bounty);
+        }
+        if msg.clear_crank_bounty {
+            self.crank_bounty = None;
+        }
+
+        if let Some(keeper) = msg.keeper {
+            self.keeper = Some(keeper);
+        }
+        if msg.clear_keeper {
+            self.keeper = None;
+        }
+
+        if let Some(operation_expiry) = msg.operation_expiry {
+            self.operation_expiry = Some(operation_expiry);
+        }
+        if msg.clear_operation_expiry {
+            self.operation_expiry = None;
+        }
+
+        if let Some(min_request_value) = msg.min_request_value {
+            self.min_request_value = Some(min_request_value);
+        }
+        if msg.clear_min_request_value {
+            self.min_request_value = None;
+        }
+
         self.validate(api)?;
         Ok(())
     }
 
+    /// Outposts with a credit account that hold or can receive funds
+    pub fn active_outposts(&self) -> impl Iterator<Item = &Outpost> {
+        self.outposts
+            .iter()
+            .filter(|outpost| outpost.account_id.is_some())
+    }
+
+    pub fn find_denom(&self, denom: &str) -> Result<&DenomInfo, ContractError> {
+        self.denoms
+            .iter()
+            .find(|info| info.denom == denom)
+            .ok_or(ContractError::UnsupportedDenom {
+                denom: denom.to_string(),
+            })
+    }
+
+    /// FIN pair trading `offer` against `ask` in either direction
+    pub fn find_fin_pair(&self, offer: &str, ask: &str) -> Result<&FinPair, ContractError> {
+        self.fin_pairs
+            .iter()
+            .find(|pair| {
+                pair.denoms.contains(&offer.to_string()) && pair.denoms.contains(&ask.to_string())
+            })
+            .ok_or(ContractError::NoSwapRoute {
+                offer: offer.to_string(),
+                ask: ask.to_string(),
+            })
+    }
+
+    pub fn find_remote_chain(&self, chain: &str) -> Option<&RemoteChain> {
+        self.remote_chains
+            .iter()
+            .find(|remote_chain| remote_chain.chain == chain)
+    }
+
     pub fn find_destination_outpost(&self, destination: &str) -> Option<&Outpost> {
         self.outposts
             .iter()
@@ -89,6 +271,18 @@ impl Config {
         Ok(())
     }
 
+    pub fn find_controller_outpost(&self, controller: &str) -> Option<&Outpost> {
+        self.outposts
+            .iter()
+            .find(|outpost| outpost.cw_ica_controller_contract == controller)
+    }
+
+    pub fn find_controller_outpost_mut(&mut self, controller: &str) -> Option<&mut Outpost> {
+        self.outposts
+            .iter_mut()
+            .find(|outpost| outpost.cw_ica_controller_contract == controller)
+    }
+
     pub fn update_outpost_controller(
         &mut self,
         destination: &str,
@@ -111,6 +305,17 @@ impl From<Config> for ConfigResponse {
             owner: config.owner,
             outposts: config.outposts,
             cw_ica_controller_code_id: config.cw_ica_controller_code_id,
+            nav_refresh_timeout: config.nav_refresh_timeout,
+            remote_chains: config.remote_chains,
+            denoms: config.denoms,
+            fin_pairs: config.fin_pairs,
+            epoch_length: config.epoch_length,
+            buffer_ratio: config.buffer_ratio,
+            rebalance: config.rebalance,
+            crank_bounty: config.crank_bounty,
+            keeper: config.keeper,
+            operation_expiry: config.operation_expiry,
+            min_request_value: config.min_request_value,
         }
     }
 }
@@ -120,6 +325,42 @@ pub struct ConfigUpdate {
     pub owner: Option<Addr>,
     pub outposts: Option<Vec<Outpost>>,
     pub cw_ica_controller_code_id: Option<u64>,
+    pub nav_refresh_timeout: Option<u64>,
+    pub remote_chains: Option<Vec<RemoteChain>>,
+    pub denoms: Option<Vec<DenomInfo>>,
+    pub fin_pairs: Option<Vec<FinPair>>,
+    pub epoch_length: Option<u64>,
+    /// Turns epochs off, refused while the open epoch holds deposits or withdrawals.
+    /// Takes precedence over `epoch_length`.
+    #[serde(default)]
+    pub clear_epoch_length: bool,
+    pub buffer_ratio: Option<Decimal>,
+    /// Turns the liquidity buffer off, the funds already in it stay there until it is turned on
+    /// again. Takes precedence over `buffer_ratio`.
+    #[serde(default)]
+    pub clear_buffer_ratio: bool,
+    pub rebalance: Option<RebalanceParams>,
+    /// Turns the rebalancer off, moves in flight still complete. Takes precedence over `rebalance`.
+    #[serde(default)]
+    pub clear_rebalance: bool,
+    pub crank_bounty: Option<Coin>,
+    /// Stops paying crank bounties. Takes precedence over `crank_bounty`.
+    #[serde(default)]
+    pub clear_crank_bounty: bool,
+    pub keeper: Option<Addr>,
+    /// Removes the keeper, leaving the owner alone to retry failed operations.
+    /// Takes precedence over `keeper`.
+    #[serde(default)]
+    pub clear_keeper: bool,
+    pub operation_expiry: Option<u64>,
+    /// Keeps operations that fail from now on retryable until they succeed. Failures already
+    /// recorded keep their deadline. Takes precedence over `operation_expiry`.
+    #[serde(default)]
+    pub clear_operation_expiry: bool,
+    pub min_request_value: Option<Uint128>,
+    /// Accepts deposits and withdrawals of any amount. Takes precedence over `min_request_value`.
+    #[serde(default)]
+    pub clear_min_request_value: bool,
 }
 
 #[cw_serde]
@@ -127,4 +368,15 @@ pub struct ConfigResponse {
     pub owner: Addr,
     pub outposts: Vec<Outpost>,
     pub cw_ica_controller_code_id: u64,
+    pub nav_refresh_timeout: u64,
+    pub remote_chains: Vec<RemoteChain>,
+    pub denoms: Vec<DenomInfo>,
+    pub fin_pairs: Vec<FinPair>,
+    pub epoch_length: Option<u64>,
+    pub buffer_ratio: Option<Decimal>,
+    pub rebalance: Option<RebalanceParams>,
+    pub crank_bounty: Option<Coin>,
+    pub keeper: Option<Addr>,
+    pub operation_expiry: Option<u64>,
+    pub min_request_value: Option<Uint128>,
 }
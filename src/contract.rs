@@ -1,27 +1,45 @@
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
-    ensure, instantiate2_address, to_json_binary, wasm_execute, Binary, Coin, Decimal, Deps,
-    DepsMut, Env, MessageInfo, Response, Uint128, WasmMsg,
+    ensure, instantiate2_address, to_json_binary, Binary, Deps, DepsMut, Env, MessageInfo, Order,
+    Reply, Response, StdResult, WasmMsg,
 };
 use cw2::set_contract_version;
-use cw_ica_controller::types::{callbacks::IcaControllerCallbackMsg, query_msg::IcaQueryResult};
 use cw_utils::nonpayable;
 use kujira::{KujiraMsg, KujiraQuery};
-use mars_types::credit_manager::{Action, ActionCoin, ExecuteMsg as CreditManagerExecuteMsg};
 
 use crate::config::{Config, ConfigResponse};
 use crate::error::ContractError;
 use crate::handler::{
-    channels::{try_close_channel, try_create_channel},
+    buffer::{query_buffer, try_top_up_buffer},
+    callback::ica_callback_execute,
+    channels::{
+        query_channels, try_accept_controller_ownership, try_accept_ica_address, try_close_channel,
+        try_create_channel,
+    },
+    crank::{query_bounty_pool, try_crank, try_fund_bounty_pool},
     create_vault::try_create_vault,
     deposit::try_deposit,
-    ica::{execute_ica, extract_packet_memo},
+    epoch::{
+        ensure_epoch_empty, handle_epoch_reply, receive_epoch, try_process_epoch, EPOCH_REPLY_ID,
+    },
+    in_flight::query_in_flight,
     move_funds::try_move_funds,
+    rebalance::{receive_move, try_rebalance},
+    receive_withdrawal,
+    refresh_nav::try_refresh_nav,
+    refund::{query_pending_refunds, receive_refund, try_retry_refund},
+    request::{query_request, query_requests, try_cancel_request, try_claim},
+    retry::{query_failed_operations, try_retry_operation},
+    simulate::{query_simulate_allocation, query_simulate_rebalance},
+    swap::{handle_swap_reply, SWAP_REPLY_ID},
+    transfer::{handle_transfer_reply, transfer_lifecycle_complete, TRANSFER_REPLY_ID},
     try_withdraw,
+    withdraw::{query_withdrawal_queue, try_process_withdrawal_queue, try_retry_payout},
 };
-use crate::msg::{ExecuteMsg, InstantiateMsg, QueryMsg};
-use crate::state::{self, STATE};
+use crate::helpers::{deposit_owner, ensure_fresh_nav};
+use crate::msg::{ExecuteMsg, InstantiateMsg, QueryMsg, SudoMsg, WithdrawMsg};
+use crate::state::{Epoch, NavResponse, OutpostNavResponse, State, EPOCH, OUTPOST_NAV, STATE};
 
 // version info for migration info
 const CONTRACT_NAME: &str = "mars_controller";
@@ -46,8 +64,9 @@ pub fn instantiate(
     // Update each outpost with its predicted address and create instantiate messages
     for outpost in &mut config.outposts {
         // Initialize the outpost configuration
+        // The controller is owned by this contract, which sends its transactions and reopens its channel
         let controller_init_msg = cw_ica_controller::types::msg::InstantiateMsg {
-            owner: Some(msg.owner.to_string()),
+            owner: Some(env.contract.address.to_string()),
             channel_open_init_options: outpost.channel_open_init_options.clone(),
             send_callbacks_to: Some(env.contract.address.to_string()),
         };
@@ -88,6 +107,8 @@ pub fn instantiate(
     }
 
     config.save(deps.storage, deps.api)?;
+    STATE.save(deps.storage, &State::default())?;
+    EPOCH.save(deps.storage, &Epoch::new(0, env.block.time))?;
 
     Ok(
         Response::new()
@@ -98,8 +119,8 @@ pub fn instantiate(
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn execute(
-    deps: DepsMut<KujiraQuery>,
-    _env: Env,
+    mut deps: DepsMut<KujiraQuery>,
+    env: Env,
     info: MessageInfo,
     msg: ExecuteMsg,
 ) -> Result<Response<KujiraMsg>, ContractError> {
@@ -107,23 +128,153 @@ pub fn execute(
     let mut state = STATE.load(deps.storage)?;
 
     let response = match msg {
-        ExecuteMsg::Deposit(msg) => try_deposit(&config, msg.destination),
+        ExecuteMsg::Deposit(msg) => {
+            ensure_fresh_nav(deps.storage, &config, env.block.time)?;
+            let owner = deposit_owner(deps.as_ref(), &info.sender, msg.beneficiary.clone())?;
+            try_deposit(
+                deps.branch(),
+                &env,
+                &info,
+                &config,
+                &mut state,
+                owner,
+                msg,
+                true,
+            )
+        }
+        ExecuteMsg::RequestDeposit(msg) => {
+            ensure_fresh_nav(deps.storage, &config, env.block.time)?;
+            let owner = deposit_owner(deps.as_ref(), &info.sender, msg.beneficiary.clone())?;
+            try_deposit(
+                deps.branch(),
+                &env,
+                &info,
+                &config,
+                &mut state,
+                owner,
+                msg,
+                false,
+            )
+        }
         ExecuteMsg::Withdraw(msg) => {
             nonpayable(&info)?;
-            try_withdraw(msg)
+            ensure_fresh_nav(deps.storage, &config, env.block.time)?;
+            try_withdraw(deps.branch(), &env, &info, &config, &mut state, msg, true)
+        }
+        ExecuteMsg::RequestRedeem {
+            shares,
+            min_assets_out,
+        } => {
+            nonpayable(&info)?;
+            ensure_fresh_nav(deps.storage, &config, env.block.time)?;
+            let msg = WithdrawMsg {
+                amount: shares,
+                recipient: None,
+                remote_chain: None,
+                min_assets_out,
+            };
+            try_withdraw(deps.branch(), &env, &info, &config, &mut state, msg, false)
+        }
+        ExecuteMsg::Claim { id } => {
+            nonpayable(&info)?;
+            try_claim(deps.storage, &info, id)
+        }
+        ExecuteMsg::CancelRequest { id } => {
+            nonpayable(&info)?;
+            try_cancel_request(deps.storage, &info, id)
+        }
+        ExecuteMsg::ProcessEpoch {} => {
+            nonpayable(&info)?;
+            try_process_epoch(deps.storage, &deps.querier, &env, &config, &mut state)
+        }
+        ExecuteMsg::ReceiveEpoch { epoch, destination } => receive_epoch(
+            deps.storage,
+            deps.api,
+            &env,
+            &info,
+            &config,
+            epoch,
+            destination,
+        ),
+        ExecuteMsg::ProcessWithdrawalQueue { limit } => {
+            nonpayable(&info)?;
+            try_process_withdrawal_queue(
+                deps.storage,
+                &deps.querier,
+                &env,
+                &config,
+                &mut state,
+                limit,
+            )
+        }
+        ExecuteMsg::TopUpBuffer {} => {
+            nonpayable(&info)?;
+            try_top_up_buffer(deps.storage, &deps.querier, &env, &config, &mut state)
+        }
+        ExecuteMsg::Rebalance {} => {
+            nonpayable(&info)?;
+            ensure_fresh_nav(deps.storage, &config, env.block.time)?;
+            try_rebalance(deps.storage, &deps.querier, &env, &config, &mut state)
+        }
+        ExecuteMsg::Crank { limit } => {
+            nonpayable(&info)?;
+            try_crank(
+                deps.storage,
+                &deps.querier,
+                &env,
+                &info,
+                &config,
+                &mut state,
+                limit,
+            )
+        }
+        ExecuteMsg::FundBountyPool {} => try_fund_bounty_pool(deps.storage, &info),
+        ExecuteMsg::RetryOperation {
+            id,
+            timeout_seconds,
+        } => {
+            nonpayable(&info)?;
+            try_retry_operation(deps.storage, &info, &config, id, timeout_seconds)
+        }
+        ExecuteMsg::ReceiveMove { id } => {
+            receive_move(deps.storage, deps.api, &env, &info, &config, id)
+        }
+        ExecuteMsg::ReceiveWithdrawal { id } => {
+            receive_withdrawal(deps.storage, deps.api, &env, &info, &config, id)
+        }
+        ExecuteMsg::ReceiveRefund { id } => {
+            receive_refund(deps.storage, deps.api, &info, &config, id)
+        }
+        ExecuteMsg::RetryRefund { id } => {
+            nonpayable(&info)?;
+            try_retry_refund(deps.storage, &env, &info, &config, id)
+        }
+        ExecuteMsg::RetryPayout { id } => {
+            nonpayable(&info)?;
+            try_retry_payout(deps.storage, &env, &info, &config, &mut state, id)
         }
         ExecuteMsg::CreateVault {} => {
             ensure!(config.owner == info.sender, ContractError::Unauthorized {});
             try_create_vault()
         }
-        ExecuteMsg::CreateChannel {} => {
+        ExecuteMsg::CreateChannel { destination } => {
             ensure!(config.owner == info.sender, ContractError::Unauthorized {});
-            try_create_channel()
+            try_create_channel(deps.storage, &env, &config, destination)
         }
         ExecuteMsg::CloseChannel {} => {
             ensure!(config.owner == info.sender, ContractError::Unauthorized {});
             try_close_channel()
         }
+        ExecuteMsg::AcceptIcaAddress { destination } => {
+            ensure!(config.owner == info.sender, ContractError::Unauthorized {});
+            nonpayable(&info)?;
+            try_accept_ica_address(deps.storage, &mut config, destination)
+        }
+        ExecuteMsg::AcceptControllerOwnership { destination } => {
+            ensure!(config.owner == info.sender, ContractError::Unauthorized {});
+            nonpayable(&info)?;
+            try_accept_controller_ownership(&config, destination)
+        }
         ExecuteMsg::MoveFunds(msg) => {
             ensure!(config.owner == info.sender, ContractError::Unauthorized {});
             // Ensure no funds are sent
@@ -132,114 +283,24 @@ pub fn execute(
             try_move_funds(msg)
         }
         ExecuteMsg::ReceiveIcaCallback(msg) => {
-            match msg {
-                IcaControllerCallbackMsg::OnChannelOpenAckCallback {
-                    channel,
-                    ica_address,
-                    tx_encoding,
-                } => !unimplemented!("OnChannelOpenAckCallback"),
-                IcaControllerCallbackMsg::OnTimeoutPacketCallback {
-                    original_packet,
-                    relayer,
-                } => !unimplemented!("OnTimeoutPacketCallback"),
-                IcaControllerCallbackMsg::OnAcknowledgementPacketCallback {
-                    ica_acknowledgement: _,
-                    original_packet,
-                    relayer: _,
-                    query_result,
-                } => {
-                    // Based on the memo the packet was sent with, we can determine the action to take
-
-                    let packet_memo = extract_packet_memo(&original_packet)?;
-                    if let Some(memo) = packet_memo {
-                        match memo {
-                            memo if memo.starts_with("deposit") => {
-                                let parts: Vec<&str> = memo.split('/').collect();
-
-                                match parts.as_slice() {
-                                    ["deposit", address, denom, amount, destination] => {
-                                        let stable_amount: Uint128 = if let Some(query_result) =
-                                            query_result
-                                        {
-                                            match query_result {
-                                                IcaQueryResult::Success { responses, .. } => {
-                                                    // let response = responses.get(0).unwrap();
-                                                    // let amount = response.amount.clone();
-                                                    // amount
-                                                    Uint128::zero()
-                                                }
-                                                IcaQueryResult::Error(error) => {
-                                                    return Err(ContractError::IcaQueryError {
-                                                        error: error.to_string(),
-                                                    });
-                                                }
-                                            }
-                                        } else {
-                                            Uint128::zero()
-                                        };
-                                        state.total_stables += stable_amount;
-                                        state.deposit_redemption_rate = Decimal::from_ratio(
-                                            state.total_stables,
-                                            state.virtual_receipt,
-                                        );
-
-                                        // create new SendCosmosMsgs to call the red_bank contract
-                                        let coin = Coin {
-                                            denom: denom.to_string(),
-                                            amount: amount
-                                                .parse::<u128>()
-                                                .map(Uint128::new)
-                                                .map_err(|_| ContractError::InvalidAmount {})?,
-                                        };
-                                        let funds = vec![coin];
-
-                                        let outpost = config
-                                            .find_destination_outpost(destination)
-                                            .ok_or(ContractError::DestinationNotFound {
-                                                destination: destination.to_string(),
-                                            })?;
-
-                                        let update_credit_msg = wasm_execute(
-                                            outpost.mars_red_bank_contract.clone(),
-                                            &CreditManagerExecuteMsg::UpdateCreditAccount {
-                                                account_id: None,
-                                                account_kind: None,
-                                                actions: vec![
-                                                    Action::Deposit(coin),
-                                                    Action::Lend(ActionCoin::from(coin)),
-                                                ],
-                                            },
-                                            funds,
-                                        )?
-                                        .into();
-
-                                        let msg = execute_ica(
-                                            outpost.cw_ica_controller_contract.clone(),
-                                            None,
-                                            vec![update_credit_msg],
-                                            vec![],
-                                        )?;
+            // Only the ICA controllers of the configured outposts may report packet results
+            ensure!(
+                config
+                    .outposts
+                    .iter()
+                    .any(|outpost| outpost.cw_ica_controller_contract == info.sender),
+                ContractError::Unauthorized {}
+            );
 
-                                        Ok(Response::new().add_message(msg))
-                                    }
-                                    _ => {
-                                        // Handle invalid format
-                                        return Err(ContractError::InvalidMemoFormat(
-                                            memo.to_string(),
-                                        ));
-                                    }
-                                }
-                            }
-                            memo if memo.starts_with("withdraw") => !unimplemented!("withdraw"),
-                            memo if memo.starts_with("move_funds") => !unimplemented!("move_funds"),
-                            _ => Err(ContractError::UnknownMemo {}),
-                        }
-                        // ica_callback_execute(msg, &mut state, &mut config, deps.api)?;
-                    } else {
-                        Err(ContractError::UnknownMemo {})
-                    }
-                }
-            }
+            ica_callback_execute(
+                deps.storage,
+                &deps.querier,
+                &env,
+                &info.sender,
+                msg,
+                &mut state,
+                &mut config,
+            )
         }
         ExecuteMsg::UpdateConfig(msg) => {
             ensure!(
@@ -247,6 +308,9 @@ pub fn execute(
                 ContractError::Unauthorized {}
             );
             nonpayable(&info)?;
+            if msg.clear_epoch_length {
+                ensure_epoch_empty(deps.storage)?;
+            }
 
             config.apply_update(msg, deps.api)?;
             config.save(deps.storage, deps.api)?;
@@ -254,6 +318,10 @@ pub fn execute(
 
             Ok(Response::new().add_attribute("method", "update_config"))
         }
+        ExecuteMsg::RefreshNav {} => {
+            nonpayable(&info)?;
+            try_refresh_nav(deps.storage, &env, &config, &mut state)
+        }
     }?;
 
     STATE.save(deps.storage, &state)?;
@@ -262,12 +330,84 @@ pub fn execute(
 }
 
 #[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(_deps: Deps<KujiraQuery>, _env: Env, msg: QueryMsg) -> Result<Binary, ContractError> {
-    let config = Config::load(_deps.storage)?;
+pub fn sudo(
+    deps: DepsMut<KujiraQuery>,
+    env: Env,
+    msg: SudoMsg,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let config = Config::load(deps.storage)?;
+
+    match msg {
+        SudoMsg::IBCLifecycleComplete(msg) => {
+            transfer_lifecycle_complete(deps.storage, &env, &config, msg)
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(
+    deps: DepsMut<KujiraQuery>,
+    env: Env,
+    msg: Reply,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    match msg.id {
+        TRANSFER_REPLY_ID => handle_transfer_reply(deps.storage, msg),
+        SWAP_REPLY_ID => handle_swap_reply(deps, &env, msg),
+        EPOCH_REPLY_ID => handle_epoch_reply(msg),
+        id => Err(ContractError::UnknownReply { id }),
+    }
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(deps: Deps<KujiraQuery>, env: Env, msg: QueryMsg) -> Result<Binary, ContractError> {
+    let config = Config::load(deps.storage)?;
     Ok(match msg {
         QueryMsg::Config {} => to_json_binary(&ConfigResponse::from(config)),
+        QueryMsg::Nav {} => to_json_binary(&query_nav(deps, &env)?),
+        QueryMsg::Epoch {} => to_json_binary(&EPOCH.load(deps.storage)?),
+        QueryMsg::Request { owner, id } => to_json_binary(&query_request(deps, owner, id)?),
+        QueryMsg::Requests {
+            owner,
+            start_after,
+            limit,
+        } => to_json_binary(&query_requests(deps, owner, start_after, limit)?),
+        QueryMsg::Buffer {} => to_json_binary(&query_buffer(deps)?),
+        QueryMsg::WithdrawalQueue { start, limit } => {
+            to_json_binary(&query_withdrawal_queue(deps, start, limit)?)
+        }
+        QueryMsg::SimulateAllocation {
+            amount,
+            destination,
+        } => to_json_binary(&query_simulate_allocation(deps, &env, amount, destination)?),
+        QueryMsg::FailedOperations { start_after, limit } => {
+            to_json_binary(&query_failed_operations(deps, start_after, limit)?)
+        }
+        QueryMsg::BountyPool {} => to_json_binary(&query_bounty_pool(deps)?),
+        QueryMsg::PendingRefunds { start_after, limit } => {
+            to_json_binary(&query_pending_refunds(deps, start_after, limit)?)
+        }
+        QueryMsg::InFlight {} => to_json_binary(&query_in_flight(deps)?),
+        QueryMsg::Channels {} => to_json_binary(&query_channels(deps)?),
+        QueryMsg::SimulateRebalance {} => to_json_binary(&query_simulate_rebalance(deps, &env)?),
     }?)
 }
 
+fn query_nav(deps: Deps<KujiraQuery>, env: &Env) -> StdResult<NavResponse> {
+    let state = STATE.load(deps.storage)?;
+    let outposts = OUTPOST_NAV
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            item.map(|(destination, nav)| OutpostNavResponse::new(destination, nav, env.block.time))
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(NavResponse {
+        total_stables: state.total_stables,
+        virtual_receipt: state.virtual_receipt,
+        deposit_redemption_rate: state.deposit_redemption_rate,
+        outposts,
+    })
+}
+
 #[cfg(test)]
 mod tests {}
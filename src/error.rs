@@ -1,4 +1,4 @@
-use cosmwasm_std::StdError;
+use cosmwasm_std::{Decimal, StdError, Timestamp, Uint128};
 use cw_utils::PaymentError;
 use thiserror::Error;
 
@@ -30,6 +30,173 @@ pub enum ContractError {
 
     #[error("Failed Parsing Amount")]
     InvalidAmount {},
+
+    #[error("Expected a payment of {expected}, received {received}")]
+    UnexpectedPayment {
+        expected: Uint128,
+        received: Uint128,
+    },
+
+    #[error("Deposits through an IBC wasm hook must name a beneficiary")]
+    MissingBeneficiary {},
+
+    #[error("Insufficient receipts: requested {requested}, available {available}")]
+    InsufficientReceipts {
+        requested: Uint128,
+        available: Uint128,
+    },
+
+    #[error("No outpost can cover a withdrawal of {amount}")]
+    InsufficientLiquidity { amount: Uint128 },
+
+    #[error("Denom is not whitelisted: {denom}")]
+    UnsupportedDenom { denom: String },
+
+    #[error("No FIN pair to swap {offer} into {ask}")]
+    NoSwapRoute { offer: String, ask: String },
+
+    #[error("Swap returned {returned}, less than the minimum of {min_return}")]
+    MinReturnNotMet {
+        min_return: Uint128,
+        returned: Uint128,
+    },
+
+    #[error("Request worth {value} is below the minimum of {min_request_value}")]
+    RequestTooSmall {
+        value: Uint128,
+        min_request_value: Uint128,
+    },
+
+    #[error("Withdrawal pays out {amount}, less than the minimum of {min_assets_out}")]
+    MinAssetsNotMet {
+        min_assets_out: Uint128,
+        amount: Uint128,
+    },
+
+    #[error("Request not found: {id}")]
+    RequestNotFound { id: u64 },
+
+    #[error("Request {id} is not claimable")]
+    RequestNotClaimable { id: u64 },
+
+    #[error("Request {id} can no longer be cancelled")]
+    RequestNotCancellable { id: u64 },
+
+    #[error("Epochs are not enabled")]
+    EpochsDisabled {},
+
+    #[error("Epoch {id} has not ended yet")]
+    EpochNotEnded { id: u64 },
+
+    #[error("Epoch {id} still holds deposits or withdrawals")]
+    EpochNotEmpty { id: u64 },
+
+    #[error(
+        "Target weights and exposure caps must be at most 1, and the weights must sum to at most 1"
+    )]
+    InvalidWeights {},
+
+    #[error("No outpost can take the deposit within its target weight and exposure cap")]
+    NoAllocation {},
+
+    #[error("Deposit would push {destination} above its maximum exposure of {max_exposure}")]
+    ExposureCapExceeded {
+        destination: String,
+        max_exposure: Decimal,
+    },
+
+    #[error("Buffer ratio must be at most 1")]
+    InvalidBufferRatio {},
+
+    #[error("ICA timeouts must be greater than 0")]
+    InvalidTimeout {},
+
+    #[error("The liquidity buffer is not enabled")]
+    BufferDisabled {},
+
+    #[error("The liquidity buffer is already at its target of {target}")]
+    BufferFull { target: Uint128 },
+
+    #[error("Deposit mints {shares} receipts, less than the minimum of {min_shares_out}")]
+    MinSharesNotMet {
+        min_shares_out: Uint128,
+        shares: Uint128,
+    },
+
+    #[error("The rebalancer is not enabled")]
+    RebalanceDisabled {},
+
+    #[error("The last rebalance is still cooling down until {ready_at}")]
+    RebalanceCooldown { ready_at: Timestamp },
+
+    #[error("No outposts are far enough apart in yield to rebalance")]
+    NothingToRebalance {},
+
+    #[error("Move {id} is not on its way to Kujira")]
+    MoveNotReturning { id: u64 },
+
+    #[error("No failed operation with id {id}")]
+    OperationNotFound { id: u64 },
+
+    #[error("Failed operation {id} is already being retried")]
+    OperationRetrying { id: u64 },
+
+    #[error("Outpost {destination} already has {limit} operations in flight")]
+    OutpostBusy { destination: String, limit: u32 },
+
+    #[error("Outpost {destination} has operations in flight that conflict with a {operation}")]
+    ConflictingOperation {
+        destination: String,
+        operation: String,
+    },
+
+    #[error("There is no pending work to crank")]
+    NothingToCrank {},
+
+    #[error("Recipient and remote chain must be set together")]
+    InvalidRecipient {},
+
+    #[error("Unknown chain: {chain}")]
+    UnknownChain { chain: String },
+
+    #[error("Withdrawal {id} is already being paid out")]
+    WithdrawalPaidOut { id: u64 },
+
+    #[error("Withdrawal {id} is not returning to Kujira")]
+    WithdrawalNotReturning { id: u64 },
+
+    #[error("The payout of withdrawal {id} can arrive until {expires_at}")]
+    PayoutInFlight { id: u64, expires_at: Timestamp },
+
+    #[error("No refund is pending for deposit {id}")]
+    RefundNotFound { id: u64 },
+
+    #[error("The refund of deposit {id} can arrive until {expires_at}")]
+    RefundInFlight { id: u64, expires_at: Timestamp },
+
+    #[error("Outpost {destination} has no interchain account yet")]
+    NoIcaAddress { destination: String },
+
+    #[error(
+        "Outpost {destination} reported another interchain account, the owner must resolve it"
+    )]
+    IcaAddressMismatch { destination: String },
+
+    #[error("Outpost {destination} has no interchain account mismatch")]
+    NoIcaAddressMismatch { destination: String },
+
+    #[error("Unknown reply id: {id}")]
+    UnknownReply { id: u64 },
+
+    #[error("NAV refresh {id} is still in progress")]
+    NavRefreshInProgress { id: u64 },
+
+    #[error("NAV of {destination} is stale: last updated {age}s ago, max age is {max_age}s")]
+    StaleNav {
+        destination: String,
+        age: u64,
+        max_age: u64,
+    },
     // Add any other custom errors you like here.
     // Look at https://docs.rs/thiserror/1.0.21/thiserror/ for details.
 }
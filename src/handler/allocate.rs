@@ -0,0 +1,190 @@
+use crate::{
+    config::Config,
+    error::ContractError,
+    msg::Outpost,
+    state::{State, OUTPOST_NAV},
+};
+use cosmwasm_std::{ensure, StdResult, Storage, Uint128};
+
+use super::in_flight::{can_accept, OperationKind};
+
+/// Destination of deposits routed by the allocation engine instead of a chosen outpost
+pub const AUTO_DESTINATION: &str = "auto";
+
+/// Current value of the outpost and the value it holds at its maximum exposure once a deposit of
+/// `value` was added to the TVL
+fn exposure(
+    storage: &dyn Storage,
+    state: &State,
+    outpost: &Outpost,
+    value: Uint128,
+) -> StdResult<(Uint128, Option<Uint128>)> {
+    let current = OUTPOST_NAV
+        .may_load(storage, &outpost.mars_red_bank_contract)?
+        .unwrap_or_default()
+        .total();
+    let cap = outpost
+        .max_exposure
+        .map(|max_exposure| (state.total_stables + value).mul_floor(max_exposure));
+    Ok((current, cap))
+}
+
+/// Refuses a deposit of `value` that would push the outpost above its maximum exposure
+pub fn ensure_exposure(
+    storage: &dyn Storage,
+    state: &State,
+    outpost: &Outpost,
+    value: Uint128,
+) -> Result<(), ContractError> {
+    let (current, cap) = exposure(storage, state, outpost, value)?;
+    if let (Some(cap), Some(max_exposure)) = (cap, outpost.max_exposure) {
+        ensure!(
+            current + value <= cap,
+            ContractError::ExposureCapExceeded {
+                destination: outpost.mars_red_bank_contract.clone(),
+                max_exposure,
+            }
+        );
+    }
+    Ok(())
+}
+
+/// Picks the outpost an `auto` deposit of `value` in `denom` is routed to.
+/// Among the active outposts with a target weight that take the denom, directly or through a FIN swap,
+/// it is the one furthest below its target once the deposit is added to the TVL.
+/// Outposts the deposit would push above their maximum exposure or that accept no deposit right now
+/// are skipped.
+/// The whole deposit goes to that one outpost, it is bridged in a single transfer tracked under a
+/// single id. A deposit larger than the gap overshoots the target, later deposits and rebalances
+/// even it out.
+pub fn allocate_deposit<'a>(
+    storage: &dyn Storage,
+    config: &'a Config,
+    state: &State,
+    denom: &str,
+    value: Uint128,
+) -> Result<&'a Outpost, ContractError> {
+    let tvl = state.total_stables + value;
+
+    let mut best: Option<(&Outpost, i128)> = None;
+    for outpost in config.active_outposts() {
+        let Some(target_weight) = outpost.target_weight else {
+            continue;
+        };
+        if outpost.base_denom != denom && config.find_fin_pair(denom, &outpost.base_denom).is_err()
+        {
+            continue;
+        }
+        let (current, cap) = exposure(storage, state, outpost, value)?;
+        if cap.is_some_and(|cap| current + value > cap) {
+            continue;
+        }
+        if !can_accept(storage, outpost, OperationKind::Deposit) {
+            continue;
+        }
+
+        let gap = tvl.mul_floor(target_weight).u128() as i128 - current.u128() as i128;
+        if best.map_or(true, |(_, best_gap)| gap > best_gap) {
+            best = Some((outpost, gap));
+        }
+    }
+
+    best.map(|(outpost, _)| outpost)
+        .ok_or(ContractError::NoAllocation {})
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::MockStorage;
+    use cosmwasm_std::Decimal;
+
+    use super::*;
+    use crate::msg::FinPair;
+    use crate::testing::{mock_config, mock_outpost, mock_state, save_nav};
+
+    fn outpost(destination: &str, base_denom: &str, max_exposure: Option<u64>) -> Outpost {
+        Outpost {
+            target_weight: Some(Decimal::percent(50)),
+            max_exposure: max_exposure.map(Decimal::percent),
+            ..mock_outpost(destination, "osmosis-1", base_denom)
+        }
+    }
+
+    fn allocated(
+        storage: &dyn Storage,
+        config: &Config,
+        state: &State,
+        denom: &str,
+        value: u128,
+    ) -> String {
+        allocate_deposit(storage, config, state, denom, Uint128::new(value))
+            .unwrap()
+            .mars_red_bank_contract
+            .clone()
+    }
+
+    #[test]
+    fn test_allocate_deposit_fills_largest_gap() {
+        let mut storage = MockStorage::new();
+        let config = mock_config(vec![
+            outpost("a", "uusdc", None),
+            outpost("b", "uusdc", None),
+            // Outposts without a target weight only take deposits routed to them
+            mock_outpost("c", "pion-1", "uusdc"),
+        ]);
+        save_nav(&mut storage, "a", 0, 1_000);
+        save_nav(&mut storage, "b", 0, 200);
+        let state = mock_state(&storage, 1_200);
+
+        // With the deposit the TVL is 1500, a is 250 above its target and b 550 below
+        assert_eq!(allocated(&storage, &config, &state, "uusdc", 300), "b");
+
+        // The deposit is not split, b takes all of it and ends 100 above its target of 1100
+        assert_eq!(allocated(&storage, &config, &state, "uusdc", 1_000), "b");
+    }
+
+    #[test]
+    fn test_allocate_deposit_respects_exposure_caps() {
+        let mut storage = MockStorage::new();
+        let config = mock_config(vec![
+            outpost("a", "uusdc", None),
+            outpost("b", "uusdc", Some(20)),
+        ]);
+        save_nav(&mut storage, "a", 0, 1_000);
+        save_nav(&mut storage, "b", 0, 200);
+        let state = mock_state(&storage, 1_200);
+
+        // b may hold 300 of the TVL of 1500 but would end at 500, a takes the deposit instead
+        assert_eq!(allocated(&storage, &config, &state, "uusdc", 300), "a");
+
+        let config = mock_config(vec![
+            outpost("a", "uusdc", Some(60)),
+            outpost("b", "uusdc", Some(20)),
+        ]);
+        assert!(matches!(
+            allocate_deposit(&storage, &config, &state, "uusdc", Uint128::new(300)),
+            Err(ContractError::NoAllocation {})
+        ));
+    }
+
+    #[test]
+    fn test_allocate_deposit_needs_fin_pair() {
+        let mut storage = MockStorage::new();
+        let mut config = mock_config(vec![
+            outpost("a", "uusdc", None),
+            outpost("b", "uusdt", None),
+        ]);
+        save_nav(&mut storage, "a", 0, 200);
+        save_nav(&mut storage, "b", 0, 1_000);
+        let state = mock_state(&storage, 1_200);
+
+        // a has the larger gap but cannot swap the deposit into its base denom
+        assert_eq!(allocated(&storage, &config, &state, "uusdt", 300), "b");
+
+        config.fin_pairs = vec![FinPair {
+            pair: "fin".to_string(),
+            denoms: ["uusdt".to_string(), "uusdc".to_string()],
+        }];
+        assert_eq!(allocated(&storage, &config, &state, "uusdt", 300), "a");
+    }
+}
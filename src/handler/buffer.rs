@@ -0,0 +1,373 @@
+use crate::{
+    config::Config,
+    error::ContractError,
+    oracle::{amount_for_usd, usd_value},
+    state::{
+        buffer_value, next_operation_id, Buffer, BufferResponse, DenomBuffer, Payout,
+        PendingWithdrawal, Request, RequestKind, RequestStatus, State, BUFFERS, REQUESTS, STATE,
+    },
+};
+use cosmwasm_std::{
+    ensure, Addr, BankMsg, Coin, Deps, Env, Order, QuerierWrapper, Response, StdResult, Storage,
+    Uint128,
+};
+use kujira::{KujiraMsg, KujiraQuery};
+
+use super::withdraw::route_and_reclaim;
+
+/// USD value the buffers are topped up to, a share of the TVL
+fn buffer_target(config: &Config, tvl: Uint128) -> Option<Uint128> {
+    config.buffer_ratio.map(|ratio| tvl.mul_floor(ratio))
+}
+
+/// USD value of a deposit worth `value` the buffers would keep to reach their target
+pub fn buffer_shortfall(
+    storage: &dyn Storage,
+    config: &Config,
+    state: &State,
+    value: Uint128,
+) -> StdResult<Uint128> {
+    if config.epoch_length.is_some() {
+        return Ok(Uint128::zero());
+    }
+    let Some(target) = buffer_target(config, state.total_stables + value) else {
+        return Ok(Uint128::zero());
+    };
+    Ok(target.saturating_sub(buffer_value(storage)?).min(value))
+}
+
+/// Keeps as much of a deposit on Kujira as the buffer lacks to reach its target. The kept part
+/// is priced at once and its receipts minted, the rest is bridged as usual.
+/// Returns the amount kept and the receipts minted for it.
+/// Epochs price deposits at the NAV their epoch ends with, so the buffer is only filled without them.
+pub fn fill_buffer(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper<KujiraQuery>,
+    config: &Config,
+    state: &mut State,
+    coin: &Coin,
+) -> Result<(Uint128, Uint128), ContractError> {
+    if config.epoch_length.is_some() || config.buffer_ratio.is_none() {
+        return Ok((Uint128::zero(), Uint128::zero()));
+    }
+    let value = usd_value(querier, config, coin)?;
+    let missing = buffer_shortfall(storage, config, state, value)?;
+    if missing.is_zero() {
+        return Ok((Uint128::zero(), Uint128::zero()));
+    }
+    let kept = coin.amount.multiply_ratio(missing, value);
+    let shares = state.shares_for(missing);
+
+    let mut buffer = BUFFERS.may_load(storage, &coin.denom)?.unwrap_or_default();
+    buffer.amount += kept;
+    buffer.value = usd_value(
+        querier,
+        config,
+        &Coin {
+            denom: coin.denom.clone(),
+            amount: buffer.amount,
+        },
+    )?;
+    BUFFERS.save(storage, &coin.denom, &buffer)?;
+
+    state.virtual_receipt += shares;
+    state.sync_nav(storage)?;
+    Ok((kept, shares))
+}
+
+/// Pays a withdrawal on Kujira straight from a buffer that covers all of it.
+/// The receipts are burned at the current NAV and the owner is paid in the same transaction,
+/// or once they claim the request. Returns `None` when no buffer covers the withdrawal.
+#[allow(clippy::too_many_arguments)]
+pub fn pay_from_buffer(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper<KujiraQuery>,
+    env: &Env,
+    config: &Config,
+    state: &mut State,
+    owner: &Addr,
+    shares: Uint128,
+    min_assets_out: Option<Uint128>,
+    auto_claim: bool,
+) -> Result<Option<Response<KujiraMsg>>, ContractError> {
+    if config.buffer_ratio.is_none() {
+        return Ok(None);
+    }
+    let value = state.assets_for(shares);
+
+    let buffers = BUFFERS
+        .range(storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    // Pay from the fullest buffer that covers the withdrawal
+    let mut source: Option<(String, Uint128, Buffer)> = None;
+    for (denom, buffer) in buffers {
+        let amount = amount_for_usd(querier, config, &denom, value)?;
+        let fuller = source
+            .as_ref()
+            .map_or(true, |(_, _, best)| buffer.amount > best.amount);
+        if amount <= buffer.amount && fuller {
+            source = Some((denom, amount, buffer));
+        }
+    }
+    let Some((denom, amount, mut buffer)) = source else {
+        return Ok(None);
+    };
+
+    if let Some(min_assets_out) = min_assets_out {
+        ensure!(
+            amount >= min_assets_out,
+            ContractError::MinAssetsNotMet {
+                min_assets_out,
+                amount,
+            }
+        );
+    }
+
+    buffer.amount -= amount;
+    buffer.value = usd_value(
+        querier,
+        config,
+        &Coin {
+            denom: denom.clone(),
+            amount: buffer.amount,
+        },
+    )?;
+    BUFFERS.save(storage, &denom, &buffer)?;
+    state.virtual_receipt -= shares;
+    state.sync_nav(storage)?;
+
+    let id = next_operation_id(storage)?;
+    REQUESTS.save(
+        storage,
+        (owner, id),
+        &Request {
+            kind: RequestKind::Redeem,
+            status: if auto_claim {
+                RequestStatus::Claimed
+            } else {
+                RequestStatus::Claimable
+            },
+            auto_claim,
+            destination: String::new(),
+            denom: denom.clone(),
+            assets: amount,
+            shares,
+            legs: 0,
+            created_at: env.block.time,
+        },
+    )?;
+
+    let mut response = Response::new()
+        .add_attribute("method", "withdraw")
+        .add_attribute("withdrawal_id", id.to_string())
+        .add_attribute("owner", owner.to_string())
+        .add_attribute("shares", shares)
+        .add_attribute("amount", amount)
+        .add_attribute("source", "buffer");
+    // Redeem requests keep the funds until the owner claims them
+    if auto_claim {
+        response = response.add_message(BankMsg::Send {
+            to_address: owner.to_string(),
+            amount: vec![Coin { denom, amount }],
+        });
+    }
+    Ok(Some(response))
+}
+
+/// Reclaims the value the buffers lack to reach their target from the outposts.
+/// The funds are transferred back to Kujira and added to the buffer of their denom once they arrive.
+/// Callable by anyone, usually a keeper.
+pub fn try_top_up_buffer(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper<KujiraQuery>,
+    env: &Env,
+    config: &Config,
+    state: &mut State,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let target =
+        buffer_target(config, state.total_stables).ok_or(ContractError::BufferDisabled {})?;
+    let missing = target.saturating_sub(buffer_value(storage)?);
+    ensure!(!missing.is_zero(), ContractError::BufferFull { target });
+
+    let id = next_operation_id(storage)?;
+    let routed = route_and_reclaim(
+        storage,
+        querier,
+        env,
+        config,
+        &env.contract.address,
+        id,
+        Uint128::zero(),
+        missing,
+        &Payout::Buffer,
+    )?;
+
+    // The reclaimed value stays part of the NAV while it is on its way to Kujira
+    let mut buffer = BUFFERS
+        .may_load(storage, &routed.denom)?
+        .unwrap_or_default();
+    buffer.incoming += missing;
+    BUFFERS.save(storage, &routed.denom, &buffer)?;
+    state.sync_nav(storage)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "top_up_buffer")
+        .add_attribute("target", target)
+        .add_attribute("missing", missing)
+        .add_submessages(routed.response.messages)
+        .add_attributes(routed.response.attributes))
+}
+
+/// Adds the funds of a top-up that arrived on Kujira to the buffer of their denom
+pub fn receive_top_up(
+    storage: &mut dyn Storage,
+    withdrawal: &PendingWithdrawal,
+    coin: &Coin,
+) -> StdResult<()> {
+    let mut buffer = BUFFERS.may_load(storage, &coin.denom)?.unwrap_or_default();
+    buffer.amount += coin.amount;
+    buffer.value += withdrawal.value;
+    buffer.incoming = buffer.incoming.saturating_sub(withdrawal.value);
+    BUFFERS.save(storage, &coin.denom, &buffer)
+}
+
+pub fn query_buffer(deps: Deps<KujiraQuery>) -> Result<BufferResponse, ContractError> {
+    let config = Config::load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+    let buffers = BUFFERS
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(denom, buffer)| DenomBuffer { denom, buffer }))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(BufferResponse {
+        target: buffer_target(&config, state.total_stables).unwrap_or_default(),
+        value: buffer_value(deps.storage)?,
+        buffers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::{mock_env, MockStorage};
+    use cosmwasm_std::{CosmosMsg, Decimal};
+
+    use super::*;
+    use crate::testing::{mock_config, mock_oracle, mock_outpost, mock_state, save_nav};
+
+    /// Outpost `a` holding `lent` and a buffer of `buffered` against `receipts`
+    fn setup(storage: &mut dyn Storage, lent: u128, buffered: u128, receipts: u128) -> State {
+        save_nav(storage, "a", 0, lent);
+        if buffered > 0 {
+            let buffer = Buffer {
+                amount: Uint128::new(buffered),
+                value: Uint128::new(buffered),
+                incoming: Uint128::zero(),
+            };
+            BUFFERS.save(storage, "uusdc", &buffer).unwrap();
+        }
+        mock_state(storage, receipts)
+    }
+
+    #[test]
+    fn test_fill_buffer_keeps_shortfall() {
+        let mut storage = MockStorage::new();
+        let oracle = mock_oracle(Decimal::one());
+        let querier = QuerierWrapper::<KujiraQuery>::new(&oracle);
+        let config = Config {
+            buffer_ratio: Some(Decimal::percent(10)),
+            ..mock_config(vec![mock_outpost("a", "osmosis-1", "uusdc")])
+        };
+        let mut state = setup(&mut storage, 900, 0, 900);
+
+        // The TVL reaches 1100 with the deposit, the buffer keeps 10% of it
+        let coin = Coin::new(200u128, "uusdc");
+        let (kept, shares) =
+            fill_buffer(&mut storage, &querier, &config, &mut state, &coin).unwrap();
+        assert_eq!((kept, shares), (Uint128::new(110), Uint128::new(110)));
+        assert_eq!(
+            BUFFERS.load(&storage, "uusdc").unwrap().amount,
+            Uint128::new(110)
+        );
+        assert_eq!(state.total_stables, Uint128::new(1_010));
+
+        // The next deposit only tops the buffer up to 10% of the new TVL of 1210
+        let (kept, _) = fill_buffer(&mut storage, &querier, &config, &mut state, &coin).unwrap();
+        assert_eq!(kept, Uint128::new(11));
+    }
+
+    #[test]
+    fn test_fill_buffer_disabled_by_epochs() {
+        let mut storage = MockStorage::new();
+        let oracle = mock_oracle(Decimal::one());
+        let querier = QuerierWrapper::<KujiraQuery>::new(&oracle);
+        let config = Config {
+            buffer_ratio: Some(Decimal::percent(10)),
+            epoch_length: Some(3600),
+            ..mock_config(vec![mock_outpost("a", "osmosis-1", "uusdc")])
+        };
+        let mut state = setup(&mut storage, 900, 0, 900);
+
+        let coin = Coin::new(200u128, "uusdc");
+        let (kept, shares) =
+            fill_buffer(&mut storage, &querier, &config, &mut state, &coin).unwrap();
+        assert!(kept.is_zero() && shares.is_zero());
+        assert!(!BUFFERS.has(&storage, "uusdc"));
+    }
+
+    #[test]
+    fn test_pay_from_buffer_burns_receipts() {
+        let mut storage = MockStorage::new();
+        let oracle = mock_oracle(Decimal::one());
+        let querier = QuerierWrapper::<KujiraQuery>::new(&oracle);
+        let env = mock_env();
+        let config = Config {
+            buffer_ratio: Some(Decimal::percent(10)),
+            ..mock_config(vec![mock_outpost("a", "osmosis-1", "uusdc")])
+        };
+        let owner = Addr::unchecked("bob");
+        // 1100 held against 1000 receipts, a receipt is worth 1.1
+        let mut state = setup(&mut storage, 900, 200, 1_000);
+
+        let response = pay_from_buffer(
+            &mut storage,
+            &querier,
+            &env,
+            &config,
+            &mut state,
+            &owner,
+            Uint128::new(100),
+            None,
+            true,
+        )
+        .unwrap()
+        .unwrap();
+        match &response.messages[0].msg {
+            CosmosMsg::Bank(BankMsg::Send { to_address, amount }) => {
+                assert_eq!(to_address, "bob");
+                assert_eq!(amount, &vec![Coin::new(110u128, "uusdc")]);
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        assert_eq!(
+            BUFFERS.load(&storage, "uusdc").unwrap().amount,
+            Uint128::new(90)
+        );
+        assert_eq!(state.virtual_receipt, Uint128::new(900));
+        assert_eq!(state.total_stables, Uint128::new(990));
+
+        // A withdrawal the buffer cannot cover is left to the outposts
+        let paid = pay_from_buffer(
+            &mut storage,
+            &querier,
+            &env,
+            &config,
+            &mut state,
+            &owner,
+            Uint128::new(500),
+            None,
+            true,
+        )
+        .unwrap();
+        assert!(paid.is_none());
+    }
+}
@@ -0,0 +1,740 @@
+use cosmwasm_std::{
+    Addr, Coin, Env, IbcChannel, IbcPacket, QuerierWrapper, Response, Storage, Uint128,
+};
+use cw_ica_controller::ibc::types::packet::acknowledgement::Data;
+use cw_ica_controller::types::{callbacks::IcaControllerCallbackMsg, query_msg::IcaQueryResult};
+use kujira::{KujiraMsg, KujiraQuery};
+
+use crate::config::{Config, CONFIG};
+use crate::error::ContractError;
+use crate::helpers::{positions_value, record_market};
+use crate::msg::Outpost;
+use crate::oracle::usd_value;
+use crate::state::{
+    DepositStage, IcaAddressMismatch, IcaChannel, State, CHANNEL_REOPENS, ICA_CHANNELS,
+    ICA_MISMATCHES, OUTPOST_NAV, PENDING_DEPOSITS, VIRTUAL_RECEIPTS,
+};
+
+use super::channels::reopen_channel;
+
+use super::epoch::{epoch_callback, epoch_market_callback, size_epoch_batch};
+use super::ica::{
+    extract_packet, extract_packet_memo, lend_ica_msg, outpost_queries, parse_positions, IcaMemo,
+};
+use super::in_flight::{end_operation, OperationKind};
+use super::rebalance::{expire_move, move_callback, move_lend_callback, move_relend_callback};
+use super::refresh_nav::refresh_nav_callback;
+use super::refund::{refund_failed, refund_ica_msg, track_refund};
+use super::request::{
+    is_cancelled, refund_deposit_request, revoke_deposit_shares, settle_deposit_request,
+};
+use super::retry::{clear_failure, record_failure};
+use super::withdraw::{
+    payout_callback, relend_callback, rollback_withdrawal, size_withdrawal, withdraw_callback,
+    withdraw_market_callback,
+};
+
+/// Handles the callbacks of the ICA controller contracts.
+/// Based on the memo the packet was sent with, we can determine the action to take.
+pub fn ica_callback_execute(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper<KujiraQuery>,
+    env: &Env,
+    sender: &Addr,
+    msg: IcaControllerCallbackMsg,
+    state: &mut State,
+    config: &mut Config,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    match msg {
+        IcaControllerCallbackMsg::OnChannelOpenAckCallback {
+            channel,
+            ica_address,
+            ..
+        } => on_channel_open_ack(storage, env, sender, config, channel, ica_address),
+        IcaControllerCallbackMsg::OnTimeoutPacketCallback {
+            original_packet, ..
+        } => on_timeout(storage, env, sender, &original_packet, state, config),
+        IcaControllerCallbackMsg::OnAcknowledgementPacketCallback {
+            ica_acknowledgement,
+            original_packet,
+            relayer: _,
+            query_result,
+        } => {
+            let memo =
+                extract_packet_memo(&original_packet)?.ok_or(ContractError::UnknownMemo {})?;
+            let parsed = IcaMemo::parse(&memo);
+
+            match ica_acknowledgement {
+                Data::Result(_) => clear_failure(storage, &memo)?,
+                Data::Error(_) if parsed.as_ref().is_ok_and(|memo| memo.is_guidance_query()) => {}
+                Data::Error(error) => {
+                    return on_error_ack(
+                        storage,
+                        env,
+                        sender,
+                        &original_packet,
+                        state,
+                        config,
+                        &memo,
+                        error,
+                    )
+                }
+            }
+
+            match parsed? {
+                IcaMemo::Deposit(id) => {
+                    on_deposit_query(storage, querier, env, state, config, query_result, id)
+                }
+                IcaMemo::Lend(id) => on_lend_ack(storage, state, id),
+                IcaMemo::RefreshNav { id, destination } => refresh_nav_callback(
+                    storage,
+                    querier,
+                    env,
+                    config,
+                    state,
+                    id,
+                    destination,
+                    query_result,
+                ),
+                IcaMemo::Market(destination) => {
+                    record_market(storage, destination, &query_result, env.block.time)?;
+                    Ok(Response::new()
+                        .add_attribute("method", "market_callback")
+                        .add_attribute("destination", destination.to_string()))
+                }
+                IcaMemo::Withdraw(id) => withdraw_callback(storage, env, config, id),
+                IcaMemo::WithdrawMarket(id) => {
+                    withdraw_market_callback(storage, env, config, state, id, query_result)
+                }
+                IcaMemo::Payout(id) => payout_callback(storage, id),
+                IcaMemo::Epoch { epoch, destination } => {
+                    epoch_callback(storage, env, config, state, epoch, destination)
+                }
+                IcaMemo::EpochMarket { epoch, destination } => epoch_market_callback(
+                    storage,
+                    env,
+                    config,
+                    state,
+                    epoch,
+                    destination,
+                    query_result,
+                ),
+                // The refund stays pending until its funds arrive through the hook
+                IcaMemo::RefundDeposit(id) => Ok(Response::new()
+                    .add_attribute("method", "refund_deposit_callback")
+                    .add_attribute("deposit_id", id.to_string())),
+                IcaMemo::Move(id) => move_callback(storage, env, config, id),
+                IcaMemo::MoveLend(id) => move_lend_callback(storage, state, id),
+                IcaMemo::MoveRelend(id) => move_relend_callback(storage, state, id),
+                IcaMemo::Relend(id) => relend_callback(storage, state, id),
+            }
+        }
+    }
+}
+
+/// Handles a transaction the host chain answered with an error acknowledgement.
+/// A priced deposit whose lend failed goes back to pricing and is retried with a new positions
+/// query. A failed withdrawal is sized to the liquidity of its red bank market and sent again, or
+/// gives the receipts back to its owner when the market could not be queried. A failed epoch
+/// transaction that reclaims lent funds is recorded and its market queried as well. A failed move
+/// reclaimed nothing, so its value goes straight back to the outpost it was leaving.
+/// All other transactions are kept as they are until they are retried or expire. This includes the
+/// positions query of a deposit: nothing was priced yet, its funds wait in the ICA and are refunded
+/// once the failure expires.
+#[allow(clippy::too_many_arguments)]
+fn on_error_ack(
+    storage: &mut dyn Storage,
+    env: &Env,
+    sender: &Addr,
+    packet: &IbcPacket,
+    state: &mut State,
+    config: &Config,
+    memo: &str,
+    error: String,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    match IcaMemo::parse(memo) {
+        Ok(IcaMemo::Lend(id)) => {
+            if let Some(destination) = rollback_lend(storage, state, id)? {
+                let outpost = config.find_destination_outpost(&destination).ok_or(
+                    ContractError::DestinationNotFound {
+                        destination: destination.clone(),
+                    },
+                )?;
+                // Replaces the record of an earlier failure that could not be rolled back
+                clear_failure(storage, memo)?;
+                return Ok(record_failure(
+                    storage,
+                    env,
+                    config,
+                    sender,
+                    &format!("deposit/{}", id),
+                    vec![],
+                    outpost_queries(outpost)?,
+                    error,
+                )?
+                .add_attribute("rolled_back", memo));
+            }
+        }
+        Ok(IcaMemo::Withdraw(id)) => {
+            return Ok(size_withdrawal(storage, config, state, id)?.add_attribute("error", error));
+        }
+        Ok(IcaMemo::WithdrawMarket(id)) => {
+            return Ok(
+                rollback_withdrawal(storage, config, state, id)?.add_attribute("error", error)
+            );
+        }
+        Ok(IcaMemo::Epoch { epoch, destination }) => {
+            let sizing = size_epoch_batch(storage, config, epoch, destination)?;
+            let packet = extract_packet(packet)?;
+            return Ok(record_failure(
+                storage,
+                env,
+                config,
+                sender,
+                memo,
+                packet.messages,
+                packet.queries,
+                error,
+            )?
+            .add_messages(sizing));
+        }
+        Ok(IcaMemo::Move(id)) => {
+            // Replaces the record of an earlier failure of the move
+            clear_failure(storage, memo)?;
+            return Ok(expire_move(storage, state, id)?
+                .add_attribute("memo", memo)
+                .add_attribute("error", error));
+        }
+        Ok(IcaMemo::RefundDeposit(id)) => {
+            return Ok(refund_failed(storage, env, id)?
+                .add_attribute("memo", memo)
+                .add_attribute("error", error));
+        }
+        _ => {}
+    }
+
+    let packet = extract_packet(packet)?;
+    record_failure(
+        storage,
+        env,
+        config,
+        sender,
+        memo,
+        packet.messages,
+        packet.queries,
+        error,
+    )
+}
+
+/// Undoes the pricing of a deposit whose lend failed: the receipts minted for it are burned and its
+/// value is no longer pending on the outpost. Returns the outpost of the deposit, or `None` when the
+/// owner no longer holds the receipts and nothing was undone.
+fn rollback_lend(
+    storage: &mut dyn Storage,
+    state: &mut State,
+    id: u64,
+) -> Result<Option<String>, ContractError> {
+    let mut deposit = PENDING_DEPOSITS.load(storage, id)?;
+    if !revoke_deposit_shares(storage, &deposit.owner, id, deposit.shares)? {
+        return Ok(None);
+    }
+    state.virtual_receipt -= deposit.shares;
+
+    let mut nav = OUTPOST_NAV
+        .may_load(storage, &deposit.destination)?
+        .unwrap_or_default();
+    nav.pending = nav.pending.saturating_sub(deposit.value);
+    OUTPOST_NAV.save(storage, &deposit.destination, &nav)?;
+    state.sync_nav(storage)?;
+
+    deposit.shares = Uint128::zero();
+    deposit.value = Uint128::zero();
+    deposit.stage = DepositStage::Pricing;
+    PENDING_DEPOSITS.save(storage, id, &deposit)?;
+    Ok(Some(deposit.destination))
+}
+
+/// Records the channel and interchain account address of the outpost whose controller opened a channel.
+/// A reopened channel must keep the interchain account of the outpost. Another address is recorded
+/// as a mismatch and reported, the outpost keeps the address its funds are held by.
+fn on_channel_open_ack(
+    storage: &mut dyn Storage,
+    env: &Env,
+    sender: &Addr,
+    config: &mut Config,
+    channel: IbcChannel,
+    ica_address: String,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let outpost = config
+        .find_controller_outpost_mut(sender.as_str())
+        .ok_or(ContractError::Unauthorized {})?;
+    let destination = outpost.mars_red_bank_contract.clone();
+    let channel_id = channel.endpoint.channel_id;
+
+    let reopened = CHANNEL_REOPENS.has(storage, &destination);
+    CHANNEL_REOPENS.remove(storage, &destination);
+    ICA_CHANNELS.save(
+        storage,
+        &destination,
+        &IcaChannel {
+            channel_id: channel_id.clone(),
+            connection_id: channel.connection_id,
+            version: channel.version,
+            order: channel.order,
+        },
+    )?;
+
+    let response = Response::new()
+        .add_attribute("method", "channel_open_ack_callback")
+        .add_attribute("channel_id", channel_id.clone())
+        .add_attribute("ica_address", ica_address.clone())
+        .add_attribute("reopened", reopened.to_string());
+
+    if let Some(expected) = outpost.ica_address.clone() {
+        if expected != ica_address {
+            ICA_MISMATCHES.save(
+                storage,
+                &destination,
+                &IcaAddressMismatch {
+                    expected: expected.clone(),
+                    actual: ica_address,
+                    channel_id,
+                    detected_at: env.block.time,
+                },
+            )?;
+            return Ok(response.add_attribute("ica_address_mismatch", expected));
+        }
+    }
+    outpost.ica_address = Some(ica_address);
+    ICA_MISMATCHES.remove(storage, &destination);
+    CONFIG.save(storage, config)?;
+
+    Ok(response)
+}
+
+/// Handles a transaction that timed out before the host chain executed it like one answered with
+/// an error acknowledgement, a NAV refresh settles at its deadline and a market query is dropped.
+/// The timeout closed the ICA channel if it is ordered, so it is reopened right away.
+fn on_timeout(
+    storage: &mut dyn Storage,
+    env: &Env,
+    sender: &Addr,
+    packet: &IbcPacket,
+    state: &mut State,
+    config: &Config,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let outpost = config
+        .find_controller_outpost(sender.as_str())
+        .ok_or(ContractError::Unauthorized {})?;
+    let reopen = reopen_channel(storage, env, outpost)?;
+
+    let memo = extract_packet_memo(packet)?.ok_or(ContractError::UnknownMemo {})?;
+    let query = IcaMemo::parse(&memo).is_ok_and(|memo| memo.is_guidance_query());
+    let mut response = if query {
+        Response::new()
+            .add_attribute("method", "timeout_callback")
+            .add_attribute("memo", memo)
+    } else {
+        on_error_ack(
+            storage,
+            env,
+            sender,
+            packet,
+            state,
+            config,
+            &memo,
+            "timeout".to_string(),
+        )?
+    };
+    if let Some(reopen) = reopen {
+        response = response
+            .add_message(reopen)
+            .add_attribute("channel", "reopening");
+    }
+    Ok(response)
+}
+
+/// Records the positions of the outpost, prices the deposit against them and credits the owner
+/// with virtual receipts. Both are valued in USD through the Kujira oracle.
+/// The funds already sit in the ICA and are deposited and lent on mars.
+/// The deposited value is tracked as pending until the lend transaction is acknowledged.
+/// Cancelled deposits and those that would receive fewer receipts than their `min_shares_out`
+/// are refunded instead.
+fn on_deposit_query(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper<KujiraQuery>,
+    env: &Env,
+    state: &mut State,
+    config: &Config,
+    query_result: Option<IcaQueryResult>,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let mut deposit = PENDING_DEPOSITS.load(storage, id)?;
+    let destination = deposit.destination.as_str();
+    let outpost =
+        config
+            .find_destination_outpost(destination)
+            .ok_or(ContractError::DestinationNotFound {
+                destination: destination.to_string(),
+            })?;
+
+    let (height, positions) = parse_positions(query_result)?;
+    let (idle, lent) = positions_value(querier, config, &positions)?;
+
+    let mut nav = OUTPOST_NAV
+        .may_load(storage, destination)?
+        .unwrap_or_default();
+    nav.record_positions(idle, lent, env.block.time, height);
+    OUTPOST_NAV.save(storage, destination, &nav)?;
+    state.sync_nav(storage)?;
+
+    // Price the deposit before its own value is added to the outpost
+    let amount = deposit.coin.amount;
+    let value = usd_value(querier, config, &deposit.coin)?;
+    let shares = state.shares_for(value);
+    if is_cancelled(storage, &deposit.owner, id)?
+        || deposit
+            .min_shares_out
+            .is_some_and(|min_shares_out| shares < min_shares_out)
+    {
+        return refund_deposit(storage, env, outpost, id, shares);
+    }
+
+    // Receipts of deposit requests are minted now but only credited once claimed
+    if settle_deposit_request(storage, &deposit.owner, id, shares)? {
+        VIRTUAL_RECEIPTS.update(storage, deposit.owner.clone(), |existing| {
+            Ok::<_, ContractError>(existing.unwrap_or_default() + shares)
+        })?;
+    }
+    state.virtual_receipt += shares;
+
+    nav.pending += value;
+    OUTPOST_NAV.save(storage, destination, &nav)?;
+    state.sync_nav(storage)?;
+
+    // create new SendCosmosMsgs to call the red_bank contract with the funds held by the ICA
+    let coin = Coin {
+        denom: outpost.host_denom.clone(),
+        amount,
+    };
+
+    let msg = lend_ica_msg(outpost, coin, format!("lend/{}", id))?;
+
+    deposit.value = value;
+    deposit.shares = shares;
+    deposit.stage = DepositStage::Lending;
+    PENDING_DEPOSITS.save(storage, id, &deposit)?;
+
+    Ok(Response::new()
+        .add_message(msg)
+        .add_attribute("method", "deposit_query_callback")
+        .add_attribute("deposit_id", id.to_string())
+        .add_attribute("owner", deposit.owner)
+        .add_attribute("value", value)
+        .add_attribute("shares", shares)
+        .add_attribute("total_stables", state.total_stables))
+}
+
+/// Sends the funds of a deposit that was cancelled, missed its minimum receipts or failed for good
+/// from the ICA back to this contract instead of lending them, the owner is paid once they arrive.
+/// No receipts are credited.
+pub fn refund_deposit(
+    storage: &mut dyn Storage,
+    env: &Env,
+    outpost: &Outpost,
+    id: u64,
+    shares: Uint128,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let deposit = PENDING_DEPOSITS.load(storage, id)?;
+    PENDING_DEPOSITS.remove(storage, id);
+    end_operation(storage, &deposit.destination, OperationKind::Deposit)?;
+    refund_deposit_request(storage, &deposit.owner, id)?;
+
+    let transfer = track_refund(
+        storage,
+        env,
+        outpost,
+        id,
+        deposit.owner.clone(),
+        deposit.coin.amount,
+    )?;
+    let msg = refund_ica_msg(outpost, id, transfer)?;
+
+    Ok(Response::new()
+        .add_message(msg)
+        .add_attribute("method", "refund_deposit")
+        .add_attribute("deposit_id", id.to_string())
+        .add_attribute("owner", deposit.owner)
+        .add_attribute("shares", shares)
+        .add_attribute("min_shares_out", deposit.min_shares_out.unwrap_or_default()))
+}
+
+/// Refunds a deposit whose pricing query or lend failed for good from the ICA to its owner.
+/// Receipts minted once it was priced are burned along with its pending value, so a priced deposit
+/// only expires while its owner still holds them. Returns `None` otherwise.
+pub fn expire_deposit(
+    storage: &mut dyn Storage,
+    env: &Env,
+    config: &Config,
+    state: &mut State,
+    id: u64,
+) -> Result<Option<Response<KujiraMsg>>, ContractError> {
+    let Some(deposit) = PENDING_DEPOSITS.may_load(storage, id)? else {
+        return Ok(Some(Response::new()));
+    };
+    let destination = deposit.destination.as_str();
+    let outpost =
+        config
+            .find_destination_outpost(destination)
+            .ok_or(ContractError::DestinationNotFound {
+                destination: destination.to_string(),
+            })?;
+
+    if !deposit.shares.is_zero() && rollback_lend(storage, state, id)?.is_none() {
+        return Ok(None);
+    }
+
+    refund_deposit(storage, env, outpost, id, deposit.shares).map(Some)
+}
+
+/// Moves the deposited value from pending to lent once the red bank confirmed the lend.
+fn on_lend_ack(
+    storage: &mut dyn Storage,
+    state: &mut State,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let deposit = PENDING_DEPOSITS.load(storage, id)?;
+    PENDING_DEPOSITS.remove(storage, id);
+    end_operation(storage, &deposit.destination, OperationKind::Deposit)?;
+
+    let value = deposit.value;
+    let destination = deposit.destination.as_str();
+    let mut nav = OUTPOST_NAV
+        .may_load(storage, destination)?
+        .unwrap_or_default();
+    nav.pending = nav.pending.saturating_sub(value);
+    nav.lent += value;
+    OUTPOST_NAV.save(storage, destination, &nav)?;
+    state.sync_nav(storage)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "lend_callback")
+        .add_attribute("deposit_id", id.to_string())
+        .add_attribute("destination", destination)
+        .add_attribute("total_stables", state.total_stables))
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::{mock_env, MockStorage};
+    use cosmwasm_std::{coin, Timestamp};
+
+    use super::*;
+    use crate::handler::in_flight::track_operation;
+    use crate::state::{
+        EpochBatch, MoveStage, OutpostNav, PendingDeposit, PendingMove, PendingWithdrawal,
+        WithdrawStage, EPOCH_BATCHES, FAILED_MEMOS, IN_FLIGHT, PENDING_MOVES, PENDING_WITHDRAWALS,
+    };
+    use crate::testing::{
+        fail, mock_config, mock_outpost, mock_packet, mock_state, mock_withdrawal, save_nav,
+    };
+
+    /// Config of outposts `a` and `b`, the red bank of `a` can be queried for its market
+    fn config() -> Config {
+        let mut a = mock_outpost("a", "osmosis-1", "uusdc");
+        a.red_bank_contract = Some("a_red_bank".to_string());
+        mock_config(vec![a, mock_outpost("b", "neutron-1", "uusdc")])
+    }
+
+    fn error_ack(
+        storage: &mut dyn Storage,
+        config: &Config,
+        state: &mut State,
+        memo: &str,
+    ) -> Response<KujiraMsg> {
+        on_error_ack(
+            storage,
+            &mock_env(),
+            &Addr::unchecked("a_controller"),
+            &mock_packet(memo),
+            state,
+            config,
+            memo,
+            "error".to_string(),
+        )
+        .unwrap()
+    }
+
+    /// A withdrawal of 200 for 100 receipts, 150 reclaimed from the lent funds of `a`
+    fn save_withdrawal(storage: &mut dyn Storage) -> State {
+        save_nav(storage, "a", 50, 750);
+        let withdrawal = PendingWithdrawal {
+            reclaim: Uint128::new(150),
+            ..mock_withdrawal("a", 100, 200)
+        };
+        PENDING_WITHDRAWALS.save(storage, 1, &withdrawal).unwrap();
+        track_operation(storage, "a", OperationKind::Withdrawal).unwrap();
+        mock_state(storage, 400)
+    }
+
+    #[test]
+    fn test_error_ack_lend_prices_deposit_again() {
+        let mut storage = MockStorage::new();
+        let config = config();
+        OUTPOST_NAV
+            .save(
+                &mut storage,
+                "a",
+                &OutpostNav {
+                    pending: Uint128::new(100),
+                    lent: Uint128::new(900),
+                    ..OutpostNav::default()
+                },
+            )
+            .unwrap();
+        let deposit = PendingDeposit {
+            value: Uint128::new(100),
+            shares: Uint128::new(100),
+            stage: DepositStage::Lending,
+            ..PendingDeposit::new(
+                Addr::unchecked("bob"),
+                coin(100, "uusdc"),
+                &config.outposts[0],
+                None,
+                Timestamp::from_seconds(0),
+            )
+        };
+        PENDING_DEPOSITS.save(&mut storage, 3, &deposit).unwrap();
+        VIRTUAL_RECEIPTS
+            .save(&mut storage, Addr::unchecked("bob"), &Uint128::new(100))
+            .unwrap();
+        let mut state = mock_state(&storage, 1_000);
+
+        error_ack(&mut storage, &config, &mut state, "lend/3");
+
+        // The receipts are burned and the deposit is priced again by a new positions query
+        let deposit = PENDING_DEPOSITS.load(&storage, 3).unwrap();
+        assert_eq!(deposit.stage, DepositStage::Pricing);
+        assert_eq!(deposit.shares, Uint128::zero());
+        assert_eq!(
+            VIRTUAL_RECEIPTS
+                .load(&storage, Addr::unchecked("bob"))
+                .unwrap(),
+            Uint128::zero()
+        );
+        assert_eq!(state.virtual_receipt, Uint128::new(900));
+        assert_eq!(state.total_stables, Uint128::new(900));
+        assert!(FAILED_MEMOS.has(&storage, "deposit/3"));
+        assert!(!FAILED_MEMOS.has(&storage, "lend/3"));
+    }
+
+    #[test]
+    fn test_error_ack_withdraw_queries_market() {
+        let mut storage = MockStorage::new();
+        let config = config();
+        let mut state = save_withdrawal(&mut storage);
+
+        let response = error_ack(&mut storage, &config, &mut state, "withdraw/1");
+
+        assert_eq!(response.messages.len(), 1);
+        let withdrawal = PENDING_WITHDRAWALS.load(&storage, 1).unwrap();
+        assert_eq!(withdrawal.stage, WithdrawStage::Sizing);
+        assert!(!FAILED_MEMOS.has(&storage, "withdraw/1"));
+    }
+
+    #[test]
+    fn test_error_ack_withdraw_market_rolls_back() {
+        let mut storage = MockStorage::new();
+        let config = config();
+        let mut state = save_withdrawal(&mut storage);
+
+        error_ack(&mut storage, &config, &mut state, "withdraw_market/1");
+
+        // The receipts are back with the owner and the outpost slot is free
+        assert!(!PENDING_WITHDRAWALS.has(&storage, 1));
+        assert!(!IN_FLIGHT.has(&storage, "a"));
+        let nav = OUTPOST_NAV.load(&storage, "a").unwrap();
+        assert_eq!((nav.idle, nav.lent), (Uint128::new(100), Uint128::new(900)));
+        assert_eq!(
+            VIRTUAL_RECEIPTS
+                .load(&storage, Addr::unchecked("bob"))
+                .unwrap(),
+            Uint128::new(100)
+        );
+        assert_eq!(state.virtual_receipt, Uint128::new(500));
+    }
+
+    #[test]
+    fn test_error_ack_epoch_records_and_queries_market() {
+        let mut storage = MockStorage::new();
+        let config = config();
+        let mut state = State::default();
+        let batch = EpochBatch {
+            lent_value: Uint128::zero(),
+            reclaimed_value: Uint128::new(150),
+            idle_value: Uint128::zero(),
+            reclaim: Uint128::new(150),
+            refunds: vec![],
+        };
+        EPOCH_BATCHES.save(&mut storage, (0, "a"), &batch).unwrap();
+        EPOCH_BATCHES.save(&mut storage, (0, "b"), &batch).unwrap();
+
+        // The batch of a is sized to its market, b has no red bank to query
+        let response = error_ack(&mut storage, &config, &mut state, "epoch/0/a");
+        assert_eq!(response.messages.len(), 1);
+        assert!(FAILED_MEMOS.has(&storage, "epoch/0/a"));
+        let response = error_ack(&mut storage, &config, &mut state, "epoch/0/b");
+        assert!(response.messages.is_empty());
+        assert!(FAILED_MEMOS.has(&storage, "epoch/0/b"));
+    }
+
+    #[test]
+    fn test_error_ack_move_rolls_back() {
+        let mut storage = MockStorage::new();
+        let env = mock_env();
+        let config = config();
+        save_nav(&mut storage, "a", 0, 900);
+        OUTPOST_NAV
+            .save(
+                &mut storage,
+                "b",
+                &OutpostNav {
+                    pending: Uint128::new(100),
+                    ..OutpostNav::default()
+                },
+            )
+            .unwrap();
+        track_operation(&mut storage, "a", OperationKind::Move).unwrap();
+        track_operation(&mut storage, "b", OperationKind::Move).unwrap();
+        let pending = PendingMove {
+            from: "a".to_string(),
+            to: "b".to_string(),
+            amount: Uint128::new(100),
+            value: Uint128::new(100),
+            stage: MoveStage::Returning,
+            created_at: env.block.time,
+            expires_at: None,
+        };
+        PENDING_MOVES.save(&mut storage, 7, &pending).unwrap();
+        // A retry of the move failed again
+        fail(&mut storage, &env, &config, "move/7");
+        let mut state = mock_state(&storage, 1_000);
+
+        error_ack(&mut storage, &config, &mut state, "move/7");
+
+        // The value is lent on a again and both slots are free, nothing is left to retry
+        assert!(!PENDING_MOVES.has(&storage, 7));
+        assert!(!FAILED_MEMOS.has(&storage, "move/7"));
+        assert!(!IN_FLIGHT.has(&storage, "a"));
+        assert!(!IN_FLIGHT.has(&storage, "b"));
+        assert_eq!(
+            OUTPOST_NAV.load(&storage, "a").unwrap().lent,
+            Uint128::new(1_000)
+        );
+        assert_eq!(
+            OUTPOST_NAV.load(&storage, "b").unwrap().pending,
+            Uint128::zero()
+        );
+        assert_eq!(state.total_stables, Uint128::new(1_000));
+    }
+}
@@ -1,11 +1,161 @@
-use crate::error::ContractError;
-use cosmwasm_std::Response;
-use kujira::KujiraMsg;
+use crate::{
+    config::{Config, CONFIG},
+    error::ContractError,
+    msg::Outpost,
+    state::{ChannelsResponse, OutpostChannel, CHANNEL_REOPENS, ICA_CHANNELS, ICA_MISMATCHES},
+};
+use cosmwasm_schema::cw_serde;
+use cosmwasm_std::{wasm_execute, Deps, Env, IbcOrder, Response, StdResult, Storage, WasmMsg};
+use cw_ica_controller::types::msg::ExecuteMsg as IcaControllerExecuteMsg;
+use kujira::{KujiraMsg, KujiraQuery};
 
-pub fn try_create_channel() -> Result<Response<KujiraMsg>, ContractError> {
-    unimplemented!()
+/// Opens the ICA channel of an outpost again with its channel options, e.g. when an automatic
+/// reopen after a timeout did not complete. Only callable by the contract owner.
+pub fn try_create_channel(
+    storage: &mut dyn Storage,
+    env: &Env,
+    config: &Config,
+    destination: String,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let outpost = config.find_destination_outpost(&destination).ok_or(
+        ContractError::DestinationNotFound {
+            destination: destination.clone(),
+        },
+    )?;
+    CHANNEL_REOPENS.save(storage, &destination, &env.block.time)?;
+
+    Ok(Response::new()
+        .add_message(create_channel_msg(storage, outpost)?)
+        .add_attribute("method", "create_channel")
+        .add_attribute("destination", destination))
+}
+
+/// Adopts the interchain account a reopened channel of an outpost reported instead of the one it had,
+/// so the outpost accepts operations again. Only callable by the contract owner. To keep the old
+/// account, the channel is opened again with `CreateChannel` instead.
+pub fn try_accept_ica_address(
+    storage: &mut dyn Storage,
+    config: &mut Config,
+    destination: String,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let mismatch = ICA_MISMATCHES.may_load(storage, &destination)?.ok_or(
+        ContractError::NoIcaAddressMismatch {
+            destination: destination.clone(),
+        },
+    )?;
+    let outpost = config.find_destination_outpost_mut(&destination).ok_or(
+        ContractError::DestinationNotFound {
+            destination: destination.clone(),
+        },
+    )?;
+    outpost.ica_address = Some(mismatch.actual.clone());
+    CONFIG.save(storage, config)?;
+    ICA_MISMATCHES.remove(storage, &destination);
+
+    Ok(Response::new()
+        .add_attribute("method", "accept_ica_address")
+        .add_attribute("destination", destination)
+        .add_attribute("previous_ica_address", mismatch.expected)
+        .add_attribute("ica_address", mismatch.actual))
+}
+
+/// `update_ownership` message of the ICA controller, which manages its owner with cw-ownable
+#[cw_serde]
+enum ControllerOwnershipMsg {
+    UpdateOwnership(OwnershipAction),
+}
+
+#[cw_serde]
+enum OwnershipAction {
+    AcceptOwnership,
+}
+
+/// Accepts the ownership of the ICA controller of an outpost, which its owner transferred to this
+/// contract with `update_ownership`. Controllers instantiated before this contract owned them need
+/// it before this contract can reopen their channel. Only callable by the contract owner.
+pub fn try_accept_controller_ownership(
+    config: &Config,
+    destination: String,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let outpost = config.find_destination_outpost(&destination).ok_or(
+        ContractError::DestinationNotFound {
+            destination: destination.clone(),
+        },
+    )?;
+
+    Ok(Response::new()
+        .add_message(wasm_execute(
+            outpost.cw_ica_controller_contract.clone(),
+            &ControllerOwnershipMsg::UpdateOwnership(OwnershipAction::AcceptOwnership),
+            vec![],
+        )?)
+        .add_attribute("method", "accept_controller_ownership")
+        .add_attribute("destination", destination))
 }
 
 pub fn try_close_channel() -> Result<Response<KujiraMsg>, ContractError> {
     unimplemented!()
 }
+
+/// Asks the controller of the outpost to open a channel on the connection its last channel was
+/// opened on, so the host keeps the same interchain account. Outposts without a recorded channel
+/// use the connection of their options. The controller derives the version from the connection.
+fn create_channel_msg(storage: &dyn Storage, outpost: &Outpost) -> Result<WasmMsg, ContractError> {
+    let mut options = outpost.channel_open_init_options.clone();
+    if let Some(channel) = ICA_CHANNELS.may_load(storage, &outpost.mars_red_bank_contract)? {
+        options.connection_id = channel.connection_id;
+    }
+
+    Ok(wasm_execute(
+        outpost.cw_ica_controller_contract.clone(),
+        &IcaControllerExecuteMsg::CreateChannel {
+            channel_open_init_options: Some(options),
+        },
+        vec![],
+    )?)
+}
+
+/// Reopens the ICA channel of an outpost after a packet timed out on it. Only ordered channels are
+/// closed by a timeout, channels opened with `ORDER_UNORDERED` stay open. The reopen is requested
+/// once until the reopened channel is acknowledged.
+pub fn reopen_channel(
+    storage: &mut dyn Storage,
+    env: &Env,
+    outpost: &Outpost,
+) -> Result<Option<WasmMsg>, ContractError> {
+    let destination = outpost.mars_red_bank_contract.as_str();
+    let order = match ICA_CHANNELS.may_load(storage, destination)? {
+        Some(channel) => channel.order,
+        None => outpost
+            .channel_open_init_options
+            .channel_ordering
+            .unwrap_or(IbcOrder::Ordered),
+    };
+    if order == IbcOrder::Unordered || CHANNEL_REOPENS.has(storage, destination) {
+        return Ok(None);
+    }
+    CHANNEL_REOPENS.save(storage, destination, &env.block.time)?;
+
+    create_channel_msg(storage, outpost).map(Some)
+}
+
+/// The ICA channel of each outpost, a reopen waiting for its acknowledgement and a reopened channel
+/// that reported another interchain account
+pub fn query_channels(deps: Deps<KujiraQuery>) -> StdResult<ChannelsResponse> {
+    let config = Config::load(deps.storage)?;
+    let channels = config
+        .outposts
+        .iter()
+        .map(|outpost| {
+            let destination = outpost.mars_red_bank_contract.as_str();
+            Ok(OutpostChannel {
+                destination: destination.to_string(),
+                ica_address: outpost.ica_address.clone(),
+                channel: ICA_CHANNELS.may_load(deps.storage, destination)?,
+                reopen_requested_at: CHANNEL_REOPENS.may_load(deps.storage, destination)?,
+                mismatch: ICA_MISMATCHES.may_load(deps.storage, destination)?,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(ChannelsResponse { channels })
+}
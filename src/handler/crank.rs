@@ -0,0 +1,373 @@
+use crate::{
+    config::Config,
+    error::ContractError,
+    helpers::{callback_update_stable_amt, ensure_fresh_nav},
+    msg::ExecuteMsg,
+    state::{BountyPoolResponse, State, BOUNTY_POOL, NAV_REFRESH, WITHDRAWAL_QUEUE},
+};
+use cosmwasm_std::{
+    ensure, wasm_execute, BankMsg, Coin, Deps, Env, MessageInfo, Order, QuerierWrapper, Response,
+    StdResult, Storage, SubMsg,
+};
+use kujira::{KujiraMsg, KujiraQuery};
+
+use super::{
+    epoch::{epoch_due, EPOCH_REPLY_ID},
+    rebalance::expire_returning_moves,
+    refresh_nav::try_refresh_nav,
+    retry::expire_operations,
+    withdraw::try_process_withdrawal_queue,
+};
+
+const DEFAULT_CRANK_LIMIT: u32 = 10;
+const MAX_CRANK_LIMIT: u32 = 30;
+
+/// Runs up to `limit` units of pending lifecycle work, in order:
+/// processing an ended epoch in a sub-message whose failure does not fail the crank, refreshing
+/// a stale NAV, retrying queued withdrawals one unit each, settling a NAV refresh that passed its
+/// deadline, rolling back expired failed operations and lending moves whose transfer to Kujira
+/// timed out again on the outpost they left, also one unit each.
+/// Work that is not due is skipped. The caller is paid the crank bounty from the bounty pool,
+/// or what is left of it, once any work was done.
+pub fn try_crank(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper<KujiraQuery>,
+    env: &Env,
+    info: &MessageInfo,
+    config: &Config,
+    state: &mut State,
+    limit: Option<u32>,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let limit = limit.unwrap_or(DEFAULT_CRANK_LIMIT).min(MAX_CRANK_LIMIT);
+    let mut response = Response::new().add_attribute("method", "crank");
+    let mut done = 0;
+
+    // The epoch is processed by a sub-message, so a failure only reverts its own changes.
+    // The NAV refresh it may start would be refused next to one of the crank.
+    let epoch = done < limit && epoch_due(storage, env, config)?;
+    if epoch {
+        let msg = wasm_execute(
+            env.contract.address.to_string(),
+            &ExecuteMsg::ProcessEpoch {},
+            vec![],
+        )?;
+        response = response
+            .add_submessage(SubMsg::reply_on_error(msg, EPOCH_REPLY_ID))
+            .add_attribute("cranked", "epoch");
+        done += 1;
+    }
+
+    if done < limit && !epoch && ensure_fresh_nav(storage, config, env.block.time).is_err() {
+        match try_refresh_nav(storage, env, config, state) {
+            Ok(refreshed) => {
+                response = merge(response, refreshed).add_attribute("cranked", "refresh_nav");
+                done += 1;
+            }
+            Err(ContractError::NavRefreshInProgress { .. }) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    let queued = WITHDRAWAL_QUEUE.len(storage)?;
+    if done < limit && queued > 0 {
+        let processed =
+            try_process_withdrawal_queue(storage, querier, env, config, state, Some(limit - done))?;
+        let retried = queued - WITHDRAWAL_QUEUE.len(storage)?;
+        if retried > 0 {
+            response = merge(response, processed).add_attribute("cranked", "withdrawal_queue");
+            done += retried;
+        }
+    }
+
+    if done < limit {
+        if let Some(mut refresh) = NAV_REFRESH.may_load(storage)? {
+            if refresh.in_progress() && refresh.expired(env.block.time) {
+                callback_update_stable_amt(storage, state, &mut refresh)?;
+                NAV_REFRESH.save(storage, &refresh)?;
+                response = response
+                    .add_attribute("cranked", "expired_refresh")
+                    .add_attribute("refresh_id", refresh.id.to_string());
+                done += 1;
+            }
+        }
+    }
+
+    if done < limit {
+        let (expired, count) = expire_operations(storage, env, config, state, limit - done)?;
+        if count > 0 {
+            response = merge(response, expired).add_attribute("cranked", "expired_operations");
+            done += count;
+        }
+    }
+
+    if done < limit {
+        let (expired, count) = expire_returning_moves(storage, env, config, state, limit - done)?;
+        if count > 0 {
+            response = merge(response, expired).add_attribute("cranked", "expired_moves");
+            done += count;
+        }
+    }
+
+    ensure!(done > 0, ContractError::NothingToCrank {});
+
+    let bounty = pay_bounty(storage, config)?;
+    if let Some(bounty) = &bounty {
+        response = response.add_message(BankMsg::Send {
+            to_address: info.sender.to_string(),
+            amount: vec![bounty.clone()],
+        });
+    }
+
+    Ok(response
+        .add_attribute("done", done.to_string())
+        .add_attribute(
+            "bounty",
+            bounty.map_or_else(|| "none".to_string(), |bounty| bounty.to_string()),
+        ))
+}
+
+/// Adds the messages and attributes of a cranked task, without its `method` attribute
+fn merge(response: Response<KujiraMsg>, task: Response<KujiraMsg>) -> Response<KujiraMsg> {
+    response.add_submessages(task.messages).add_attributes(
+        task.attributes
+            .into_iter()
+            .filter(|attribute| attribute.key != "method"),
+    )
+}
+
+/// Takes the crank bounty out of the bounty pool, or what is left of it in its denom
+fn pay_bounty(storage: &mut dyn Storage, config: &Config) -> StdResult<Option<Coin>> {
+    let Some(bounty) = &config.crank_bounty else {
+        return Ok(None);
+    };
+    let pool = BOUNTY_POOL
+        .may_load(storage, &bounty.denom)?
+        .unwrap_or_default();
+    let amount = bounty.amount.min(pool);
+    if amount.is_zero() {
+        return Ok(None);
+    }
+    BOUNTY_POOL.save(storage, &bounty.denom, &(pool - amount))?;
+
+    Ok(Some(Coin {
+        denom: bounty.denom.clone(),
+        amount,
+    }))
+}
+
+/// Adds the funds sent along to the bounty pool
+pub fn try_fund_bounty_pool(
+    storage: &mut dyn Storage,
+    info: &MessageInfo,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    ensure!(!info.funds.is_empty(), ContractError::InvalidAmount {});
+    for coin in &info.funds {
+        BOUNTY_POOL.update(storage, &coin.denom, |pool| {
+            Ok::<_, ContractError>(pool.unwrap_or_default() + coin.amount)
+        })?;
+    }
+
+    Ok(Response::new()
+        .add_attribute("method", "fund_bounty_pool")
+        .add_attribute(
+            "funds",
+            info.funds
+                .iter()
+                .map(Coin::to_string)
+                .collect::<Vec<_>>()
+                .join(","),
+        ))
+}
+
+pub fn query_bounty_pool(deps: Deps<KujiraQuery>) -> StdResult<BountyPoolResponse> {
+    let funds = BOUNTY_POOL
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(denom, amount)| Coin { denom, amount }))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(BountyPoolResponse { funds })
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::{message_info, mock_env, MockStorage};
+    use cosmwasm_std::{coin, Addr, CosmosMsg, Decimal, ReplyOn, Timestamp};
+
+    use super::*;
+    use crate::state::{Epoch, EPOCH, FAILED_OPERATIONS};
+    use crate::testing::{fail, mock_config, mock_oracle, mock_outpost};
+
+    fn paid(response: &Response<KujiraMsg>) -> Option<Coin> {
+        response.messages.iter().find_map(|msg| match &msg.msg {
+            CosmosMsg::Bank(BankMsg::Send { amount, .. }) => Some(amount[0].clone()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_crank_without_due_work() {
+        let mut storage = MockStorage::new();
+        let oracle = mock_oracle(Decimal::one());
+        let querier = QuerierWrapper::<KujiraQuery>::new(&oracle);
+        let env = mock_env();
+        let info = message_info(&Addr::unchecked("keeper"), &[]);
+        let config = Config {
+            crank_bounty: Some(coin(10, "ukuji")),
+            ..mock_config(vec![mock_outpost("a", "osmosis-1", "uusdc")])
+        };
+        BOUNTY_POOL
+            .save(&mut storage, "ukuji", &100u128.into())
+            .unwrap();
+
+        // A failure that has not expired yet is no work, nothing is paid
+        fail(&mut storage, &env, &config, "deposit/1");
+        let err = try_crank(
+            &mut storage,
+            &querier,
+            &env,
+            &info,
+            &config,
+            &mut State::default(),
+            None,
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::NothingToCrank {}));
+        assert_eq!(BOUNTY_POOL.load(&storage, "ukuji").unwrap().u128(), 100);
+    }
+
+    #[test]
+    fn test_crank_pays_bounty_from_pool() {
+        let mut storage = MockStorage::new();
+        let oracle = mock_oracle(Decimal::one());
+        let querier = QuerierWrapper::<KujiraQuery>::new(&oracle);
+        let mut env = mock_env();
+        let info = message_info(&Addr::unchecked("keeper"), &[]);
+        let config = Config {
+            crank_bounty: Some(coin(10, "ukuji")),
+            ..mock_config(vec![mock_outpost("a", "osmosis-1", "uusdc")])
+        };
+        let mut state = State::default();
+        BOUNTY_POOL
+            .save(&mut storage, "ukuji", &15u128.into())
+            .unwrap();
+
+        fail(&mut storage, &env, &config, "deposit/1");
+        fail(&mut storage, &env, &config, "deposit/2");
+        env.block.time = env.block.time.plus_seconds(60);
+
+        // Both failures expire in one crank that is paid a single bounty
+        let response = try_crank(
+            &mut storage,
+            &querier,
+            &env,
+            &info,
+            &config,
+            &mut state,
+            None,
+        )
+        .unwrap();
+        assert_eq!(paid(&response), Some(coin(10, "ukuji")));
+        assert!(response
+            .attributes
+            .iter()
+            .any(|attribute| attribute.key == "done" && attribute.value == "2"));
+
+        // The next crank only gets what is left of the pool
+        fail(&mut storage, &env, &config, "deposit/3");
+        env.block.time = env.block.time.plus_seconds(60);
+        let response = try_crank(
+            &mut storage,
+            &querier,
+            &env,
+            &info,
+            &config,
+            &mut state,
+            None,
+        )
+        .unwrap();
+        assert_eq!(paid(&response), Some(coin(5, "ukuji")));
+
+        // An empty pool still lets the work be done
+        fail(&mut storage, &env, &config, "deposit/4");
+        env.block.time = env.block.time.plus_seconds(60);
+        let response = try_crank(
+            &mut storage,
+            &querier,
+            &env,
+            &info,
+            &config,
+            &mut state,
+            None,
+        )
+        .unwrap();
+        assert_eq!(paid(&response), None);
+        assert!(BOUNTY_POOL.load(&storage, "ukuji").unwrap().is_zero());
+    }
+
+    #[test]
+    fn test_crank_dispatches_due_epoch() {
+        let mut storage = MockStorage::new();
+        let oracle = mock_oracle(Decimal::one());
+        let querier = QuerierWrapper::<KujiraQuery>::new(&oracle);
+        let env = mock_env();
+        let info = message_info(&Addr::unchecked("keeper"), &[]);
+        let config = Config {
+            epoch_length: Some(60),
+            ..mock_config(vec![mock_outpost("a", "osmosis-1", "uusdc")])
+        };
+        EPOCH
+            .save(&mut storage, &Epoch::new(0, Timestamp::from_seconds(0)))
+            .unwrap();
+
+        // The epoch runs on its own and the crank leaves the NAV refresh to it
+        let response = try_crank(
+            &mut storage,
+            &querier,
+            &env,
+            &info,
+            &config,
+            &mut State::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(response.messages.len(), 1);
+        assert_eq!(response.messages[0].id, EPOCH_REPLY_ID);
+        assert_eq!(response.messages[0].reply_on, ReplyOn::Error);
+        assert!(!NAV_REFRESH.exists(&storage));
+    }
+
+    #[test]
+    fn test_crank_respects_limit() {
+        let mut storage = MockStorage::new();
+        let oracle = mock_oracle(Decimal::one());
+        let querier = QuerierWrapper::<KujiraQuery>::new(&oracle);
+        let mut env = mock_env();
+        let info = message_info(&Addr::unchecked("keeper"), &[]);
+        let config = Config {
+            crank_bounty: Some(coin(10, "ukuji")),
+            ..mock_config(vec![mock_outpost("a", "osmosis-1", "uusdc")])
+        };
+        let mut state = State::default();
+
+        for id in 1..=3 {
+            fail(&mut storage, &env, &config, &format!("deposit/{}", id));
+        }
+        env.block.time = env.block.time.plus_seconds(60);
+
+        try_crank(
+            &mut storage,
+            &querier,
+            &env,
+            &info,
+            &config,
+            &mut state,
+            Some(2),
+        )
+        .unwrap();
+        assert_eq!(
+            FAILED_OPERATIONS
+                .range(&storage, None, None, Order::Ascending)
+                .count(),
+            1
+        );
+    }
+}
@@ -1,37 +1,236 @@
-use crate::{config::Config, error::ContractError, msg::Positions};
-use cosmwasm_std::{to_json_binary, Response, WasmQuery};
-use kujira::KujiraMsg;
+use crate::{
+    config::Config,
+    error::ContractError,
+    helpers::ensure_min_request,
+    msg::{DepositMsg, Outpost},
+    oracle::usd_value,
+    state::{
+        next_operation_id, DepositStage, PendingDeposit, PendingSwap, Request, RequestKind,
+        RequestStatus, State, TransferKind, EPOCH, EPOCH_DEPOSITS, PENDING_DEPOSITS, REQUESTS,
+        VIRTUAL_RECEIPTS,
+    },
+};
+use cosmwasm_std::{
+    ensure, Addr, Coin, DepsMut, Empty, Env, MessageInfo, QuerierWrapper, Response, Storage,
+    Uint128,
+};
+use cw_utils::one_coin;
+use kujira::{KujiraMsg, KujiraQuery};
 
-use super::ica::query_ica;
+use super::{
+    allocate::{allocate_deposit, ensure_exposure, AUTO_DESTINATION},
+    buffer::fill_buffer,
+    ica::{outpost_queries, query_ica, IcaOperation},
+    in_flight::{begin_operation, ensure_capacity, OperationKind},
+    request::{credit_deposit_request, record_deposit_assets},
+    swap::swap_deposit,
+    transfer::transfer_to_outpost,
+};
 
-/// Creates the correct red_bank deposit message based on the user input and
-/// calls the corresponding CW ICA Controller to execute the deposit on the target chain.
-/// The user is then credited an amount of virtual receipt tokens.
+/// Escrows the deposit of the user and transfers it to the ICA on the target chain.
+/// Deposits to the `auto` destination go to the outpost furthest below its target weight.
+/// Deposits in another whitelisted denom are swapped into the base denom through FIN first.
+/// Deposits worth less than the minimum request value are refused.
+/// Once the transfer is acknowledged, the deposit is priced and lent through the CW ICA Controller.
+/// The user is then credited an amount of virtual receipt tokens, right away for plain deposits
+/// or on claim for deposit requests.
+#[allow(clippy::too_many_arguments)]
 pub fn try_deposit(
+    deps: DepsMut<KujiraQuery>,
+    env: &Env,
+    info: &MessageInfo,
     config: &Config,
-    destination: String,
+    state: &mut State,
+    owner: Addr,
+    msg: DepositMsg,
+    auto_claim: bool,
 ) -> Result<Response<KujiraMsg>, ContractError> {
-    // Check if a vault exists on the target chain.
-    let outpost = config
-        .find_destination_outpost(&destination)
-        .ok_or(ContractError::DestinationNotFound { destination })?;
+    let coin = one_coin(info)?;
+    let value = usd_value(&deps.querier, config, &coin)?;
+    ensure_min_request(config, value)?;
+
+    let outpost = if msg.destination == AUTO_DESTINATION {
+        allocate_deposit(deps.storage, config, state, &coin.denom, value)?
+    } else {
+        // Check if a vault exists on the target chain.
+        let outpost = config.find_destination_outpost(&msg.destination).ok_or(
+            ContractError::DestinationNotFound {
+                destination: msg.destination.clone(),
+            },
+        )?;
+        if outpost.max_exposure.is_some() {
+            ensure_exposure(deps.storage, state, outpost, value)?;
+        }
+        ensure_capacity(deps.storage, outpost, OperationKind::Deposit)?;
+        outpost
+    };
+    let destination = outpost.mars_red_bank_contract.clone();
 
     // Check if account_id exists
-    let account_id = outpost
-        .account_id
-        .as_ref()
-        .cloned()
-        .ok_or(ContractError::NoCreditAccount {})?;
-
-    let query = cosmwasm_std::QueryRequest::Wasm(WasmQuery::Smart {
-        contract_addr: outpost.mars_red_bank_contract.clone(),
-        msg: to_json_binary(&Positions { account_id })?,
-    });
+    if outpost.account_id.is_none() {
+        return Err(ContractError::NoCreditAccount {});
+    }
+
+    let swapped = coin.denom != outpost.base_denom;
+
+    // The request tracks the deposit from here on, including its swap
+    let id = next_operation_id(deps.storage)?;
+    REQUESTS.save(
+        deps.storage,
+        (&owner, id),
+        &Request {
+            kind: RequestKind::Deposit,
+            status: RequestStatus::Pending,
+            auto_claim,
+            destination: destination.clone(),
+            denom: outpost.base_denom.clone(),
+            assets: if swapped {
+                Uint128::zero()
+            } else {
+                coin.amount
+            },
+            shares: Uint128::zero(),
+            legs: 0,
+            created_at: env.block.time,
+        },
+    )?;
+
+    if swapped {
+        let swap = PendingSwap {
+            id,
+            owner,
+            destination,
+            offer: coin,
+            balance_before: Uint128::zero(),
+            min_return: msg.min_return,
+            min_shares_out: msg.min_shares_out,
+        };
+        return swap_deposit(deps, env, config, swap, &outpost.base_denom);
+    }
+
+    let deposit = PendingDeposit::new(owner, coin, outpost, msg.min_shares_out, env.block.time);
+    create_deposit(
+        deps.storage,
+        &deps.querier,
+        env,
+        config,
+        state,
+        outpost,
+        id,
+        deposit,
+    )
+}
+
+/// Saves the pending deposit of funds in the base denom of the outpost and transfers them to its ICA.
+/// The part the liquidity buffer lacks is kept on Kujira first and priced at once, see `fill_buffer`.
+#[allow(clippy::too_many_arguments)]
+pub fn create_deposit(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper<KujiraQuery>,
+    env: &Env,
+    config: &Config,
+    state: &mut State,
+    outpost: &Outpost,
+    id: u64,
+    mut deposit: PendingDeposit,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    // Only whitelisted denoms can be priced once the deposit arrived
+    config.find_denom(&outpost.base_denom)?;
+    record_deposit_assets(storage, &deposit.owner, id, deposit.coin.amount)?;
+
+    let owner = deposit.owner.clone();
+    let amount = deposit.coin.amount;
+    let mut response = Response::new()
+        .add_attribute("method", "deposit")
+        .add_attribute("deposit_id", id.to_string())
+        .add_attribute("owner", owner.clone())
+        .add_attribute("amount", amount);
+
+    let (kept, shares) = fill_buffer(storage, querier, config, state, &deposit.coin)?;
+    if !kept.is_zero() {
+        // The minimum receipts are split between the kept and the bridged part
+        let min_shares_out = deposit.min_shares_out.unwrap_or_default();
+        let kept_min = min_shares_out.multiply_ratio(kept, amount);
+        ensure!(
+            shares >= kept_min,
+            ContractError::MinSharesNotMet {
+                min_shares_out: kept_min,
+                shares,
+            }
+        );
+        deposit.min_shares_out = deposit.min_shares_out.map(|min| min - kept_min);
+        deposit.coin.amount -= kept;
+
+        // Receipts of deposit requests are only credited once claimed
+        let settled = deposit.coin.amount.is_zero();
+        if credit_deposit_request(storage, &owner, id, shares, settled)? {
+            VIRTUAL_RECEIPTS.update(storage, owner.clone(), |existing| {
+                Ok::<_, ContractError>(existing.unwrap_or_default() + shares)
+            })?;
+        }
+        response = response
+            .add_attribute("buffered", kept)
+            .add_attribute("shares", shares);
+        if settled {
+            return Ok(response);
+        }
+    }
+
+    begin_operation(storage, outpost, OperationKind::Deposit)?;
+    PENDING_DEPOSITS.save(storage, id, &deposit)?;
+    let transfer = transfer_to_outpost(
+        storage,
+        env,
+        outpost,
+        deposit.coin,
+        TransferKind::Deposit,
+        id,
+        owner,
+    )?;
+
+    Ok(response.add_submessage(transfer))
+}
+
+/// Queries the positions of the outpost once the deposit arrived in the ICA,
+/// so the deposit can be priced against the current value of the outpost.
+/// With epochs enabled the deposit waits in the ICA until its epoch is processed instead.
+pub fn deposit_transferred(
+    storage: &mut dyn Storage,
+    config: &Config,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let mut deposit = PENDING_DEPOSITS.load(storage, id)?;
+    if config.epoch_length.is_some() {
+        let epoch = EPOCH.load(storage)?.open_id();
+        deposit.stage = DepositStage::Queued;
+        deposit.epoch = Some(epoch);
+        PENDING_DEPOSITS.save(storage, id, &deposit)?;
+        EPOCH_DEPOSITS.save(storage, (epoch, id), &Empty {})?;
+
+        return Ok(Response::new()
+            .add_attribute("method", "deposit_transferred")
+            .add_attribute("deposit_id", id.to_string())
+            .add_attribute("epoch", epoch.to_string()));
+    }
+
+    let outpost = config
+        .find_destination_outpost(&deposit.destination)
+        .ok_or(ContractError::DestinationNotFound {
+            destination: deposit.destination.clone(),
+        })?;
+
     let msg = query_ica(
         outpost.cw_ica_controller_contract.clone(),
-        Some("deposit".to_string()),
-        vec![query],
+        Some(format!("deposit/{}", id)),
+        outpost_queries(outpost)?,
+        outpost.ica_timeout(IcaOperation::Query),
     )?;
 
-    Ok(Response::new().add_message(msg))
+    deposit.stage = DepositStage::Pricing;
+    PENDING_DEPOSITS.save(storage, id, &deposit)?;
+
+    Ok(Response::new()
+        .add_message(msg)
+        .add_attribute("method", "deposit_transferred")
+        .add_attribute("deposit_id", id.to_string()))
 }
@@ -0,0 +1,1262 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    config::Config,
+    error::ContractError,
+    helpers::{callback_update_stable_amt, ensure_outpost_hook},
+    msg::{ExecuteMsg, Outpost},
+    oracle::{amount_for_usd, usd_value},
+    state::{
+        next_operation_id, DepositStage, Epoch, EpochBatch, EpochFlows, EpochSettlement, Payout,
+        PendingWithdrawal, RequestStatus, State, WithdrawStage, EPOCH, EPOCH_BATCHES,
+        EPOCH_DEPOSITS, EPOCH_FLOWS, EPOCH_SETTLEMENT, EPOCH_WITHDRAWALS, NAV_REFRESH, OUTPOST_NAV,
+        PENDING_DEPOSITS, PENDING_REFUNDS, PENDING_WITHDRAWALS, VIRTUAL_RECEIPTS,
+    },
+};
+use cosmwasm_std::{
+    ensure, wasm_execute, Addr, Api, BankMsg, Coin, CosmosMsg, Empty, Env, MessageInfo, Order,
+    QuerierWrapper, Reply, Response, StdResult, Storage, SubMsgResult, Uint128, WasmMsg,
+};
+use cw_ica_controller::types::query_msg::IcaQueryResult;
+use cw_storage_plus::{Bound, Map};
+use kujira::{KujiraMsg, KujiraQuery};
+use mars_types::credit_manager::{Action, ActionCoin, ExecuteMsg as CreditManagerExecuteMsg};
+
+use super::{
+    callback::refund_deposit,
+    ica::{available_liquidity, execute_ica, market_query, parse_market, query_ica, IcaOperation},
+    in_flight::{can_commit, end_operation, track_operation, OperationKind},
+    refresh_nav::try_refresh_nav,
+    refund::{refund_failed, track_refund},
+    request::{
+        close_request, is_cancelled, price_redeem_request, revoke_deposit_shares,
+        settle_deposit_request, settle_redeem_leg,
+    },
+    retry::{awaits_retry, resend_failure},
+    route::route_withdrawal,
+    transfer::{ica_transfer_msg, wasm_hook_memo},
+    withdraw::{pay_withdrawal, requeue_unfilled, return_receipts},
+};
+
+/// Requests of a closing epoch priced by a single call of `settle_epoch`
+const SETTLE_PAGE: u32 = 20;
+
+/// Reply id of the epoch processing dispatched by a crank
+pub const EPOCH_REPLY_ID: u64 = 3;
+
+/// Closes the current epoch once it ended by measuring the NAV of every outpost.
+/// Once the refresh settled, every call settles a page of the requests of the epoch, see
+/// `settle_epoch`. If the refresh expires without all answers, it is settled with the positions
+/// it received first.
+pub fn try_process_epoch(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper<KujiraQuery>,
+    env: &Env,
+    config: &Config,
+    state: &mut State,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let epoch_length = config
+        .epoch_length
+        .ok_or(ContractError::EpochsDisabled {})?;
+    let mut epoch = EPOCH.load(storage)?;
+    ensure!(
+        epoch.ended(env.block.time, epoch_length),
+        ContractError::EpochNotEnded { id: epoch.id }
+    );
+
+    // A settlement under way prices the rest of the epoch at the NAV it started with
+    if EPOCH_SETTLEMENT.exists(storage) {
+        return settle_epoch(storage, querier, env, config, state);
+    }
+
+    if !epoch.closing {
+        // A refresh still waiting for answers would refuse to start, the epoch stays open until then
+        if let Some(refresh) = NAV_REFRESH.may_load(storage)? {
+            ensure!(
+                !refresh.in_progress() || refresh.expired(env.block.time),
+                ContractError::NavRefreshInProgress { id: refresh.id }
+            );
+        }
+        epoch.closing = true;
+        EPOCH.save(storage, &epoch)?;
+        return Ok(try_refresh_nav(storage, env, config, state)?
+            .add_attribute("epoch", epoch.id.to_string()));
+    }
+
+    let mut refresh = NAV_REFRESH.load(storage)?;
+    ensure!(
+        !refresh.in_progress() || refresh.expired(env.block.time),
+        ContractError::NavRefreshInProgress { id: refresh.id }
+    );
+    if refresh.in_progress() {
+        callback_update_stable_amt(storage, state, &mut refresh)?;
+        NAV_REFRESH.save(storage, &refresh)?;
+    }
+
+    settle_epoch(storage, querier, env, config, state)
+}
+
+/// Whether processing the epoch has work to do: the epoch ended and either its settlement is
+/// under way or no NAV refresh is waiting for answers
+pub fn epoch_due(storage: &dyn Storage, env: &Env, config: &Config) -> StdResult<bool> {
+    let (Some(epoch_length), Some(epoch)) = (config.epoch_length, EPOCH.may_load(storage)?) else {
+        return Ok(false);
+    };
+    if !epoch.ended(env.block.time, epoch_length) {
+        return Ok(false);
+    }
+    if EPOCH_SETTLEMENT.exists(storage) {
+        return Ok(true);
+    }
+    Ok(NAV_REFRESH.may_load(storage)?.map_or(true, |refresh| {
+        !refresh.in_progress() || refresh.expired(env.block.time)
+    }))
+}
+
+/// Reports why processing the epoch failed when a crank dispatched it. Its changes are reverted,
+/// the rest of the crank goes through.
+pub fn handle_epoch_reply(reply: Reply) -> Result<Response<KujiraMsg>, ContractError> {
+    let error = match reply.result {
+        SubMsgResult::Err(error) => error,
+        SubMsgResult::Ok(_) => String::new(),
+    };
+    Ok(Response::new()
+        .add_attribute("method", "epoch_reply")
+        .add_attribute("error", error))
+}
+
+/// Epochs can only be turned off while the open epoch holds no deposits or withdrawals, they would
+/// never be processed otherwise
+pub fn ensure_epoch_empty(storage: &dyn Storage) -> Result<(), ContractError> {
+    let Some(epoch) = EPOCH.may_load(storage)? else {
+        return Ok(());
+    };
+    let empty = !epoch.closing
+        && epoch_ids(storage, EPOCH_DEPOSITS, epoch.id)?.is_empty()
+        && epoch_ids(storage, EPOCH_WITHDRAWALS, epoch.id)?.is_empty();
+    ensure!(empty, ContractError::EpochNotEmpty { id: epoch.id });
+    Ok(())
+}
+
+/// Prices the deposits and withdrawals of the closing epoch at the NAV it ended with, at most
+/// `SETTLE_PAGE` of them per call. Deposits are priced first, withdrawals can be paid out of them.
+/// Deposits that were cancelled or missed their minimum are refunded, withdrawals that missed
+/// their minimum get their receipts back. Once every request is priced, the inflows are netted
+/// against the outflows of each outpost and a single ICA transaction is sent per outpost.
+pub fn settle_epoch(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper<KujiraQuery>,
+    env: &Env,
+    config: &Config,
+    state: &mut State,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let epoch = EPOCH.load(storage)?;
+    let mut settlement = match EPOCH_SETTLEMENT.may_load(storage)? {
+        Some(settlement) => settlement,
+        None => EpochSettlement {
+            nav: state.clone(),
+            last_deposit: None,
+            deposits_done: false,
+            last_withdrawal: None,
+            minted: Uint128::zero(),
+            burned: Uint128::zero(),
+        },
+    };
+    let nav = settlement.nav.clone();
+    let mut flows = EPOCH_FLOWS
+        .range(storage, None, None, Order::Ascending)
+        .collect::<StdResult<BTreeMap<_, _>>>()?;
+    let mut budget = SETTLE_PAGE;
+
+    if !settlement.deposits_done {
+        let ids = epoch_page(
+            storage,
+            EPOCH_DEPOSITS,
+            epoch.id,
+            settlement.last_deposit,
+            budget,
+        )?;
+        settlement.deposits_done = ids.len() < budget as usize;
+        budget -= ids.len() as u32;
+        settlement.last_deposit = ids.last().copied().or(settlement.last_deposit);
+        settle_deposits(
+            storage,
+            querier,
+            config,
+            epoch.id,
+            &mut settlement,
+            &mut flows,
+            ids,
+        )?;
+    }
+
+    let mut withdrawals_done = false;
+    if settlement.deposits_done {
+        let ids = epoch_page(
+            storage,
+            EPOCH_WITHDRAWALS,
+            epoch.id,
+            settlement.last_withdrawal,
+            budget,
+        )?;
+        withdrawals_done = ids.len() < budget as usize;
+        budget -= ids.len() as u32;
+        settlement.last_withdrawal = ids.last().copied().or(settlement.last_withdrawal);
+        settle_withdrawals(
+            storage,
+            querier,
+            config,
+            epoch.id,
+            &mut settlement,
+            &mut flows,
+            ids,
+        )?;
+    }
+
+    if !withdrawals_done {
+        EPOCH_SETTLEMENT.save(storage, &settlement)?;
+        for (destination, flow) in &flows {
+            EPOCH_FLOWS.save(storage, destination, flow)?;
+        }
+        return Ok(Response::new()
+            .add_attribute("method", "settle_epoch")
+            .add_attribute("epoch", epoch.id.to_string())
+            .add_attribute("priced", (SETTLE_PAGE - budget).to_string()));
+    }
+
+    EPOCH_SETTLEMENT.remove(storage);
+    state.virtual_receipt = state.virtual_receipt + settlement.minted - settlement.burned;
+    let mut msgs = vec![];
+    for (destination, mut flow) in flows {
+        EPOCH_FLOWS.remove(storage, &destination);
+        let outpost = config.find_destination_outpost(&destination).ok_or(
+            ContractError::DestinationNotFound {
+                destination: destination.clone(),
+            },
+        )?;
+        let batch = apply_flows(storage, &destination, &mut flow)?;
+        EPOCH_BATCHES.save(storage, (epoch.id, &destination), &batch)?;
+        msgs.push(epoch_ica_msg(storage, env, outpost, epoch.id, flow)?);
+    }
+    state.sync_nav(storage)?;
+
+    EPOCH.save(storage, &Epoch::new(epoch.id + 1, env.block.time))?;
+
+    Ok(Response::new()
+        .add_messages(msgs)
+        .add_attribute("method", "settle_epoch")
+        .add_attribute("epoch", epoch.id.to_string())
+        .add_attribute(
+            "deposit_redemption_rate",
+            nav.deposit_redemption_rate.to_string(),
+        ))
+}
+
+/// Prices a page of the deposits of the closing epoch and books them into the flows of their outpost.
+/// Deposits on outposts the epoch cannot commit to are carried over to the next epoch.
+fn settle_deposits(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper<KujiraQuery>,
+    config: &Config,
+    epoch: u64,
+    settlement: &mut EpochSettlement,
+    flows: &mut BTreeMap<String, EpochFlows>,
+    ids: Vec<u64>,
+) -> Result<(), ContractError> {
+    let nav = settlement.nav.clone();
+    let blocked = blocked_outposts(storage, config, OperationKind::Deposit)?;
+    for id in ids {
+        let mut deposit = PENDING_DEPOSITS.load(storage, id)?;
+        // The funds wait in the ICA of their outpost until the epoch can commit to it again
+        if blocked.contains(&deposit.destination) {
+            EPOCH_DEPOSITS.remove(storage, (epoch, id));
+            EPOCH_DEPOSITS.save(storage, (epoch + 1, id), &Empty {})?;
+            deposit.epoch = Some(epoch + 1);
+            PENDING_DEPOSITS.save(storage, id, &deposit)?;
+            continue;
+        }
+        let flow = flows.entry(deposit.destination.clone()).or_default();
+
+        let value = usd_value(querier, config, &deposit.coin)?;
+        let shares = nav.shares_for(value);
+        if is_cancelled(storage, &deposit.owner, id)?
+            || deposit
+                .min_shares_out
+                .is_some_and(|min_shares_out| shares < min_shares_out)
+        {
+            PENDING_DEPOSITS.remove(storage, id);
+            EPOCH_DEPOSITS.remove(storage, (epoch, id));
+            end_operation(storage, &deposit.destination, OperationKind::Deposit)?;
+            close_request(storage, &deposit.owner, id, RequestStatus::Cancelled)?;
+            flow.refunds.push((id, deposit.owner, deposit.coin.amount));
+            continue;
+        }
+
+        if settle_deposit_request(storage, &deposit.owner, id, shares)? {
+            VIRTUAL_RECEIPTS.update(storage, deposit.owner.clone(), |existing| {
+                Ok::<_, ContractError>(existing.unwrap_or_default() + shares)
+            })?;
+        }
+        settlement.minted += shares;
+
+        flow.deposited_value += value;
+        flow.deposited += deposit.coin.amount;
+        deposit.value = value;
+        deposit.shares = shares;
+        deposit.stage = DepositStage::Lending;
+        PENDING_DEPOSITS.save(storage, id, &deposit)?;
+    }
+    Ok(())
+}
+
+/// Routes a page of the withdrawals of the closing epoch across the outposts and books their legs
+/// into the flows of each outpost. The legs of withdrawals routed before are skipped, outposts the
+/// epoch cannot commit to pay out no legs.
+fn settle_withdrawals(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper<KujiraQuery>,
+    config: &Config,
+    epoch: u64,
+    settlement: &mut EpochSettlement,
+    flows: &mut BTreeMap<String, EpochFlows>,
+    ids: Vec<u64>,
+) -> Result<(), ContractError> {
+    let nav = settlement.nav.clone();
+    let blocked = blocked_outposts(storage, config, OperationKind::Withdrawal)?;
+    for id in ids {
+        let withdrawal = PENDING_WITHDRAWALS.load(storage, id)?;
+        if withdrawal.stage != WithdrawStage::Queued {
+            continue;
+        }
+        let value = nav.assets_for(withdrawal.shares);
+
+        // Outposts can pay out of the value they hold and the deposits they receive this epoch
+        let chain = match &withdrawal.payout {
+            Payout::Host { chain, .. } => Some(chain.as_str()),
+            _ => None,
+        };
+        // The legs of a processed epoch are committed, so outposts take them beyond their limit,
+        // but never while their ICA is mismatched or a move is in flight
+        let routed = route_withdrawal(
+            storage,
+            querier,
+            config,
+            value,
+            chain,
+            |outpost| {
+                flows
+                    .get(&outpost.mars_red_bank_contract)
+                    .map_or((Uint128::zero(), Uint128::zero()), |flow| {
+                        (flow.deposited_value, flow.withdrawn_value)
+                    })
+            },
+            |outpost| !blocked.contains(&outpost.mars_red_bank_contract),
+        );
+        let legs = match routed {
+            Ok(legs) => legs,
+            Err(ContractError::InsufficientLiquidity { .. }) => vec![],
+            Err(err) => return Err(err),
+        };
+        let amounts = legs
+            .iter()
+            .map(|leg| amount_for_usd(querier, config, &leg.outpost.host_denom, leg.value))
+            .collect::<Result<Vec<_>, _>>()?;
+        let amount: Uint128 = amounts.iter().sum();
+
+        // Withdrawals that cannot be filled or miss their minimum get their receipts back
+        if legs.is_empty()
+            || withdrawal
+                .min_assets_out
+                .is_some_and(|min_assets_out| amount < min_assets_out)
+        {
+            PENDING_WITHDRAWALS.remove(storage, id);
+            EPOCH_WITHDRAWALS.remove(storage, (epoch, id));
+            VIRTUAL_RECEIPTS.update(storage, withdrawal.owner.clone(), |existing| {
+                Ok::<_, ContractError>(existing.unwrap_or_default() + withdrawal.shares)
+            })?;
+            close_request(storage, &withdrawal.owner, id, RequestStatus::Cancelled)?;
+            continue;
+        }
+
+        settlement.burned += withdrawal.shares;
+        price_redeem_request(
+            storage,
+            &withdrawal.owner,
+            id,
+            legs[0].outpost,
+            amount,
+            legs.len() as u32,
+        )?;
+
+        for (index, (leg, amount)) in legs.into_iter().zip(amounts).enumerate() {
+            let leg_id = match index {
+                0 => id,
+                _ => next_operation_id(storage)?,
+            };
+            let destination = leg.outpost.mars_red_bank_contract.clone();
+
+            let flow = flows.entry(destination.clone()).or_default();
+            flow.withdrawn_value += leg.value;
+            flow.withdrawn += amount;
+            match &withdrawal.payout {
+                Payout::Host { recipient, .. } => {
+                    flow.host_payouts.push((recipient.clone(), amount))
+                }
+                Payout::Local | Payout::Remote { .. } | Payout::Buffer => flow.returning += amount,
+            }
+
+            let leg_withdrawal = PendingWithdrawal {
+                shares: withdrawal.shares.multiply_ratio(leg.value, value),
+                amount,
+                value: leg.value,
+                // Reclaimed for all legs at once by the transaction of the epoch
+                reclaim: Uint128::zero(),
+                destination,
+                stage: WithdrawStage::Reclaiming,
+                ..withdrawal.clone()
+            };
+            PENDING_WITHDRAWALS.save(storage, leg_id, &leg_withdrawal)?;
+            EPOCH_WITHDRAWALS.save(storage, (epoch, leg_id), &Empty {})?;
+            // The epoch is already committed, its legs are sent in one transaction per outpost
+            track_operation(
+                storage,
+                &leg_withdrawal.destination,
+                OperationKind::Withdrawal,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Destinations of the outposts the epoch cannot commit operations of `kind` to, see `can_commit`
+fn blocked_outposts(
+    storage: &dyn Storage,
+    config: &Config,
+    kind: OperationKind,
+) -> StdResult<BTreeSet<String>> {
+    let mut blocked = BTreeSet::new();
+    for outpost in &config.outposts {
+        if !can_commit(storage, outpost, kind)? {
+            blocked.insert(outpost.mars_red_bank_contract.clone());
+        }
+    }
+    Ok(blocked)
+}
+
+/// Books the netted flows of an outpost into its NAV and returns the batch awaiting the
+/// acknowledgement of its transaction. Net inflows are pending until the lend is acknowledged,
+/// net outflows are reclaimed from the lent funds first and taken from the idle funds for the rest.
+fn apply_flows(
+    storage: &mut dyn Storage,
+    destination: &str,
+    flow: &mut EpochFlows,
+) -> StdResult<EpochBatch> {
+    let mut nav = OUTPOST_NAV
+        .may_load(storage, destination)?
+        .unwrap_or_default();
+
+    let mut batch = EpochBatch {
+        lent_value: Uint128::zero(),
+        reclaimed_value: Uint128::zero(),
+        idle_value: Uint128::zero(),
+        reclaim: Uint128::zero(),
+        refunds: flow.refunds.iter().map(|(id, _, _)| *id).collect(),
+    };
+    if flow.deposited_value >= flow.withdrawn_value {
+        batch.lent_value = flow.deposited_value - flow.withdrawn_value;
+        nav.pending += batch.lent_value;
+    } else {
+        let net_value = flow.withdrawn_value - flow.deposited_value;
+        batch.reclaimed_value = net_value.min(nav.lent);
+        batch.idle_value = (net_value - batch.reclaimed_value).min(nav.idle);
+        nav.lent -= batch.reclaimed_value;
+        nav.idle -= batch.idle_value;
+        flow.reclaim = flow
+            .withdrawn
+            .saturating_sub(flow.deposited)
+            .multiply_ratio(batch.reclaimed_value, net_value);
+        batch.reclaim = flow.reclaim;
+    }
+
+    OUTPOST_NAV.save(storage, destination, &nav)?;
+    Ok(batch)
+}
+
+/// Builds the single ICA transaction settling the flows of an outpost, see `epoch_msgs`
+fn epoch_ica_msg(
+    storage: &mut dyn Storage,
+    env: &Env,
+    outpost: &Outpost,
+    epoch: u64,
+    flow: EpochFlows,
+) -> Result<WasmMsg, ContractError> {
+    execute_ica(
+        outpost.cw_ica_controller_contract.clone(),
+        Some(format!(
+            "epoch/{}/{}",
+            epoch, outpost.mars_red_bank_contract
+        )),
+        epoch_msgs(storage, env, outpost, epoch, flow)?,
+        vec![],
+        outpost.ica_timeout(IcaOperation::Epoch),
+    )
+}
+
+/// Messages settling the flows of an outpost. Net inflows are deposited and lent, net outflows are
+/// reclaimed and withdrawn. The ICA then refunds cancelled deposits, pays recipients on the outpost
+/// chain and transfers all other payouts back to this contract.
+fn epoch_msgs(
+    storage: &mut dyn Storage,
+    env: &Env,
+    outpost: &Outpost,
+    epoch: u64,
+    flow: EpochFlows,
+) -> Result<Vec<CosmosMsg>, ContractError> {
+    let coin = |amount: Uint128| Coin {
+        denom: outpost.host_denom.clone(),
+        amount,
+    };
+    let timeout = env.block.time.plus_seconds(outpost.transfer_timeout);
+
+    let mut actions = vec![];
+    let mut funds = vec![];
+    if flow.deposited > flow.withdrawn {
+        let lend = coin(flow.deposited - flow.withdrawn);
+        actions.push(Action::Deposit(lend.clone()));
+        actions.push(Action::Lend(ActionCoin::from(&lend)));
+        funds.push(lend);
+    } else if flow.withdrawn > flow.deposited {
+        if !flow.reclaim.is_zero() {
+            actions.push(Action::Reclaim(ActionCoin::from(&coin(flow.reclaim))));
+        }
+        actions.push(Action::Withdraw(ActionCoin::from(&coin(
+            flow.withdrawn - flow.deposited,
+        ))));
+    }
+
+    let mut msgs: Vec<CosmosMsg> = vec![];
+    if !actions.is_empty() {
+        msgs.push(
+            wasm_execute(
+                outpost.mars_red_bank_contract.clone(),
+                &CreditManagerExecuteMsg::UpdateCreditAccount {
+                    account_id: outpost.account_id.clone(),
+                    account_kind: None,
+                    actions,
+                },
+                funds,
+            )?
+            .into(),
+        );
+    }
+    for (id, owner, amount) in flow.refunds {
+        msgs.push(track_refund(storage, env, outpost, id, owner, amount)?);
+    }
+    for (recipient, amount) in flow.host_payouts {
+        msgs.push(
+            BankMsg::Send {
+                to_address: recipient,
+                amount: vec![coin(amount)],
+            }
+            .into(),
+        );
+    }
+    if !flow.returning.is_zero() {
+        let hook = ExecuteMsg::ReceiveEpoch {
+            epoch,
+            destination: outpost.mars_red_bank_contract.clone(),
+        };
+        msgs.push(ica_transfer_msg(
+            outpost,
+            &coin(flow.returning),
+            env.contract.address.to_string(),
+            timeout,
+            wasm_hook_memo(env, &hook)?,
+        )?);
+    }
+    Ok(msgs)
+}
+
+/// Queries the red bank market of an outpost whose epoch transaction failed while reclaiming lent
+/// funds, so the batch can be sized to the liquidity of the market. Returns `None` when the batch
+/// reclaims nothing or the outpost has no red bank to query.
+pub fn size_epoch_batch(
+    storage: &dyn Storage,
+    config: &Config,
+    epoch: u64,
+    destination: &str,
+) -> Result<Option<WasmMsg>, ContractError> {
+    let Some(batch) = EPOCH_BATCHES.may_load(storage, (epoch, destination))? else {
+        return Ok(None);
+    };
+    if batch.reclaim.is_zero() {
+        return Ok(None);
+    }
+    let outpost =
+        config
+            .find_destination_outpost(destination)
+            .ok_or(ContractError::DestinationNotFound {
+                destination: destination.to_string(),
+            })?;
+    let Some(query) = market_query(outpost)? else {
+        return Ok(None);
+    };
+
+    query_ica(
+        outpost.cw_ica_controller_contract.clone(),
+        Some(format!("epoch_market/{}/{}", epoch, destination)),
+        vec![query],
+        outpost.ica_timeout(IcaOperation::Query),
+    )
+    .map(Some)
+}
+
+/// Sizes the failed epoch transaction of an outpost to the liquidity its red bank market can
+/// supply and sends it again. The withdrawals of the batch give up the part the market cannot
+/// supply in proportion to their amounts, it is queued until the keeper retries it.
+/// A market with enough liquidity did not cause the failure, which then waits for a retry or its
+/// expiry like any other. Every resize reclaims strictly less, so a batch is sized a bounded
+/// number of times.
+#[allow(clippy::too_many_arguments)]
+pub fn epoch_market_callback(
+    storage: &mut dyn Storage,
+    env: &Env,
+    config: &Config,
+    state: &mut State,
+    epoch: u64,
+    destination: &str,
+    query_result: Option<IcaQueryResult>,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let memo = format!("epoch/{}/{}", epoch, destination);
+    let mut response = Response::new()
+        .add_attribute("method", "epoch_market_callback")
+        .add_attribute("epoch", epoch.to_string())
+        .add_attribute("destination", destination);
+
+    let Some(mut batch) = EPOCH_BATCHES.may_load(storage, (epoch, destination))? else {
+        return Ok(response);
+    };
+    let Some(market) = parse_market(&query_result) else {
+        return Ok(response);
+    };
+    let liquidity = available_liquidity(&market);
+    // A retry already in flight is sized by its own failure
+    if liquidity >= batch.reclaim || !awaits_retry(storage, &memo)? {
+        return Ok(response);
+    }
+    let outpost =
+        config
+            .find_destination_outpost(destination)
+            .ok_or(ContractError::DestinationNotFound {
+                destination: destination.to_string(),
+            })?;
+
+    let mut legs = vec![];
+    for id in epoch_ids(storage, EPOCH_WITHDRAWALS, epoch)? {
+        let withdrawal = PENDING_WITHDRAWALS.load(storage, id)?;
+        if withdrawal.destination == destination {
+            legs.push((id, withdrawal));
+        }
+    }
+    let unfilled = batch.reclaim - liquidity;
+    let withdrawn: Uint128 = legs.iter().map(|(_, leg)| leg.amount).sum();
+    let mut cuts: Vec<Uint128> = legs
+        .iter()
+        .map(|(_, leg)| leg.amount.multiply_ratio(unfilled, withdrawn))
+        .collect();
+    // Rounding leaves less than one unit per leg, it is taken from the first legs with room left
+    let mut remainder = unfilled - cuts.iter().sum::<Uint128>();
+    for ((_, leg), cut) in legs.iter().zip(cuts.iter_mut()) {
+        let extra = remainder.min(leg.amount - *cut);
+        *cut += extra;
+        remainder -= extra;
+    }
+
+    for ((id, mut leg), cut) in legs.into_iter().zip(cuts) {
+        if cut.is_zero() {
+            continue;
+        }
+        let value = leg.value;
+        requeue_unfilled(storage, env, state, outpost, &mut leg, cut)?;
+        batch.reclaimed_value = batch.reclaimed_value.saturating_sub(value - leg.value);
+
+        if leg.amount.is_zero() {
+            PENDING_WITHDRAWALS.remove(storage, id);
+            EPOCH_WITHDRAWALS.remove(storage, (epoch, id));
+            end_operation(storage, destination, OperationKind::Withdrawal)?;
+            settle_redeem_leg(storage, &leg.owner, leg.request_id, RequestStatus::Claimed)?;
+        } else {
+            PENDING_WITHDRAWALS.save(storage, id, &leg)?;
+        }
+    }
+    batch.reclaim = liquidity;
+
+    let flow = batch_flows(storage, epoch, destination, &batch)?;
+    let msgs = epoch_msgs(storage, env, outpost, epoch, flow)?;
+    match resend_failure(storage, config, &memo, msgs)? {
+        Some(msg) => {
+            EPOCH_BATCHES.save(storage, (epoch, destination), &batch)?;
+            response = response.add_message(msg);
+        }
+        // Nothing is left to settle on the outpost
+        None => EPOCH_BATCHES.remove(storage, (epoch, destination)),
+    }
+
+    Ok(response
+        .add_attribute("available_liquidity", liquidity)
+        .add_attribute("unfilled", unfilled))
+}
+
+/// Rebuilds the flows of an outpost from the requests left in its epoch batch
+fn batch_flows(
+    storage: &dyn Storage,
+    epoch: u64,
+    destination: &str,
+    batch: &EpochBatch,
+) -> StdResult<EpochFlows> {
+    let mut flow = EpochFlows {
+        reclaim: batch.reclaim,
+        ..EpochFlows::default()
+    };
+    for id in epoch_ids(storage, EPOCH_DEPOSITS, epoch)? {
+        let deposit = PENDING_DEPOSITS.load(storage, id)?;
+        if deposit.destination == destination && deposit.stage == DepositStage::Lending {
+            flow.deposited_value += deposit.value;
+            flow.deposited += deposit.coin.amount;
+        }
+    }
+    for id in epoch_ids(storage, EPOCH_WITHDRAWALS, epoch)? {
+        let withdrawal = PENDING_WITHDRAWALS.load(storage, id)?;
+        if withdrawal.destination != destination {
+            continue;
+        }
+        flow.withdrawn_value += withdrawal.value;
+        flow.withdrawn += withdrawal.amount;
+        match withdrawal.payout {
+            Payout::Host { recipient, .. } => {
+                flow.host_payouts.push((recipient, withdrawal.amount))
+            }
+            Payout::Local | Payout::Remote { .. } | Payout::Buffer => {
+                flow.returning += withdrawal.amount
+            }
+        }
+    }
+    for id in &batch.refunds {
+        if let Some(refund) = PENDING_REFUNDS.may_load(storage, *id)? {
+            flow.refunds.push((*id, refund.owner, refund.amount));
+        }
+    }
+    Ok(flow)
+}
+
+/// Completes the epoch on an outpost once its ICA transaction was acknowledged.
+/// Lent deposits move from pending to lent, payouts on the outpost chain are done
+/// and all other withdrawals are returning to Kujira until the transfer times out.
+pub fn epoch_callback(
+    storage: &mut dyn Storage,
+    env: &Env,
+    config: &Config,
+    state: &mut State,
+    epoch: u64,
+    destination: &str,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let outpost =
+        config
+            .find_destination_outpost(destination)
+            .ok_or(ContractError::DestinationNotFound {
+                destination: destination.to_string(),
+            })?;
+    let batch = EPOCH_BATCHES.load(storage, (epoch, destination))?;
+    EPOCH_BATCHES.remove(storage, (epoch, destination));
+
+    let mut nav = OUTPOST_NAV
+        .may_load(storage, destination)?
+        .unwrap_or_default();
+    nav.pending = nav.pending.saturating_sub(batch.lent_value);
+    nav.lent += batch.lent_value;
+    OUTPOST_NAV.save(storage, destination, &nav)?;
+    state.sync_nav(storage)?;
+
+    for id in epoch_ids(storage, EPOCH_DEPOSITS, epoch)? {
+        let deposit = PENDING_DEPOSITS.load(storage, id)?;
+        if deposit.destination == destination && deposit.stage == DepositStage::Lending {
+            PENDING_DEPOSITS.remove(storage, id);
+            EPOCH_DEPOSITS.remove(storage, (epoch, id));
+            end_operation(storage, destination, OperationKind::Deposit)?;
+        }
+    }
+
+    for id in epoch_ids(storage, EPOCH_WITHDRAWALS, epoch)? {
+        let mut withdrawal = PENDING_WITHDRAWALS.load(storage, id)?;
+        if withdrawal.destination != destination {
+            continue;
+        }
+        if let Payout::Host { .. } = withdrawal.payout {
+            PENDING_WITHDRAWALS.remove(storage, id);
+            EPOCH_WITHDRAWALS.remove(storage, (epoch, id));
+            end_operation(storage, destination, OperationKind::Withdrawal)?;
+            settle_redeem_leg(
+                storage,
+                &withdrawal.owner,
+                withdrawal.request_id,
+                RequestStatus::Claimed,
+            )?;
+        } else if withdrawal.stage == WithdrawStage::Reclaiming {
+            withdrawal.stage = WithdrawStage::Returning;
+            withdrawal.expires_at = Some(env.block.time.plus_seconds(outpost.transfer_timeout));
+            PENDING_WITHDRAWALS.save(storage, id, &withdrawal)?;
+        }
+    }
+
+    Ok(Response::new()
+        .add_attribute("method", "epoch_callback")
+        .add_attribute("epoch", epoch.to_string())
+        .add_attribute("destination", destination)
+        .add_attribute("total_stables", state.total_stables))
+}
+
+/// Undoes the batch of an outpost whose epoch transaction failed for good. Nothing happened on the
+/// outpost, so its NAV is restored and every request of the batch is rolled back: priced deposits
+/// are refunded, or kept as idle funds of the outpost when the owner no longer holds their receipts,
+/// withdrawals get their receipts back and the refunds of the batch can be sent again.
+pub fn expire_epoch_batch(
+    storage: &mut dyn Storage,
+    env: &Env,
+    config: &Config,
+    state: &mut State,
+    epoch: u64,
+    destination: &str,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let Some(batch) = EPOCH_BATCHES.may_load(storage, (epoch, destination))? else {
+        return Ok(Response::new());
+    };
+    EPOCH_BATCHES.remove(storage, (epoch, destination));
+    let outpost =
+        config
+            .find_destination_outpost(destination)
+            .ok_or(ContractError::DestinationNotFound {
+                destination: destination.to_string(),
+            })?;
+
+    let mut nav = OUTPOST_NAV
+        .may_load(storage, destination)?
+        .unwrap_or_default();
+    nav.pending = nav.pending.saturating_sub(batch.lent_value);
+    nav.lent += batch.reclaimed_value;
+    nav.idle += batch.idle_value;
+
+    let mut response = Response::new()
+        .add_attribute("method", "expire_epoch_batch")
+        .add_attribute("epoch", epoch.to_string())
+        .add_attribute("destination", destination);
+
+    for id in epoch_ids(storage, EPOCH_DEPOSITS, epoch)? {
+        let deposit = PENDING_DEPOSITS.load(storage, id)?;
+        if deposit.destination != destination || deposit.stage != DepositStage::Lending {
+            continue;
+        }
+        EPOCH_DEPOSITS.remove(storage, (epoch, id));
+        if revoke_deposit_shares(storage, &deposit.owner, id, deposit.shares)? {
+            state.virtual_receipt -= deposit.shares;
+            let refunded = refund_deposit(storage, env, outpost, id, deposit.shares)?;
+            response = response.add_submessages(refunded.messages);
+        } else {
+            PENDING_DEPOSITS.remove(storage, id);
+            end_operation(storage, destination, OperationKind::Deposit)?;
+            nav.idle += deposit.value;
+        }
+    }
+    OUTPOST_NAV.save(storage, destination, &nav)?;
+
+    for id in epoch_ids(storage, EPOCH_WITHDRAWALS, epoch)? {
+        let withdrawal = PENDING_WITHDRAWALS.load(storage, id)?;
+        if withdrawal.destination != destination {
+            continue;
+        }
+        PENDING_WITHDRAWALS.remove(storage, id);
+        EPOCH_WITHDRAWALS.remove(storage, (epoch, id));
+        end_operation(storage, destination, OperationKind::Withdrawal)?;
+        return_receipts(storage, config, state, &withdrawal)?;
+    }
+
+    for id in batch.refunds {
+        refund_failed(storage, env, id)?;
+    }
+    state.sync_nav(storage)?;
+
+    Ok(response.add_attribute("total_stables", state.total_stables))
+}
+
+/// Pays out the withdrawals of an epoch on an outpost once their funds arrived back on Kujira.
+/// Only the transfer from the ICA of the outpost carrying the amount owed to them is accepted.
+pub fn receive_epoch(
+    storage: &mut dyn Storage,
+    api: &dyn Api,
+    env: &Env,
+    info: &MessageInfo,
+    config: &Config,
+    epoch: u64,
+    destination: String,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let outpost = config.find_destination_outpost(&destination).ok_or(
+        ContractError::DestinationNotFound {
+            destination: destination.clone(),
+        },
+    )?;
+
+    // The funds can arrive before the acknowledgement of the ICA transaction
+    let mut withdrawals = vec![];
+    for id in epoch_ids(storage, EPOCH_WITHDRAWALS, epoch)? {
+        let withdrawal = PENDING_WITHDRAWALS.load(storage, id)?;
+        let returning = matches!(
+            withdrawal.stage,
+            WithdrawStage::Reclaiming | WithdrawStage::Returning
+        );
+        if withdrawal.destination == destination
+            && returning
+            && !matches!(withdrawal.payout, Payout::Host { .. })
+        {
+            withdrawals.push((id, withdrawal));
+        }
+    }
+
+    let owed: Uint128 = withdrawals.iter().map(|(_, w)| w.amount).sum();
+    ensure!(!owed.is_zero(), ContractError::InvalidAmount {});
+    ensure_outpost_hook(api, info, outpost, owed)?;
+
+    let mut response = Response::new()
+        .add_attribute("method", "receive_epoch")
+        .add_attribute("epoch", epoch.to_string())
+        .add_attribute("destination", &destination)
+        .add_attribute("amount", owed);
+
+    for (id, withdrawal) in withdrawals {
+        EPOCH_WITHDRAWALS.remove(storage, (epoch, id));
+        let coin = Coin {
+            denom: outpost.base_denom.clone(),
+            amount: withdrawal.amount,
+        };
+        let payout = pay_withdrawal(storage, env, config, id, withdrawal, coin)?;
+        response = response.add_submessages(payout.messages);
+    }
+
+    Ok(response)
+}
+
+/// Ids of up to `limit` operations batched in `epoch` after `start_after`
+fn epoch_page(
+    storage: &dyn Storage,
+    index: Map<(u64, u64), Empty>,
+    epoch: u64,
+    start_after: Option<u64>,
+    limit: u32,
+) -> StdResult<Vec<u64>> {
+    index
+        .prefix(epoch)
+        .keys(
+            storage,
+            start_after.map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit as usize)
+        .collect()
+}
+
+/// Ids of the operations batched in `epoch`
+fn epoch_ids(
+    storage: &dyn Storage,
+    index: Map<(u64, u64), Empty>,
+    epoch: u64,
+) -> StdResult<Vec<u64>> {
+    index
+        .prefix(epoch)
+        .keys(storage, None, None, Order::Ascending)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::{mock_env, MockStorage};
+    use cosmwasm_std::{Decimal, Timestamp};
+
+    use super::*;
+    use crate::state::{IcaAddressMismatch, PendingDeposit, ICA_MISMATCHES, PENDING_REFUNDS};
+    use crate::testing::{
+        mock_config, mock_oracle, mock_outpost, mock_state, mock_withdrawal, save_nav,
+    };
+
+    /// Closing epoch 0 on an outpost holding 1000 lent against 500 receipts, a receipt is worth 2
+    fn setup(storage: &mut dyn Storage, outpost: &Outpost) -> State {
+        save_nav(storage, &outpost.mars_red_bank_contract, 0, 1_000);
+        let mut epoch = Epoch::new(0, Timestamp::from_seconds(0));
+        epoch.closing = true;
+        EPOCH.save(storage, &epoch).unwrap();
+        mock_state(storage, 500)
+    }
+
+    fn queue_deposit(
+        storage: &mut dyn Storage,
+        outpost: &Outpost,
+        id: u64,
+        amount: u128,
+        min_shares_out: Option<u128>,
+    ) {
+        let mut deposit = PendingDeposit::new(
+            Addr::unchecked("alice"),
+            Coin::new(amount, outpost.base_denom.clone()),
+            outpost,
+            min_shares_out.map(Uint128::new),
+            Timestamp::from_seconds(0),
+        );
+        deposit.stage = DepositStage::Queued;
+        deposit.epoch = Some(0);
+        PENDING_DEPOSITS.save(storage, id, &deposit).unwrap();
+        EPOCH_DEPOSITS.save(storage, (0, id), &Empty {}).unwrap();
+    }
+
+    fn queue_withdrawal(storage: &mut dyn Storage, id: u64, shares: u128) {
+        let withdrawal = PendingWithdrawal {
+            request_id: id,
+            stage: WithdrawStage::Queued,
+            epoch: Some(0),
+            ..mock_withdrawal("", shares, 0)
+        };
+        PENDING_WITHDRAWALS.save(storage, id, &withdrawal).unwrap();
+        EPOCH_WITHDRAWALS.save(storage, (0, id), &Empty {}).unwrap();
+    }
+
+    #[test]
+    fn test_apply_flows_lends_net_inflow() {
+        let mut storage = MockStorage::new();
+        save_nav(&mut storage, "a", 0, 1_000);
+        let mut flow = EpochFlows {
+            deposited_value: Uint128::new(300),
+            deposited: Uint128::new(300),
+            withdrawn_value: Uint128::new(100),
+            withdrawn: Uint128::new(100),
+            ..EpochFlows::default()
+        };
+
+        let batch = apply_flows(&mut storage, "a", &mut flow).unwrap();
+        assert_eq!(batch.lent_value, Uint128::new(200));
+        assert_eq!(batch.reclaim, Uint128::zero());
+        let nav = OUTPOST_NAV.load(&storage, "a").unwrap();
+        assert_eq!(nav.pending, Uint128::new(200));
+        assert_eq!(nav.lent, Uint128::new(1_000));
+    }
+
+    #[test]
+    fn test_apply_flows_reclaims_before_idle() {
+        let mut storage = MockStorage::new();
+        save_nav(&mut storage, "a", 50, 100);
+        let mut flow = EpochFlows {
+            deposited_value: Uint128::new(20),
+            deposited: Uint128::new(20),
+            withdrawn_value: Uint128::new(200),
+            withdrawn: Uint128::new(200),
+            ..EpochFlows::default()
+        };
+
+        // The net outflow of 180 takes all 100 lent and 50 of the idle funds
+        let batch = apply_flows(&mut storage, "a", &mut flow).unwrap();
+        assert_eq!(batch.reclaimed_value, Uint128::new(100));
+        assert_eq!(batch.idle_value, Uint128::new(50));
+        assert_eq!(flow.reclaim, Uint128::new(100));
+        assert_eq!(batch.reclaim, flow.reclaim);
+        let nav = OUTPOST_NAV.load(&storage, "a").unwrap();
+        assert_eq!((nav.idle, nav.lent), (Uint128::zero(), Uint128::zero()));
+    }
+
+    #[test]
+    fn test_settle_epoch_nets_deposits_against_withdrawals() {
+        let mut storage = MockStorage::new();
+        let oracle = mock_oracle(Decimal::one());
+        let querier = QuerierWrapper::<KujiraQuery>::new(&oracle);
+        let env = mock_env();
+        let outpost = mock_outpost("a", "osmosis-1", "uusdc");
+        let config = mock_config(vec![outpost.clone()]);
+        let mut state = setup(&mut storage, &outpost);
+        queue_deposit(&mut storage, &outpost, 1, 100, None);
+        queue_withdrawal(&mut storage, 2, 100);
+
+        let response = settle_epoch(&mut storage, &querier, &env, &config, &mut state).unwrap();
+        assert_eq!(response.messages.len(), 1);
+
+        // Both sides are priced at the NAV the epoch ended with
+        let deposit = PENDING_DEPOSITS.load(&storage, 1).unwrap();
+        assert_eq!(deposit.shares, Uint128::new(50));
+        assert_eq!(deposit.stage, DepositStage::Lending);
+        assert_eq!(
+            VIRTUAL_RECEIPTS
+                .load(&storage, Addr::unchecked("alice"))
+                .unwrap(),
+            Uint128::new(50)
+        );
+        let withdrawal = PENDING_WITHDRAWALS.load(&storage, 2).unwrap();
+        assert_eq!(withdrawal.amount, Uint128::new(200));
+        assert_eq!(withdrawal.destination, "a");
+        assert_eq!(withdrawal.stage, WithdrawStage::Reclaiming);
+
+        // Only the net outflow of 100 is reclaimed
+        let batch = EPOCH_BATCHES.load(&storage, (0, "a")).unwrap();
+        assert_eq!(batch.reclaimed_value, Uint128::new(100));
+        assert_eq!(batch.reclaim, Uint128::new(100));
+        assert_eq!(batch.lent_value, Uint128::zero());
+        let nav = OUTPOST_NAV.load(&storage, "a").unwrap();
+        assert_eq!(nav.lent, Uint128::new(900));
+
+        assert_eq!(state.virtual_receipt, Uint128::new(450));
+        assert_eq!(state.deposit_redemption_rate, Decimal::percent(200));
+        let epoch = EPOCH.load(&storage).unwrap();
+        assert_eq!((epoch.id, epoch.closing), (1, false));
+    }
+
+    #[test]
+    fn test_settle_epoch_in_pages() {
+        let mut storage = MockStorage::new();
+        let oracle = mock_oracle(Decimal::one());
+        let querier = QuerierWrapper::<KujiraQuery>::new(&oracle);
+        let env = mock_env();
+        let outpost = mock_outpost("a", "osmosis-1", "uusdc");
+        let config = Config {
+            epoch_length: Some(60),
+            ..mock_config(vec![outpost.clone()])
+        };
+        let mut state = setup(&mut storage, &outpost);
+        for id in 1..=SETTLE_PAGE as u64 + 1 {
+            queue_deposit(&mut storage, &outpost, id, 10, None);
+        }
+        queue_withdrawal(&mut storage, 30, 100);
+
+        // The first page prices deposits only, nothing is booked into the NAV yet
+        let response = settle_epoch(&mut storage, &querier, &env, &config, &mut state).unwrap();
+        assert!(response.messages.is_empty());
+        let settlement = EPOCH_SETTLEMENT.load(&storage).unwrap();
+        assert_eq!(settlement.last_deposit, Some(SETTLE_PAGE as u64));
+        assert_eq!(settlement.minted, Uint128::new(100));
+        assert_eq!(
+            PENDING_DEPOSITS.load(&storage, 21).unwrap().stage,
+            DepositStage::Queued
+        );
+        assert_eq!(state.virtual_receipt, Uint128::new(500));
+        assert!(EPOCH.load(&storage).unwrap().closing);
+
+        // The next one prices the rest at the same NAV and sends the batch
+        let response =
+            try_process_epoch(&mut storage, &querier, &env, &config, &mut state).unwrap();
+        assert_eq!(response.messages.len(), 1);
+        assert_eq!(
+            PENDING_DEPOSITS.load(&storage, 21).unwrap().shares,
+            Uint128::new(5)
+        );
+        assert_eq!(
+            PENDING_WITHDRAWALS.load(&storage, 30).unwrap().amount,
+            Uint128::new(200)
+        );
+        assert!(!EPOCH_SETTLEMENT.exists(&storage));
+        assert!(EPOCH_FLOWS.is_empty(&storage));
+        assert_eq!(state.virtual_receipt, Uint128::new(505));
+        assert_eq!(EPOCH.load(&storage).unwrap().id, 1);
+    }
+
+    #[test]
+    fn test_settle_epoch_skips_mismatched_outpost() {
+        let mut storage = MockStorage::new();
+        let oracle = mock_oracle(Decimal::one());
+        let querier = QuerierWrapper::<KujiraQuery>::new(&oracle);
+        let env = mock_env();
+        let a = mock_outpost("a", "osmosis-1", "uusdc");
+        let b = mock_outpost("b", "neutron-1", "uusdc");
+        let config = mock_config(vec![a.clone(), b.clone()]);
+        save_nav(&mut storage, "b", 1_000, 0);
+        let mut state = setup(&mut storage, &a);
+        ICA_MISMATCHES
+            .save(
+                &mut storage,
+                "b",
+                &IcaAddressMismatch {
+                    expected: "b_ica".to_string(),
+                    actual: "other".to_string(),
+                    channel_id: "channel-2".to_string(),
+                    detected_at: Timestamp::from_seconds(0),
+                },
+            )
+            .unwrap();
+        queue_deposit(&mut storage, &b, 1, 100, None);
+        queue_withdrawal(&mut storage, 2, 100);
+
+        let response = settle_epoch(&mut storage, &querier, &env, &config, &mut state).unwrap();
+        assert_eq!(response.messages.len(), 1);
+
+        // The deposit waits for the next epoch on b
+        let deposit = PENDING_DEPOSITS.load(&storage, 1).unwrap();
+        assert_eq!(deposit.stage, DepositStage::Queued);
+        assert_eq!(deposit.epoch, Some(1));
+        assert!(EPOCH_DEPOSITS.has(&storage, (1, 1)));
+        assert!(!EPOCH_DEPOSITS.has(&storage, (0, 1)));
+
+        // The idle funds of b are left alone, the withdrawal is reclaimed from a
+        let withdrawal = PENDING_WITHDRAWALS.load(&storage, 2).unwrap();
+        assert_eq!(withdrawal.destination, "a");
+        assert_eq!(withdrawal.amount, Uint128::new(400));
+        assert!(!EPOCH_BATCHES.has(&storage, (0, "b")));
+        assert_eq!(state.virtual_receipt, Uint128::new(400));
+    }
+
+    #[test]
+    fn test_settle_epoch_refunds_missed_minimum() {
+        let mut storage = MockStorage::new();
+        let oracle = mock_oracle(Decimal::one());
+        let querier = QuerierWrapper::<KujiraQuery>::new(&oracle);
+        let env = mock_env();
+        let outpost = mock_outpost("a", "osmosis-1", "uusdc");
+        let config = mock_config(vec![outpost.clone()]);
+        let mut state = setup(&mut storage, &outpost);
+        queue_deposit(&mut storage, &outpost, 1, 100, Some(60));
+
+        let response = settle_epoch(&mut storage, &querier, &env, &config, &mut state).unwrap();
+        assert_eq!(response.messages.len(), 1);
+
+        // 100 would mint 50 receipts, the deposit is refunded through the batch instead
+        assert!(!PENDING_DEPOSITS.has(&storage, 1));
+        let refund = PENDING_REFUNDS.load(&storage, 1).unwrap();
+        assert_eq!(refund.owner, Addr::unchecked("alice"));
+        assert_eq!(refund.amount, Uint128::new(100));
+        let batch = EPOCH_BATCHES.load(&storage, (0, "a")).unwrap();
+        assert_eq!(batch.refunds, vec![1]);
+        assert_eq!(batch.lent_value, Uint128::zero());
+        assert_eq!(state.virtual_receipt, Uint128::new(500));
+        assert_eq!(state.total_stables, Uint128::new(1_000));
+    }
+
+    #[test]
+    fn test_expire_epoch_batch_restores_requests() {
+        let mut storage = MockStorage::new();
+        let oracle = mock_oracle(Decimal::one());
+        let querier = QuerierWrapper::<KujiraQuery>::new(&oracle);
+        let env = mock_env();
+        let outpost = mock_outpost("a", "osmosis-1", "uusdc");
+        let config = mock_config(vec![outpost.clone()]);
+        let mut state = setup(&mut storage, &outpost);
+        queue_deposit(&mut storage, &outpost, 1, 100, None);
+        queue_withdrawal(&mut storage, 2, 100);
+        settle_epoch(&mut storage, &querier, &env, &config, &mut state).unwrap();
+
+        expire_epoch_batch(&mut storage, &env, &config, &mut state, 0, "a").unwrap();
+
+        // The deposit is refunded and the withdrawal gets its receipts back
+        assert!(PENDING_REFUNDS.has(&storage, 1));
+        assert!(!PENDING_WITHDRAWALS.has(&storage, 2));
+        assert_eq!(
+            VIRTUAL_RECEIPTS
+                .load(&storage, Addr::unchecked("bob"))
+                .unwrap(),
+            Uint128::new(100)
+        );
+        assert_eq!(state.virtual_receipt, Uint128::new(500));
+        assert_eq!(state.total_stables, Uint128::new(1_000));
+        assert!(!EPOCH_BATCHES.has(&storage, (0, "a")));
+    }
+}
@@ -1,19 +1,166 @@
-use crate::error::ContractError;
+use crate::{error::ContractError, msg::Outpost};
 use cosmwasm_std::{
-    from_json, wasm_execute, CosmosMsg, Empty, IbcPacket, QueryRequest, StdResult, WasmMsg,
+    from_json, to_json_binary, wasm_execute, Coin, CosmosMsg, Empty, IbcPacket, QueryRequest,
+    StdResult, Uint128, WasmMsg, WasmQuery,
 };
 use cw_ica_controller::types::msg::ExecuteMsg::SendCosmosMsgs;
+use cw_ica_controller::types::query_msg::{IcaQueryResponse, IcaQueryResult, WasmQueryResponse};
+use mars_types::credit_manager::{
+    Action, ActionCoin, ExecuteMsg as CreditManagerExecuteMsg, Positions,
+    QueryMsg as CreditManagerQueryMsg,
+};
+use mars_types::red_bank::{MarketV2Response, QueryMsg as RedBankQueryMsg};
 use serde::{Deserialize, Serialize};
 
 pub fn create_ibc_identifier() -> String {
     "ica".to_string()
 }
 
+/// Operations carried by the ICA transactions sent to an outpost, each with its own timeout
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IcaOperation {
+    Query,
+    Lend,
+    Withdraw,
+    Payout,
+    MoveFunds,
+    Epoch,
+}
+
+/// ICA transaction sent by the contract, parsed from the memo it was sent with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IcaMemo<'a> {
+    /// `deposit/{id}`: positions query pricing a deposit
+    Deposit(u64),
+    /// `lend/{id}`: lend of a priced deposit
+    Lend(u64),
+    /// `refresh_nav/{id}/{destination}`: positions query of a NAV refresh
+    RefreshNav { id: u64, destination: &'a str },
+    /// `market/{destination}`: red bank market query
+    Market(&'a str),
+    /// `withdraw/{id}`: reclaim of a withdrawal
+    Withdraw(u64),
+    /// `withdraw_market/{id}`: market query sizing a failed withdrawal
+    WithdrawMarket(u64),
+    /// `payout/{id}`: transfer of a reclaimed withdrawal
+    Payout(u64),
+    /// `epoch/{epoch}/{destination}`: batch of an epoch on an outpost
+    Epoch { epoch: u64, destination: &'a str },
+    /// `epoch_market/{epoch}/{destination}`: market query sizing a failed epoch batch
+    EpochMarket { epoch: u64, destination: &'a str },
+    /// `refund_deposit/{id}`: transfer refunding a deposit
+    RefundDeposit(u64),
+    /// `move/{id}`: reclaim and transfer of a move between outposts
+    Move(u64),
+    /// `move_lend/{id}`: lend of moved funds on the outpost they arrived at
+    MoveLend(u64),
+    /// `move_relend/{id}`: lend of moved funds back on the outpost they left
+    MoveRelend(u64),
+    /// `relend/{id}`: lend of a withdrawal whose payout expired
+    Relend(u64),
+}
+
+impl<'a> IcaMemo<'a> {
+    /// Parses `memo`. A memo of a known kind with other parts than expected is malformed.
+    pub fn parse(memo: &'a str) -> Result<Self, ContractError> {
+        let mut parts = memo.split('/');
+        let kind = parts.next().unwrap_or_default();
+        let parts: Vec<&'a str> = parts.collect();
+
+        let malformed = || ContractError::InvalidMemoFormat(memo.to_string());
+        let parse_id = |id: &str| id.parse::<u64>().map_err(|_| malformed());
+        let id = || match parts.as_slice() {
+            [id] => parse_id(*id),
+            _ => Err(malformed()),
+        };
+        let id_and_destination = || match parts.as_slice() {
+            [id, destination] => Ok((parse_id(*id)?, *destination)),
+            _ => Err(malformed()),
+        };
+
+        Ok(match kind {
+            "deposit" => IcaMemo::Deposit(id()?),
+            "lend" => IcaMemo::Lend(id()?),
+            "refresh_nav" => {
+                let (id, destination) = id_and_destination()?;
+                IcaMemo::RefreshNav { id, destination }
+            }
+            "market" => match parts.as_slice() {
+                [destination] => IcaMemo::Market(*destination),
+                _ => return Err(malformed()),
+            },
+            "withdraw" => IcaMemo::Withdraw(id()?),
+            "withdraw_market" => IcaMemo::WithdrawMarket(id()?),
+            "payout" => IcaMemo::Payout(id()?),
+            "epoch" => {
+                let (epoch, destination) = id_and_destination()?;
+                IcaMemo::Epoch { epoch, destination }
+            }
+            "epoch_market" => {
+                let (epoch, destination) = id_and_destination()?;
+                IcaMemo::EpochMarket { epoch, destination }
+            }
+            "refund_deposit" => IcaMemo::RefundDeposit(id()?),
+            "move" => IcaMemo::Move(id()?),
+            "move_lend" => IcaMemo::MoveLend(id()?),
+            "move_relend" => IcaMemo::MoveRelend(id()?),
+            "relend" => IcaMemo::Relend(id()?),
+            _ => return Err(ContractError::UnknownMemo {}),
+        })
+    }
+
+    /// Operation the transaction carries
+    pub fn operation(&self) -> IcaOperation {
+        match self {
+            IcaMemo::Deposit(_)
+            | IcaMemo::RefreshNav { .. }
+            | IcaMemo::Market(_)
+            | IcaMemo::WithdrawMarket(_)
+            | IcaMemo::EpochMarket { .. } => IcaOperation::Query,
+            IcaMemo::Lend(_)
+            | IcaMemo::MoveLend(_)
+            | IcaMemo::MoveRelend(_)
+            | IcaMemo::Relend(_) => IcaOperation::Lend,
+            IcaMemo::Withdraw(_) => IcaOperation::Withdraw,
+            IcaMemo::Payout(_) | IcaMemo::RefundDeposit(_) => IcaOperation::Payout,
+            IcaMemo::Move(_) => IcaOperation::MoveFunds,
+            IcaMemo::Epoch { .. } => IcaOperation::Epoch,
+        }
+    }
+
+    /// Whether the transaction only queries guidance whose failure is ignored: a refresh settles
+    /// with the answers it got and markets only guide routing and sizing
+    pub fn is_guidance_query(&self) -> bool {
+        matches!(
+            self,
+            IcaMemo::RefreshNav { .. } | IcaMemo::Market(_) | IcaMemo::EpochMarket { .. }
+        )
+    }
+}
+
+impl Outpost {
+    /// Timeout in seconds of an ICA transaction carrying `operation` to the outpost,
+    /// none leaves it to the ICA controller
+    pub fn ica_timeout(&self, operation: IcaOperation) -> Option<u64> {
+        let timeouts = self.ica_timeouts.as_ref()?;
+        let timeout = match operation {
+            IcaOperation::Query => timeouts.query,
+            IcaOperation::Lend => timeouts.lend,
+            IcaOperation::Withdraw => timeouts.withdraw,
+            IcaOperation::Payout => timeouts.payout,
+            IcaOperation::MoveFunds => timeouts.move_funds,
+            IcaOperation::Epoch => timeouts.epoch,
+        };
+        timeout.or(timeouts.default)
+    }
+}
+
 pub fn execute_ica(
     cw_ica_controller_address: String,
     memo: Option<String>,
     messages: Vec<CosmosMsg>,
     queries: Vec<QueryRequest>,
+    timeout_seconds: Option<u64>,
 ) -> Result<WasmMsg, ContractError> {
     let ica_controller_msg = SendCosmosMsgs {
         messages,
@@ -23,7 +170,7 @@ pub fn execute_ica(
         } else {
             None
         },
-        timeout_seconds: None,
+        timeout_seconds,
     };
 
     let msg = wasm_execute(cw_ica_controller_address, &ica_controller_msg, vec![])?;
@@ -35,6 +182,7 @@ pub fn query_ica(
     cw_ica_controller_address: String,
     memo: Option<String>,
     queries: Vec<QueryRequest>,
+    timeout_seconds: Option<u64>,
 ) -> Result<WasmMsg, ContractError> {
     let ica_controller_msg = SendCosmosMsgs {
         messages: vec![],
@@ -44,7 +192,7 @@ pub fn query_ica(
         } else {
             None
         },
-        timeout_seconds: None,
+        timeout_seconds,
     };
 
     let msg = wasm_execute(cw_ica_controller_address, &ica_controller_msg, vec![])?;
@@ -54,16 +202,153 @@ pub fn query_ica(
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct IcaPacketData {
-    messages: Vec<CosmosMsg>,
+    pub messages: Vec<CosmosMsg>,
     #[serde(default)]
-    queries: Vec<QueryRequest<Empty>>,
+    pub queries: Vec<QueryRequest<Empty>>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    packet_memo: Option<String>,
+    pub packet_memo: Option<String>,
+}
+
+pub fn extract_packet(packet: &IbcPacket) -> StdResult<IcaPacketData> {
+    from_json(&packet.data)
 }
 
 pub fn extract_packet_memo(packet: &IbcPacket) -> StdResult<Option<String>> {
-    let packet_data: IcaPacketData = from_json(&packet.data)?;
-    Ok(packet_data.packet_memo)
+    Ok(extract_packet(packet)?.packet_memo)
+}
+
+/// Builds the ICA transaction that deposits `coin`, held by the ICA, into the credit account of the
+/// outpost and lends it on mars.
+pub fn lend_ica_msg(outpost: &Outpost, coin: Coin, memo: String) -> Result<WasmMsg, ContractError> {
+    execute_ica(
+        outpost.cw_ica_controller_contract.clone(),
+        Some(memo),
+        vec![lend_msg(outpost, coin)?],
+        vec![],
+        outpost.ica_timeout(IcaOperation::Lend),
+    )
+}
+
+/// Message the ICA executes to deposit and lend `coin` on mars
+pub fn lend_msg(outpost: &Outpost, coin: Coin) -> Result<CosmosMsg, ContractError> {
+    Ok(wasm_execute(
+        outpost.mars_red_bank_contract.clone(),
+        &CreditManagerExecuteMsg::UpdateCreditAccount {
+            account_id: outpost.account_id.clone(),
+            account_kind: None,
+            actions: vec![
+                Action::Deposit(coin.clone()),
+                Action::Lend(ActionCoin::from(&coin)),
+            ],
+        },
+        vec![coin],
+    )?
+    .into())
+}
+
+/// Builds the query for the positions of a mars credit account on the host chain.
+pub fn positions_query(
+    mars_contract: String,
+    account_id: String,
+) -> Result<QueryRequest, ContractError> {
+    Ok(QueryRequest::Wasm(WasmQuery::Smart {
+        contract_addr: mars_contract,
+        msg: to_json_binary(&CreditManagerQueryMsg::Positions { account_id })?,
+    }))
+}
+
+/// Builds the queries answered by an outpost when its value is measured: the positions of its
+/// credit account.
+pub fn outpost_queries(outpost: &Outpost) -> Result<Vec<QueryRequest>, ContractError> {
+    let account_id = outpost
+        .account_id
+        .clone()
+        .ok_or(ContractError::NoCreditAccount {})?;
+
+    Ok(vec![positions_query(
+        outpost.mars_red_bank_contract.clone(),
+        account_id,
+    )?])
+}
+
+/// Builds the query for the red bank market of the host denom of an outpost, if a red bank is
+/// configured
+pub fn market_query(outpost: &Outpost) -> Result<Option<QueryRequest>, ContractError> {
+    let Some(red_bank) = &outpost.red_bank_contract else {
+        return Ok(None);
+    };
+    Ok(Some(QueryRequest::Wasm(WasmQuery::Smart {
+        contract_addr: red_bank.clone(),
+        msg: to_json_binary(&RedBankQueryMsg::MarketV2 {
+            denom: outpost.host_denom.clone(),
+        })?,
+    })))
+}
+
+/// Builds the ICA transaction querying the red bank market of an outpost, if a red bank is
+/// configured. It is sent on its own, so a market that cannot be queried never fails the positions
+/// query deposits and the NAV depend on.
+pub fn market_query_msg(outpost: &Outpost) -> Result<Option<WasmMsg>, ContractError> {
+    let Some(query) = market_query(outpost)? else {
+        return Ok(None);
+    };
+
+    query_ica(
+        outpost.cw_ica_controller_contract.clone(),
+        Some(format!("market/{}", outpost.mars_red_bank_contract)),
+        vec![query],
+        outpost.ica_timeout(IcaOperation::Query),
+    )
+    .map(Some)
+}
+
+/// Extracts the red bank market answered by a market query.
+/// The market is only used as a routing hint, so a missing or malformed answer is ignored.
+pub fn parse_market(query_result: &Option<IcaQueryResult>) -> Option<MarketV2Response> {
+    let Some(IcaQueryResult::Success { responses, .. }) = query_result else {
+        return None;
+    };
+    responses
+        .iter()
+        .find_map(|response| match response {
+            IcaQueryResponse::Wasm(WasmQueryResponse::SmartContractState { data }) => Some(data),
+            _ => None,
+        })
+        .and_then(|data| from_json(data).ok())
+}
+
+/// Amount of the host denom lenders can reclaim from a red bank market right now
+pub fn available_liquidity(market: &MarketV2Response) -> Uint128 {
+    market
+        .collateral_total_amount
+        .saturating_sub(market.debt_total_amount)
+}
+
+/// Extracts the mars credit account positions from the result of an ica positions query.
+/// Returns the host chain height the query was answered at together with the positions.
+pub fn parse_positions(
+    query_result: Option<IcaQueryResult>,
+) -> Result<(u64, Positions), ContractError> {
+    match query_result {
+        Some(IcaQueryResult::Success { height, responses }) => {
+            let data = responses
+                .into_iter()
+                .find_map(|response| match response {
+                    IcaQueryResponse::Wasm(WasmQueryResponse::SmartContractState { data }) => {
+                        Some(data)
+                    }
+                    _ => None,
+                })
+                .ok_or(ContractError::IcaQueryError {
+                    error: "missing positions response".to_string(),
+                })?;
+            Ok((height, from_json(data)?))
+        }
+        Some(IcaQueryResult::Error(error)) => Err(ContractError::IcaQueryError { error }),
+        None => Err(ContractError::IcaQueryError {
+            error: "missing query result".to_string(),
+        }),
+    }
 }
 
 #[cfg(test)]
@@ -103,4 +388,38 @@ mod tests {
         assert!(memo_result.is_ok());
         assert_eq!(memo_result.unwrap(), Some("test_memo".to_string()));
     }
+
+    #[test]
+    fn test_parse_memo() {
+        assert_eq!(IcaMemo::parse("withdraw/7").unwrap(), IcaMemo::Withdraw(7));
+        assert_eq!(
+            IcaMemo::parse("epoch/3/red_bank").unwrap(),
+            IcaMemo::Epoch {
+                epoch: 3,
+                destination: "red_bank"
+            }
+        );
+        assert_eq!(
+            IcaMemo::parse("move/7").unwrap().operation(),
+            IcaOperation::MoveFunds
+        );
+        assert_eq!(
+            IcaMemo::parse("move_relend/7").unwrap().operation(),
+            IcaOperation::Lend
+        );
+
+        // Known kinds with other parts are malformed, anything else is unknown
+        assert!(matches!(
+            IcaMemo::parse("payout/7/a"),
+            Err(ContractError::InvalidMemoFormat(_))
+        ));
+        assert!(matches!(
+            IcaMemo::parse("lend/x"),
+            Err(ContractError::InvalidMemoFormat(_))
+        ));
+        assert!(matches!(
+            IcaMemo::parse("move_funds/7"),
+            Err(ContractError::UnknownMemo {})
+        ));
+    }
 }
@@ -0,0 +1,293 @@
+use crate::{
+    config::Config,
+    error::ContractError,
+    msg::Outpost,
+    state::{InFlight, InFlightResponse, OutpostInFlight, ICA_MISMATCHES, IN_FLIGHT},
+};
+use cosmwasm_std::{Deps, StdResult, Storage};
+use kujira::KujiraQuery;
+
+/// Kind of operation holding a slot on an outpost
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperationKind {
+    Deposit,
+    Withdrawal,
+    Move,
+}
+
+impl OperationKind {
+    fn name(&self) -> &'static str {
+        match self {
+            OperationKind::Deposit => "deposit",
+            OperationKind::Withdrawal => "withdrawal",
+            OperationKind::Move => "move",
+        }
+    }
+
+    fn counter<'a>(&self, in_flight: &'a mut InFlight) -> &'a mut u32 {
+        match self {
+            OperationKind::Deposit => &mut in_flight.deposits,
+            OperationKind::Withdrawal => &mut in_flight.withdrawals,
+            OperationKind::Move => &mut in_flight.moves,
+        }
+    }
+
+    /// Moves change the lent value deposits are priced against and withdrawals are reclaimed from,
+    /// so they never share an outpost with either
+    fn conflicts(&self, in_flight: &InFlight) -> bool {
+        match self {
+            OperationKind::Deposit | OperationKind::Withdrawal => in_flight.moves > 0,
+            OperationKind::Move => in_flight.deposits > 0 || in_flight.withdrawals > 0,
+        }
+    }
+}
+
+/// Refuses an operation of `kind` on an outpost at its limit of operations in flight, with
+/// operations in flight it conflicts with or with an interchain account mismatch the owner did not
+/// resolve yet
+pub fn ensure_capacity(
+    storage: &dyn Storage,
+    outpost: &Outpost,
+    kind: OperationKind,
+) -> Result<(), ContractError> {
+    let destination = &outpost.mars_red_bank_contract;
+    if ICA_MISMATCHES.has(storage, destination) {
+        return Err(ContractError::IcaAddressMismatch {
+            destination: destination.clone(),
+        });
+    }
+    let in_flight = IN_FLIGHT
+        .may_load(storage, destination)?
+        .unwrap_or_default();
+    if kind.conflicts(&in_flight) {
+        return Err(ContractError::ConflictingOperation {
+            destination: destination.clone(),
+            operation: kind.name().to_string(),
+        });
+    }
+    if let Some(limit) = outpost.max_in_flight {
+        if in_flight.total() >= limit {
+            return Err(ContractError::OutpostBusy {
+                destination: destination.clone(),
+                limit,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Whether the outpost accepts an operation of `kind` right now
+pub fn can_accept(storage: &dyn Storage, outpost: &Outpost, kind: OperationKind) -> bool {
+    ensure_capacity(storage, outpost, kind).is_ok()
+}
+
+/// Whether an epoch can commit an operation of `kind` to the outpost: its interchain account
+/// matches and it has no operations in flight the operation conflicts with. Unlike
+/// [`ensure_capacity`] the limit of operations in flight does not apply.
+pub fn can_commit(
+    storage: &dyn Storage,
+    outpost: &Outpost,
+    kind: OperationKind,
+) -> StdResult<bool> {
+    let destination = &outpost.mars_red_bank_contract;
+    if ICA_MISMATCHES.has(storage, destination) {
+        return Ok(false);
+    }
+    let in_flight = IN_FLIGHT
+        .may_load(storage, destination)?
+        .unwrap_or_default();
+    Ok(!kind.conflicts(&in_flight))
+}
+
+/// Slots the outpost has left before reaching its limit of operations in flight, `None` without
+/// a limit
+pub fn free_slots(storage: &dyn Storage, outpost: &Outpost) -> StdResult<Option<u32>> {
+    let Some(limit) = outpost.max_in_flight else {
+        return Ok(None);
+    };
+    let in_flight = IN_FLIGHT
+        .may_load(storage, &outpost.mars_red_bank_contract)?
+        .unwrap_or_default();
+    Ok(Some(limit.saturating_sub(in_flight.total())))
+}
+
+/// Takes a slot on the outpost for an operation of `kind`, refused like in [`ensure_capacity`]
+pub fn begin_operation(
+    storage: &mut dyn Storage,
+    outpost: &Outpost,
+    kind: OperationKind,
+) -> Result<(), ContractError> {
+    ensure_capacity(storage, outpost, kind)?;
+    track_operation(storage, &outpost.mars_red_bank_contract, kind)?;
+    Ok(())
+}
+
+/// Takes a slot on the outpost of `destination` without checking its limit, for operations
+/// that are already committed such as the withdrawal legs of an epoch
+pub fn track_operation(
+    storage: &mut dyn Storage,
+    destination: &str,
+    kind: OperationKind,
+) -> StdResult<()> {
+    let mut in_flight = IN_FLIGHT
+        .may_load(storage, destination)?
+        .unwrap_or_default();
+    *kind.counter(&mut in_flight) += 1;
+    IN_FLIGHT.save(storage, destination, &in_flight)
+}
+
+/// Frees the slot an operation of `kind` held on the outpost of `destination`.
+/// Operations that were never routed to an outpost have an empty destination and hold no slot.
+pub fn end_operation(
+    storage: &mut dyn Storage,
+    destination: &str,
+    kind: OperationKind,
+) -> StdResult<()> {
+    if destination.is_empty() {
+        return Ok(());
+    }
+    let Some(mut in_flight) = IN_FLIGHT.may_load(storage, destination)? else {
+        return Ok(());
+    };
+    let counter = kind.counter(&mut in_flight);
+    *counter = counter.saturating_sub(1);
+    if in_flight.total() == 0 {
+        IN_FLIGHT.remove(storage, destination);
+        Ok(())
+    } else {
+        IN_FLIGHT.save(storage, destination, &in_flight)
+    }
+}
+
+pub fn query_in_flight(deps: Deps<KujiraQuery>) -> StdResult<InFlightResponse> {
+    let config = Config::load(deps.storage)?;
+    let outposts = config
+        .outposts
+        .iter()
+        .map(|outpost| {
+            let destination = outpost.mars_red_bank_contract.clone();
+            Ok(OutpostInFlight {
+                in_flight: IN_FLIGHT
+                    .may_load(deps.storage, &destination)?
+                    .unwrap_or_default(),
+                destination,
+                limit: outpost.max_in_flight,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(InFlightResponse { outposts })
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::MockStorage;
+    use cosmwasm_std::Timestamp;
+
+    use super::*;
+    use crate::state::IcaAddressMismatch;
+    use crate::testing::mock_outpost;
+
+    fn limited(limit: u32) -> Outpost {
+        Outpost {
+            max_in_flight: Some(limit),
+            ..mock_outpost("a", "osmosis-1", "uusdc")
+        }
+    }
+
+    #[test]
+    fn test_ensure_capacity_enforces_limit() {
+        let mut storage = MockStorage::new();
+        let outpost = limited(2);
+
+        begin_operation(&mut storage, &outpost, OperationKind::Deposit).unwrap();
+        begin_operation(&mut storage, &outpost, OperationKind::Withdrawal).unwrap();
+        assert!(matches!(
+            begin_operation(&mut storage, &outpost, OperationKind::Deposit),
+            Err(ContractError::OutpostBusy { limit: 2, .. })
+        ));
+        assert_eq!(free_slots(&storage, &outpost).unwrap(), Some(0));
+
+        // Committed operations take a slot beyond the limit
+        track_operation(&mut storage, "a", OperationKind::Withdrawal).unwrap();
+        let in_flight = IN_FLIGHT.load(&storage, "a").unwrap();
+        assert_eq!((in_flight.deposits, in_flight.withdrawals), (1, 2));
+        assert_eq!(free_slots(&storage, &outpost).unwrap(), Some(0));
+
+        end_operation(&mut storage, "a", OperationKind::Withdrawal).unwrap();
+        end_operation(&mut storage, "a", OperationKind::Withdrawal).unwrap();
+        assert!(can_accept(&storage, &outpost, OperationKind::Withdrawal));
+    }
+
+    #[test]
+    fn test_ensure_capacity_refuses_conflicts() {
+        let mut storage = MockStorage::new();
+        let outpost = mock_outpost("a", "osmosis-1", "uusdc");
+
+        // Deposits and withdrawals share an outpost, moves wait for both
+        begin_operation(&mut storage, &outpost, OperationKind::Deposit).unwrap();
+        begin_operation(&mut storage, &outpost, OperationKind::Withdrawal).unwrap();
+        assert!(matches!(
+            ensure_capacity(&storage, &outpost, OperationKind::Move),
+            Err(ContractError::ConflictingOperation { operation, .. }) if operation == "move"
+        ));
+        assert!(!can_commit(&storage, &outpost, OperationKind::Move).unwrap());
+
+        end_operation(&mut storage, "a", OperationKind::Deposit).unwrap();
+        end_operation(&mut storage, "a", OperationKind::Withdrawal).unwrap();
+        begin_operation(&mut storage, &outpost, OperationKind::Move).unwrap();
+        begin_operation(&mut storage, &outpost, OperationKind::Move).unwrap();
+        for kind in [OperationKind::Deposit, OperationKind::Withdrawal] {
+            assert!(matches!(
+                ensure_capacity(&storage, &outpost, kind),
+                Err(ContractError::ConflictingOperation { .. })
+            ));
+            assert!(!can_commit(&storage, &outpost, kind).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_ensure_capacity_refuses_ica_mismatch() {
+        let mut storage = MockStorage::new();
+        let outpost = mock_outpost("a", "osmosis-1", "uusdc");
+        let mismatch = IcaAddressMismatch {
+            expected: "a_ica".to_string(),
+            actual: "other".to_string(),
+            channel_id: "channel-2".to_string(),
+            detected_at: Timestamp::from_seconds(0),
+        };
+        ICA_MISMATCHES.save(&mut storage, "a", &mismatch).unwrap();
+
+        assert!(matches!(
+            begin_operation(&mut storage, &outpost, OperationKind::Deposit),
+            Err(ContractError::IcaAddressMismatch { .. })
+        ));
+        assert!(!IN_FLIGHT.has(&storage, "a"));
+        assert!(!can_commit(&storage, &outpost, OperationKind::Withdrawal).unwrap());
+    }
+
+    #[test]
+    fn test_end_operation_saturates() {
+        let mut storage = MockStorage::new();
+
+        // Nothing tracked, or never routed to an outpost
+        end_operation(&mut storage, "a", OperationKind::Deposit).unwrap();
+        end_operation(&mut storage, "", OperationKind::Deposit).unwrap();
+        assert!(!IN_FLIGHT.has(&storage, "a"));
+
+        // Ending a kind with no slot leaves the others alone
+        track_operation(&mut storage, "a", OperationKind::Withdrawal).unwrap();
+        end_operation(&mut storage, "a", OperationKind::Deposit).unwrap();
+        assert_eq!(
+            IN_FLIGHT.load(&storage, "a").unwrap(),
+            InFlight {
+                deposits: 0,
+                withdrawals: 1,
+                moves: 0,
+            }
+        );
+
+        // The last slot removes the entry
+        end_operation(&mut storage, "a", OperationKind::Withdrawal).unwrap();
+        assert!(!IN_FLIGHT.has(&storage, "a"));
+    }
+}
@@ -1,8 +1,23 @@
+pub mod allocate;
+pub mod buffer;
+pub mod callback;
 pub mod channels;
+pub mod crank;
 pub mod create_vault;
 pub mod deposit;
+pub mod epoch;
 pub mod ica;
+pub mod in_flight;
 pub mod move_funds;
+pub mod rebalance;
+pub mod refresh_nav;
+pub mod refund;
+pub mod request;
+pub mod retry;
+pub mod route;
+pub mod simulate;
+pub mod swap;
+pub mod transfer;
 pub mod withdraw;
 
-pub use withdraw::try_withdraw;
+pub use withdraw::{receive_withdrawal, try_withdraw};
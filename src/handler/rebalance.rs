@@ -0,0 +1,819 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    config::Config,
+    error::ContractError,
+    helpers::ensure_outpost_hook,
+    msg::{ExecuteMsg, Outpost, RebalanceParams},
+    oracle::{amount_for_usd, usd_value},
+    state::{
+        next_operation_id, MoveStage, PendingMove, State, TransferKind, BUFFERS, LAST_REBALANCE,
+        OUTPOST_MARKETS, OUTPOST_NAV, PENDING_MOVES,
+    },
+};
+use cosmwasm_std::{
+    ensure, wasm_execute, Api, Coin, CosmosMsg, Decimal, Env, MessageInfo, Order, QuerierWrapper,
+    Response, StdResult, Storage, Uint128, WasmMsg,
+};
+use kujira::{KujiraMsg, KujiraQuery};
+use mars_types::credit_manager::{Action, ActionCoin, ExecuteMsg as CreditManagerExecuteMsg};
+
+use super::{
+    ica::{execute_ica, lend_ica_msg, IcaOperation},
+    in_flight::{begin_operation, can_accept, end_operation, free_slots, OperationKind},
+    transfer::{ica_transfer_msg, transfer_to_outpost, wasm_hook_memo},
+};
+
+/// Lent funds moved from a low-yield outpost to a higher-yield one
+pub struct Move<'a> {
+    pub from: &'a Outpost,
+    pub to: &'a Outpost,
+    /// USD value moved
+    pub value: Uint128,
+    /// Amount of the base asset moved
+    pub amount: Uint128,
+    /// Difference between the liquidity rates of the two outposts
+    pub spread: Decimal,
+}
+
+/// An outpost with a cached red bank market the rebalancer can move funds from or to
+struct Candidate<'a> {
+    outpost: &'a Outpost,
+    liquidity_rate: Decimal,
+    /// Lent value its market has the liquidity to return
+    reclaimable: Uint128,
+    /// Value it can receive before reaching its maximum exposure
+    room: Uint128,
+    /// Moves it can still take part in before reaching its limit of operations in flight
+    slots: Option<u32>,
+}
+
+/// Plans the moves of a rebalance. Within each base denom, lent funds are moved from the outposts
+/// with the lowest cached liquidity rate to those with the highest, as long as their rates are at
+/// least `min_spread` apart. Moves are limited by the liquidity of the market funds leave, the
+/// exposure cap of the outpost they are moved to, and `max_move` in total.
+/// Outposts with deposits or withdrawals in flight are left out, and every planned move takes a slot
+/// on both outposts until they reach their limit of operations in flight.
+pub fn plan_rebalance<'a>(
+    storage: &dyn Storage,
+    querier: &QuerierWrapper<KujiraQuery>,
+    config: &'a Config,
+    state: &State,
+    params: &RebalanceParams,
+) -> Result<Vec<Move<'a>>, ContractError> {
+    let mut groups: BTreeMap<&str, Vec<Candidate>> = BTreeMap::new();
+    for outpost in config.active_outposts() {
+        let destination = outpost.mars_red_bank_contract.as_str();
+        let Some(market) = OUTPOST_MARKETS.may_load(storage, destination)? else {
+            continue;
+        };
+        if !can_accept(storage, outpost, OperationKind::Move) {
+            continue;
+        }
+        let nav = OUTPOST_NAV
+            .may_load(storage, destination)?
+            .unwrap_or_default();
+        let liquidity = usd_value(
+            querier,
+            config,
+            &Coin {
+                denom: outpost.host_denom.clone(),
+                amount: market.available_liquidity,
+            },
+        )?;
+        let room = match outpost.max_exposure {
+            Some(max_exposure) => state
+                .total_stables
+                .mul_floor(max_exposure)
+                .saturating_sub(nav.total()),
+            None => Uint128::MAX,
+        };
+
+        groups
+            .entry(outpost.base_denom.as_str())
+            .or_default()
+            .push(Candidate {
+                outpost,
+                liquidity_rate: market.liquidity_rate,
+                reclaimable: nav.lent.min(liquidity),
+                room,
+                slots: free_slots(storage, outpost)?,
+            });
+    }
+
+    let mut moves = vec![];
+    let mut budget = params.max_move;
+    for mut candidates in groups.into_values() {
+        candidates.sort_by(|a, b| a.liquidity_rate.cmp(&b.liquidity_rate));
+
+        // Pair the lowest yields with the highest until the rates are too close
+        let (mut low, mut high) = (0, candidates.len().saturating_sub(1));
+        while low < high && !budget.is_zero() {
+            let spread = candidates[high].liquidity_rate - candidates[low].liquidity_rate;
+            if spread < params.min_spread {
+                break;
+            }
+            if candidates[low].reclaimable.is_zero() || candidates[low].slots == Some(0) {
+                low += 1;
+                continue;
+            }
+            if candidates[high].room.is_zero() || candidates[high].slots == Some(0) {
+                high -= 1;
+                continue;
+            }
+
+            let value = budget
+                .min(candidates[low].reclaimable)
+                .min(candidates[high].room);
+            let from = candidates[low].outpost;
+            moves.push(Move {
+                from,
+                to: candidates[high].outpost,
+                value,
+                amount: amount_for_usd(querier, config, &from.host_denom, value)?,
+                spread,
+            });
+            candidates[low].reclaimable -= value;
+            candidates[high].room -= value;
+            for index in [low, high] {
+                if let Some(slots) = candidates[index].slots.as_mut() {
+                    *slots -= 1;
+                }
+            }
+            budget -= value;
+        }
+    }
+
+    Ok(moves)
+}
+
+/// Carries out the planned moves of a rebalance once the cooldown of the last one passed.
+/// Each move reclaims the funds on the outpost they leave and transfers them back to Kujira,
+/// from where they are sent on to the outpost they are moved to and lent there.
+/// The moved value counts as pending on the receiving outpost until it is lent.
+pub fn try_rebalance(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper<KujiraQuery>,
+    env: &Env,
+    config: &Config,
+    state: &mut State,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let params = config
+        .rebalance
+        .as_ref()
+        .ok_or(ContractError::RebalanceDisabled {})?;
+    if let Some(last) = LAST_REBALANCE.may_load(storage)? {
+        let ready_at = last.plus_seconds(params.cooldown);
+        ensure!(
+            env.block.time >= ready_at,
+            ContractError::RebalanceCooldown { ready_at }
+        );
+    }
+
+    let moves = plan_rebalance(storage, querier, config, state, params)?;
+    ensure!(!moves.is_empty(), ContractError::NothingToRebalance {});
+
+    let mut response = Response::new().add_attribute("method", "rebalance");
+    for planned in moves {
+        let id = next_operation_id(storage)?;
+        let from = planned.from.mars_red_bank_contract.clone();
+        let to = planned.to.mars_red_bank_contract.clone();
+
+        begin_operation(storage, planned.from, OperationKind::Move)?;
+        begin_operation(storage, planned.to, OperationKind::Move)?;
+
+        let mut nav = OUTPOST_NAV.may_load(storage, &from)?.unwrap_or_default();
+        nav.lent = nav.lent.saturating_sub(planned.value);
+        OUTPOST_NAV.save(storage, &from, &nav)?;
+        let mut nav = OUTPOST_NAV.may_load(storage, &to)?.unwrap_or_default();
+        nav.pending += planned.value;
+        OUTPOST_NAV.save(storage, &to, &nav)?;
+
+        PENDING_MOVES.save(
+            storage,
+            id,
+            &PendingMove {
+                from: from.clone(),
+                to: to.clone(),
+                amount: planned.amount,
+                value: planned.value,
+                stage: MoveStage::Returning,
+                created_at: env.block.time,
+                expires_at: None,
+            },
+        )?;
+
+        response = response
+            .add_message(move_ica_msg(env, planned.from, id, planned.amount)?)
+            .add_attribute("move", format!("{}/{}/{}/{}", id, from, to, planned.value));
+    }
+    LAST_REBALANCE.save(storage, &env.block.time)?;
+    state.sync_nav(storage)?;
+
+    Ok(response)
+}
+
+/// Builds the ICA transaction that reclaims `amount` on the outpost funds leave, withdraws it to the
+/// ICA and transfers it back to this contract with a wasm hook sending it on.
+pub fn move_ica_msg(
+    env: &Env,
+    from: &Outpost,
+    id: u64,
+    amount: Uint128,
+) -> Result<WasmMsg, ContractError> {
+    execute_ica(
+        from.cw_ica_controller_contract.clone(),
+        Some(format!("move/{}", id)),
+        move_msgs(env, from, id, amount)?,
+        vec![],
+        from.ica_timeout(IcaOperation::MoveFunds),
+    )
+}
+
+/// Messages the ICA of the outpost funds leave executes for a move
+pub fn move_msgs(
+    env: &Env,
+    from: &Outpost,
+    id: u64,
+    amount: Uint128,
+) -> Result<Vec<CosmosMsg>, ContractError> {
+    let coin = Coin {
+        denom: from.host_denom.clone(),
+        amount,
+    };
+
+    let update_credit_msg = wasm_execute(
+        from.mars_red_bank_contract.clone(),
+        &CreditManagerExecuteMsg::UpdateCreditAccount {
+            account_id: from.account_id.clone(),
+            account_kind: None,
+            actions: vec![
+                Action::Reclaim(ActionCoin::from(&coin)),
+                Action::Withdraw(ActionCoin::from(&coin)),
+            ],
+        },
+        vec![],
+    )?
+    .into();
+    let transfer = ica_transfer_msg(
+        from,
+        &coin,
+        env.contract.address.to_string(),
+        env.block.time.plus_seconds(from.transfer_timeout),
+        wasm_hook_memo(env, &ExecuteMsg::ReceiveMove { id })?,
+    )?;
+
+    Ok(vec![update_credit_msg, transfer])
+}
+
+/// Starts the deadline of the transfer to Kujira once the reclaim of a move was acknowledged
+pub fn move_callback(
+    storage: &mut dyn Storage,
+    env: &Env,
+    config: &Config,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let mut pending = PENDING_MOVES.load(storage, id)?;
+    let from = config.find_destination_outpost(&pending.from).ok_or(
+        ContractError::DestinationNotFound {
+            destination: pending.from.clone(),
+        },
+    )?;
+    if pending.stage == MoveStage::Returning {
+        pending.expires_at = Some(env.block.time.plus_seconds(from.transfer_timeout));
+        PENDING_MOVES.save(storage, id, &pending)?;
+    }
+
+    Ok(Response::new()
+        .add_attribute("method", "move_callback")
+        .add_attribute("move_id", id.to_string()))
+}
+
+/// Sends the funds of a move that arrived on Kujira on to the ICA of the outpost they are moved to.
+/// Only the transfer from the ICA of the outpost they leave carrying the moved amount is accepted.
+pub fn receive_move(
+    storage: &mut dyn Storage,
+    api: &dyn Api,
+    env: &Env,
+    info: &MessageInfo,
+    config: &Config,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let mut pending = PENDING_MOVES.load(storage, id)?;
+    ensure!(
+        pending.stage == MoveStage::Returning,
+        ContractError::MoveNotReturning { id }
+    );
+
+    let from = config.find_destination_outpost(&pending.from).ok_or(
+        ContractError::DestinationNotFound {
+            destination: pending.from.clone(),
+        },
+    )?;
+    ensure_outpost_hook(api, info, from, pending.amount)?;
+    let to =
+        config
+            .find_destination_outpost(&pending.to)
+            .ok_or(ContractError::DestinationNotFound {
+                destination: pending.to.clone(),
+            })?;
+
+    // The funds left the outpost they were reclaimed from
+    pending.stage = MoveStage::Transferring;
+    PENDING_MOVES.save(storage, id, &pending)?;
+    end_operation(storage, &pending.from, OperationKind::Move)?;
+
+    let coin = Coin {
+        denom: to.base_denom.clone(),
+        amount: pending.amount,
+    };
+    let transfer = transfer_to_outpost(
+        storage,
+        env,
+        to,
+        coin,
+        TransferKind::Move,
+        id,
+        env.contract.address.clone(),
+    )?;
+
+    Ok(Response::new()
+        .add_submessage(transfer)
+        .add_attribute("method", "receive_move")
+        .add_attribute("move_id", id.to_string())
+        .add_attribute("to", pending.to))
+}
+
+/// Lends the funds of a move once they arrived in the ICA of the outpost they are moved to
+pub fn move_transferred(
+    storage: &mut dyn Storage,
+    config: &Config,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let mut pending = PENDING_MOVES.load(storage, id)?;
+    let to =
+        config
+            .find_destination_outpost(&pending.to)
+            .ok_or(ContractError::DestinationNotFound {
+                destination: pending.to.clone(),
+            })?;
+
+    let coin = Coin {
+        denom: to.host_denom.clone(),
+        amount: pending.amount,
+    };
+    let msg = lend_ica_msg(to, coin, format!("move_lend/{}", id))?;
+
+    pending.stage = MoveStage::Lending;
+    PENDING_MOVES.save(storage, id, &pending)?;
+
+    Ok(Response::new()
+        .add_message(msg)
+        .add_attribute("method", "move_transferred")
+        .add_attribute("move_id", id.to_string()))
+}
+
+/// Moves the value of a move from pending to lent on the receiving outpost once it is lent
+pub fn move_lend_callback(
+    storage: &mut dyn Storage,
+    state: &mut State,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let pending = PENDING_MOVES.load(storage, id)?;
+    PENDING_MOVES.remove(storage, id);
+    end_operation(storage, &pending.to, OperationKind::Move)?;
+
+    let mut nav = OUTPOST_NAV
+        .may_load(storage, &pending.to)?
+        .unwrap_or_default();
+    nav.pending = nav.pending.saturating_sub(pending.value);
+    nav.lent += pending.value;
+    OUTPOST_NAV.save(storage, &pending.to, &nav)?;
+    state.sync_nav(storage)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "move_lend_callback")
+        .add_attribute("move_id", id.to_string()))
+}
+
+/// Puts the value of a move whose reclaim failed for good back on the outpost it was leaving
+pub fn expire_move(
+    storage: &mut dyn Storage,
+    state: &mut State,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let Some(pending) = PENDING_MOVES.may_load(storage, id)? else {
+        return Ok(Response::new());
+    };
+    PENDING_MOVES.remove(storage, id);
+    if pending.stage == MoveStage::Returning {
+        end_operation(storage, &pending.from, OperationKind::Move)?;
+    }
+    end_operation(storage, &pending.to, OperationKind::Move)?;
+
+    let mut nav = OUTPOST_NAV
+        .may_load(storage, &pending.from)?
+        .unwrap_or_default();
+    nav.lent += pending.value;
+    OUTPOST_NAV.save(storage, &pending.from, &nav)?;
+    let mut nav = OUTPOST_NAV
+        .may_load(storage, &pending.to)?
+        .unwrap_or_default();
+    nav.pending = nav.pending.saturating_sub(pending.value);
+    OUTPOST_NAV.save(storage, &pending.to, &nav)?;
+    state.sync_nav(storage)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "expire_move")
+        .add_attribute("move_id", id.to_string()))
+}
+
+/// Keeps the funds of a move whose lend failed for good idle in the ICA of the receiving outpost
+pub fn expire_move_lend(
+    storage: &mut dyn Storage,
+    state: &mut State,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let Some(pending) = PENDING_MOVES.may_load(storage, id)? else {
+        return Ok(Response::new());
+    };
+    PENDING_MOVES.remove(storage, id);
+    end_operation(storage, &pending.to, OperationKind::Move)?;
+
+    let mut nav = OUTPOST_NAV
+        .may_load(storage, &pending.to)?
+        .unwrap_or_default();
+    nav.pending = nav.pending.saturating_sub(pending.value);
+    nav.idle += pending.value;
+    OUTPOST_NAV.save(storage, &pending.to, &nav)?;
+    state.sync_nav(storage)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "expire_move_lend")
+        .add_attribute("move_id", id.to_string()))
+}
+
+/// Lends the funds of up to `limit` moves whose transfer to Kujira timed out again on the outpost
+/// they left, where they are back in the ICA. The moved value counts as pending there until the lend
+/// is acknowledged and the outpost they were moved to gives up its slot. Returns the number of moves
+/// rolled back.
+pub fn expire_returning_moves(
+    storage: &mut dyn Storage,
+    env: &Env,
+    config: &Config,
+    state: &mut State,
+    limit: u32,
+) -> Result<(Response<KujiraMsg>, u32), ContractError> {
+    let due = PENDING_MOVES
+        .range(storage, None, None, Order::Ascending)
+        .filter(|item| {
+            item.as_ref().map_or(true, |(_, pending)| {
+                pending.stage == MoveStage::Returning
+                    && pending
+                        .expires_at
+                        .is_some_and(|expires_at| expires_at <= env.block.time)
+            })
+        })
+        .take(limit as usize)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let mut response = Response::new().add_attribute("method", "expire_returning_moves");
+    let mut expired = 0;
+    for (id, mut pending) in due {
+        let from = config.find_destination_outpost(&pending.from).ok_or(
+            ContractError::DestinationNotFound {
+                destination: pending.from.clone(),
+            },
+        )?;
+        end_operation(storage, &pending.to, OperationKind::Move)?;
+
+        let mut nav = OUTPOST_NAV
+            .may_load(storage, &pending.to)?
+            .unwrap_or_default();
+        nav.pending = nav.pending.saturating_sub(pending.value);
+        OUTPOST_NAV.save(storage, &pending.to, &nav)?;
+        let mut nav = OUTPOST_NAV
+            .may_load(storage, &pending.from)?
+            .unwrap_or_default();
+        nav.pending += pending.value;
+        OUTPOST_NAV.save(storage, &pending.from, &nav)?;
+
+        pending.stage = MoveStage::Relending;
+        PENDING_MOVES.save(storage, id, &pending)?;
+
+        let coin = Coin {
+            denom: from.host_denom.clone(),
+            amount: pending.amount,
+        };
+        response = response
+            .add_message(lend_ica_msg(from, coin, format!("move_relend/{}", id))?)
+            .add_attribute("expired_move", id.to_string());
+        expired += 1;
+    }
+    if expired > 0 {
+        state.sync_nav(storage)?;
+    }
+
+    Ok((response, expired))
+}
+
+/// Moves the value of a rolled back move from pending to lent on the outpost it was leaving
+pub fn move_relend_callback(
+    storage: &mut dyn Storage,
+    state: &mut State,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let pending = PENDING_MOVES.load(storage, id)?;
+    PENDING_MOVES.remove(storage, id);
+    end_operation(storage, &pending.from, OperationKind::Move)?;
+
+    let mut nav = OUTPOST_NAV
+        .may_load(storage, &pending.from)?
+        .unwrap_or_default();
+    nav.pending = nav.pending.saturating_sub(pending.value);
+    nav.lent += pending.value;
+    OUTPOST_NAV.save(storage, &pending.from, &nav)?;
+    state.sync_nav(storage)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "move_relend_callback")
+        .add_attribute("move_id", id.to_string()))
+}
+
+/// Keeps the funds of a rolled back move whose lend failed for good idle in the ICA of the outpost
+/// it was leaving
+pub fn expire_move_relend(
+    storage: &mut dyn Storage,
+    state: &mut State,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let Some(pending) = PENDING_MOVES.may_load(storage, id)? else {
+        return Ok(Response::new());
+    };
+    PENDING_MOVES.remove(storage, id);
+    end_operation(storage, &pending.from, OperationKind::Move)?;
+
+    let mut nav = OUTPOST_NAV
+        .may_load(storage, &pending.from)?
+        .unwrap_or_default();
+    nav.pending = nav.pending.saturating_sub(pending.value);
+    nav.idle += pending.value;
+    OUTPOST_NAV.save(storage, &pending.from, &nav)?;
+    state.sync_nav(storage)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "expire_move_relend")
+        .add_attribute("move_id", id.to_string()))
+}
+
+/// Keeps the funds of a move whose transfer to the receiving outpost failed in the liquidity buffer
+pub fn move_to_buffer(storage: &mut dyn Storage, id: u64, coin: &Coin) -> StdResult<()> {
+    let Some(pending) = PENDING_MOVES.may_load(storage, id)? else {
+        return Ok(());
+    };
+    PENDING_MOVES.remove(storage, id);
+    end_operation(storage, &pending.to, OperationKind::Move)?;
+
+    let mut nav = OUTPOST_NAV
+        .may_load(storage, &pending.to)?
+        .unwrap_or_default();
+    nav.pending = nav.pending.saturating_sub(pending.value);
+    OUTPOST_NAV.save(storage, &pending.to, &nav)?;
+
+    let mut buffer = BUFFERS.may_load(storage, &coin.denom)?.unwrap_or_default();
+    buffer.amount += coin.amount;
+    buffer.value += pending.value;
+    BUFFERS.save(storage, &coin.denom, &buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::{mock_env, MockStorage};
+    use cosmwasm_std::Timestamp;
+
+    use super::*;
+    use crate::handler::in_flight::track_operation;
+    use crate::state::{OutpostMarket, OutpostNav, IN_FLIGHT};
+    use crate::testing::{mock_config, mock_oracle, mock_outpost, mock_state, save_nav};
+
+    fn save_outpost(
+        storage: &mut dyn Storage,
+        destination: &str,
+        lent: u128,
+        rate: &str,
+        liquidity: u128,
+    ) {
+        save_nav(storage, destination, 0, lent);
+        let market = OutpostMarket {
+            available_liquidity: Uint128::new(liquidity),
+            liquidity_rate: rate.parse().unwrap(),
+            updated_at: Timestamp::from_seconds(0),
+        };
+        OUTPOST_MARKETS.save(storage, destination, &market).unwrap();
+    }
+
+    fn params(min_spread: &str, max_move: u128) -> RebalanceParams {
+        RebalanceParams {
+            min_spread: min_spread.parse().unwrap(),
+            max_move: Uint128::new(max_move),
+            cooldown: 0,
+        }
+    }
+
+    fn planned<'a>(moves: &'a [Move]) -> Vec<(&'a str, &'a str, u128)> {
+        moves
+            .iter()
+            .map(|m| {
+                (
+                    m.from.mars_red_bank_contract.as_str(),
+                    m.to.mars_red_bank_contract.as_str(),
+                    m.value.u128(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_plan_rebalance_pairs_lowest_with_highest() {
+        let mut storage = MockStorage::new();
+        let oracle = mock_oracle(Decimal::one());
+        let querier = QuerierWrapper::<KujiraQuery>::new(&oracle);
+        let config = mock_config(vec![
+            mock_outpost("a", "osmosis-1", "uusdc"),
+            mock_outpost("b", "neutron-1", "uusdc"),
+            mock_outpost("c", "pion-1", "uusdc"),
+        ]);
+        save_outpost(&mut storage, "a", 1_000, "0.02", 10_000);
+        save_outpost(&mut storage, "b", 1_000, "0.05", 10_000);
+        save_outpost(&mut storage, "c", 1_000, "0.09", 10_000);
+
+        // The lowest rate is emptied into the highest, the budget left moves the next lowest
+        let moves = plan_rebalance(
+            &storage,
+            &querier,
+            &config,
+            &State::default(),
+            &params("0.01", 1_500),
+        )
+        .unwrap();
+        assert_eq!(planned(&moves), vec![("a", "c", 1_000), ("b", "c", 500)]);
+        assert_eq!(moves[0].spread, Decimal::percent(7));
+        assert_eq!(moves[0].amount, Uint128::new(1_000));
+    }
+
+    #[test]
+    fn test_plan_rebalance_below_min_spread() {
+        let mut storage = MockStorage::new();
+        let oracle = mock_oracle(Decimal::one());
+        let querier = QuerierWrapper::<KujiraQuery>::new(&oracle);
+        let config = mock_config(vec![
+            mock_outpost("a", "osmosis-1", "uusdc"),
+            mock_outpost("b", "neutron-1", "uusdc"),
+        ]);
+        save_outpost(&mut storage, "a", 1_000, "0.02", 10_000);
+        save_outpost(&mut storage, "b", 1_000, "0.025", 10_000);
+
+        let moves = plan_rebalance(
+            &storage,
+            &querier,
+            &config,
+            &State::default(),
+            &params("0.01", 1_000),
+        )
+        .unwrap();
+        assert!(moves.is_empty());
+    }
+
+    #[test]
+    fn test_plan_rebalance_limited_by_liquidity_and_exposure() {
+        let mut storage = MockStorage::new();
+        let oracle = mock_oracle(Decimal::one());
+        let querier = QuerierWrapper::<KujiraQuery>::new(&oracle);
+        let mut c = mock_outpost("c", "pion-1", "uusdc");
+        c.max_exposure = Some(Decimal::percent(50));
+        let config = mock_config(vec![
+            mock_outpost("a", "osmosis-1", "uusdc"),
+            mock_outpost("b", "neutron-1", "uusdc"),
+            c,
+        ]);
+        save_outpost(&mut storage, "a", 1_000, "0.02", 300);
+        save_outpost(&mut storage, "b", 1_000, "0.025", 10_000);
+        save_outpost(&mut storage, "c", 500, "0.09", 10_000);
+        let state = State {
+            total_stables: Uint128::new(2_000),
+            ..State::default()
+        };
+
+        // a can only return 300, c then takes 200 more from b before reaching half of the TVL
+        let moves =
+            plan_rebalance(&storage, &querier, &config, &state, &params("0.01", 1_000)).unwrap();
+        assert_eq!(planned(&moves), vec![("a", "c", 300), ("b", "c", 200)]);
+    }
+
+    #[test]
+    fn test_plan_rebalance_limited_by_max_in_flight() {
+        let mut storage = MockStorage::new();
+        let oracle = mock_oracle(Decimal::one());
+        let querier = QuerierWrapper::<KujiraQuery>::new(&oracle);
+        let mut c = mock_outpost("c", "pion-1", "uusdc");
+        c.max_in_flight = Some(2);
+        let config = mock_config(vec![
+            mock_outpost("a", "osmosis-1", "uusdc"),
+            mock_outpost("b", "neutron-1", "uusdc"),
+            c,
+        ]);
+        save_outpost(&mut storage, "a", 1_000, "0.02", 10_000);
+        save_outpost(&mut storage, "b", 1_000, "0.05", 10_000);
+        save_outpost(&mut storage, "c", 1_000, "0.09", 10_000);
+        track_operation(&mut storage, "c", OperationKind::Move).unwrap();
+
+        // c has a single slot left, the budget left does not move b into it
+        let moves = plan_rebalance(
+            &storage,
+            &querier,
+            &config,
+            &State::default(),
+            &params("0.01", 1_500),
+        )
+        .unwrap();
+        assert_eq!(planned(&moves), vec![("a", "c", 1_000)]);
+        for planned in &moves {
+            begin_operation(&mut storage, planned.from, OperationKind::Move).unwrap();
+            begin_operation(&mut storage, planned.to, OperationKind::Move).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_expire_returning_moves_relends_on_outpost_left() {
+        let mut storage = MockStorage::new();
+        let mut env = mock_env();
+        let config = mock_config(vec![
+            mock_outpost("a", "osmosis-1", "uusdc"),
+            mock_outpost("b", "neutron-1", "uusdc"),
+        ]);
+        // 100 reclaimed from a, pending on b
+        save_nav(&mut storage, "a", 0, 900);
+        OUTPOST_NAV
+            .save(
+                &mut storage,
+                "b",
+                &OutpostNav {
+                    pending: Uint128::new(100),
+                    ..OutpostNav::default()
+                },
+            )
+            .unwrap();
+        track_operation(&mut storage, "a", OperationKind::Move).unwrap();
+        track_operation(&mut storage, "b", OperationKind::Move).unwrap();
+        let pending = PendingMove {
+            from: "a".to_string(),
+            to: "b".to_string(),
+            amount: Uint128::new(100),
+            value: Uint128::new(100),
+            stage: MoveStage::Returning,
+            created_at: env.block.time,
+            expires_at: None,
+        };
+        PENDING_MOVES.save(&mut storage, 7, &pending).unwrap();
+        let mut state = mock_state(&storage, 1_000);
+
+        // The deadline starts with the acknowledgement of the reclaim
+        let (_, count) =
+            expire_returning_moves(&mut storage, &env, &config, &mut state, 10).unwrap();
+        assert_eq!(count, 0);
+        move_callback(&mut storage, &env, &config, 7).unwrap();
+        env.block.time = env.block.time.plus_seconds(599);
+        let (_, count) =
+            expire_returning_moves(&mut storage, &env, &config, &mut state, 10).unwrap();
+        assert_eq!(count, 0);
+
+        // Once the transfer timed out the funds are lent again on a and b gives up its slot
+        env.block.time = env.block.time.plus_seconds(1);
+        let (response, count) =
+            expire_returning_moves(&mut storage, &env, &config, &mut state, 10).unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(response.messages.len(), 1);
+        assert_eq!(
+            PENDING_MOVES.load(&storage, 7).unwrap().stage,
+            MoveStage::Relending
+        );
+        assert!(!IN_FLIGHT.has(&storage, "b"));
+        assert_eq!(
+            OUTPOST_NAV.load(&storage, "b").unwrap().pending,
+            Uint128::zero()
+        );
+        assert_eq!(
+            OUTPOST_NAV.load(&storage, "a").unwrap().pending,
+            Uint128::new(100)
+        );
+        assert_eq!(state.total_stables, Uint128::new(1_000));
+
+        move_relend_callback(&mut storage, &mut state, 7).unwrap();
+        assert!(!PENDING_MOVES.has(&storage, 7));
+        assert!(!IN_FLIGHT.has(&storage, "a"));
+        assert_eq!(
+            OUTPOST_NAV.load(&storage, "a").unwrap().lent,
+            Uint128::new(1_000)
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use crate::{
+    config::Config,
+    error::ContractError,
+    helpers::{callback_update_stable_amt, positions_value},
+    state::{NavRefresh, OutpostPosition, State, NAV_REFRESH},
+};
+use cosmwasm_std::{ensure, Env, QuerierWrapper, Response, Storage};
+use cw_ica_controller::types::query_msg::IcaQueryResult;
+use kujira::{KujiraMsg, KujiraQuery};
+
+use super::ica::{market_query_msg, outpost_queries, parse_positions, query_ica, IcaOperation};
+
+/// Sends a positions query to every active outpost in a single transaction, along with a query
+/// for the red bank market of outposts with a red bank.
+/// A refresh that passed its deadline is settled with the answers it received before a new one starts.
+pub fn try_refresh_nav(
+    storage: &mut dyn Storage,
+    env: &Env,
+    config: &Config,
+    state: &mut State,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let mut id = 0;
+    if let Some(mut refresh) = NAV_REFRESH.may_load(storage)? {
+        ensure!(
+            !refresh.in_progress() || refresh.expired(env.block.time),
+            ContractError::NavRefreshInProgress { id: refresh.id }
+        );
+        if refresh.in_progress() {
+            callback_update_stable_amt(storage, state, &mut refresh)?;
+        }
+        id = refresh.id + 1;
+    }
+
+    let mut awaiting = vec![];
+    let mut msgs = vec![];
+    for outpost in config.active_outposts() {
+        msgs.push(query_ica(
+            outpost.cw_ica_controller_contract.clone(),
+            Some(format!(
+                "refresh_nav/{}/{}",
+                id, outpost.mars_red_bank_contract
+            )),
+            outpost_queries(outpost)?,
+            outpost.ica_timeout(IcaOperation::Query),
+        )?);
+        msgs.extend(market_query_msg(outpost)?);
+        awaiting.push(outpost.mars_red_bank_contract.clone());
+    }
+
+    NAV_REFRESH.save(
+        storage,
+        &NavRefresh {
+            id,
+            deadline: env.block.time.plus_seconds(config.nav_refresh_timeout),
+            awaiting,
+            positions: vec![],
+        },
+    )?;
+
+    Ok(Response::new()
+        .add_messages(msgs)
+        .add_attribute("method", "refresh_nav")
+        .add_attribute("refresh_id", id.to_string()))
+}
+
+/// Stores the positions reported by an outpost for the running refresh.
+/// Once every outpost answered, or the deadline passed, all positions are applied at once.
+#[allow(clippy::too_many_arguments)]
+pub fn refresh_nav_callback(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper<KujiraQuery>,
+    env: &Env,
+    config: &Config,
+    state: &mut State,
+    id: u64,
+    destination: &str,
+    query_result: Option<IcaQueryResult>,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let mut refresh = NAV_REFRESH.load(storage)?;
+
+    // Answers of a refresh that already settled are outdated
+    if refresh.id != id || !refresh.awaiting.iter().any(|d| d == destination) {
+        return Ok(Response::new()
+            .add_attribute("method", "refresh_nav_callback")
+            .add_attribute("ignored", destination));
+    }
+
+    refresh.awaiting.retain(|d| d != destination);
+
+    // A failed query leaves the last known position of the outpost untouched
+    if let Ok((height, positions)) = parse_positions(query_result) {
+        let (idle, lent) = positions_value(querier, config, &positions)?;
+        refresh.positions.push(OutpostPosition {
+            destination: destination.to_string(),
+            idle,
+            lent,
+            time: env.block.time,
+            height,
+        });
+    }
+
+    let settled = !refresh.in_progress() || refresh.expired(env.block.time);
+    if settled {
+        callback_update_stable_amt(storage, state, &mut refresh)?;
+    }
+    NAV_REFRESH.save(storage, &refresh)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "refresh_nav_callback")
+        .add_attribute("destination", destination)
+        .add_attribute("settled", settled.to_string())
+        .add_attribute("total_stables", state.total_stables))
+}
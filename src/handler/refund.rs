@@ -0,0 +1,190 @@
+use crate::{
+    config::Config,
+    error::ContractError,
+    helpers::ensure_outpost_hook,
+    msg::{ExecuteMsg, Outpost},
+    state::{PendingRefund, PendingRefundResponse, PendingRefundsResponse, PENDING_REFUNDS},
+};
+use cosmwasm_std::{
+    ensure, Addr, Api, BankMsg, Coin, CosmosMsg, Deps, Env, MessageInfo, Order, Response,
+    StdResult, Storage, Uint128, WasmMsg,
+};
+use cw_storage_plus::Bound;
+use kujira::{KujiraMsg, KujiraQuery};
+
+use super::{
+    ica::{execute_ica, IcaOperation},
+    transfer::{ica_transfer_msg, wasm_hook_memo},
+};
+
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
+
+/// Tracks the refund of deposit `id` and returns the transfer the ICA sends it back to this
+/// contract with. The owner is paid once the funds arrive through the `ReceiveRefund` hook.
+pub fn track_refund(
+    storage: &mut dyn Storage,
+    env: &Env,
+    outpost: &Outpost,
+    id: u64,
+    owner: Addr,
+    amount: Uint128,
+) -> Result<CosmosMsg, ContractError> {
+    let refund = PendingRefund {
+        owner,
+        destination: outpost.mars_red_bank_contract.clone(),
+        amount,
+        expires_at: env.block.time.plus_seconds(outpost.transfer_timeout),
+        retries: 0,
+    };
+    PENDING_REFUNDS.save(storage, id, &refund)?;
+    refund_transfer_msg(env, outpost, id, &refund)
+}
+
+/// The ICA transaction sending a deposit refund on its own
+pub fn refund_ica_msg(
+    outpost: &Outpost,
+    id: u64,
+    transfer: CosmosMsg,
+) -> Result<WasmMsg, ContractError> {
+    execute_ica(
+        outpost.cw_ica_controller_contract.clone(),
+        Some(format!("refund_deposit/{}", id)),
+        vec![transfer],
+        vec![],
+        outpost.ica_timeout(IcaOperation::Payout),
+    )
+}
+
+fn refund_transfer_msg(
+    env: &Env,
+    outpost: &Outpost,
+    id: u64,
+    refund: &PendingRefund,
+) -> Result<CosmosMsg, ContractError> {
+    ica_transfer_msg(
+        outpost,
+        &Coin {
+            denom: outpost.host_denom.clone(),
+            amount: refund.amount,
+        },
+        env.contract.address.to_string(),
+        refund.expires_at,
+        wasm_hook_memo(env, &ExecuteMsg::ReceiveRefund { id })?,
+    )
+}
+
+/// Pays a refunded deposit to its owner once the funds arrived from the ICA of its outpost
+pub fn receive_refund(
+    storage: &mut dyn Storage,
+    api: &dyn Api,
+    info: &MessageInfo,
+    config: &Config,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let refund = PENDING_REFUNDS
+        .may_load(storage, id)?
+        .ok_or(ContractError::RefundNotFound { id })?;
+    let outpost = config.find_destination_outpost(&refund.destination).ok_or(
+        ContractError::DestinationNotFound {
+            destination: refund.destination.clone(),
+        },
+    )?;
+    ensure_outpost_hook(api, info, outpost, refund.amount)?;
+    PENDING_REFUNDS.remove(storage, id);
+
+    Ok(Response::new()
+        .add_message(BankMsg::Send {
+            to_address: refund.owner.to_string(),
+            amount: vec![Coin {
+                denom: outpost.base_denom.clone(),
+                amount: refund.amount,
+            }],
+        })
+        .add_attribute("method", "receive_refund")
+        .add_attribute("deposit_id", id.to_string())
+        .add_attribute("owner", refund.owner)
+        .add_attribute("amount", refund.amount))
+}
+
+/// The ICA transaction carrying a refund failed or timed out, so the funds never left the ICA.
+/// The refund can be sent again right away.
+pub fn refund_failed(
+    storage: &mut dyn Storage,
+    env: &Env,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    if let Some(mut refund) = PENDING_REFUNDS.may_load(storage, id)? {
+        refund.expires_at = env.block.time;
+        PENDING_REFUNDS.save(storage, id, &refund)?;
+    }
+
+    Ok(Response::new()
+        .add_attribute("method", "refund_failed")
+        .add_attribute("deposit_id", id.to_string()))
+}
+
+/// Sends a refund that did not arrive before its transfer timed out again. Once the timeout passed
+/// the funds are either paid out or back on the ICA, a refund that still arrives is bounced by the
+/// hook. Callable by the owner of the refund, the contract owner or the keeper.
+pub fn try_retry_refund(
+    storage: &mut dyn Storage,
+    env: &Env,
+    info: &MessageInfo,
+    config: &Config,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let mut refund = PENDING_REFUNDS
+        .may_load(storage, id)?
+        .ok_or(ContractError::RefundNotFound { id })?;
+    ensure!(
+        info.sender == refund.owner
+            || info.sender == config.owner
+            || config.keeper.as_ref() == Some(&info.sender),
+        ContractError::Unauthorized {}
+    );
+    ensure!(
+        refund.expires_at <= env.block.time,
+        ContractError::RefundInFlight {
+            id,
+            expires_at: refund.expires_at,
+        }
+    );
+    let outpost = config.find_destination_outpost(&refund.destination).ok_or(
+        ContractError::DestinationNotFound {
+            destination: refund.destination.clone(),
+        },
+    )?;
+
+    refund.expires_at = env.block.time.plus_seconds(outpost.transfer_timeout);
+    refund.retries += 1;
+    PENDING_REFUNDS.save(storage, id, &refund)?;
+    let transfer = refund_transfer_msg(env, outpost, id, &refund)?;
+
+    Ok(Response::new()
+        .add_message(refund_ica_msg(outpost, id, transfer)?)
+        .add_attribute("method", "retry_refund")
+        .add_attribute("deposit_id", id.to_string())
+        .add_attribute("retries", refund.retries.to_string()))
+}
+
+/// Lists the deposit refunds that did not arrive on Kujira yet, by deposit id
+pub fn query_pending_refunds(
+    deps: Deps<KujiraQuery>,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<PendingRefundsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let refunds = PENDING_REFUNDS
+        .range(
+            deps.storage,
+            start_after.map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+        .map(|item| item.map(|(id, refund)| PendingRefundResponse { id, refund }))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(PendingRefundsResponse { refunds })
+}
@@ -0,0 +1,356 @@
+use crate::{
+    error::ContractError,
+    msg::Outpost,
+    state::{
+        RequestKind, RequestResponse, RequestStatus, RequestsResponse, REQUESTS, VIRTUAL_RECEIPTS,
+    },
+};
+use cosmwasm_std::{
+    ensure, Addr, BankMsg, Coin, Deps, MessageInfo, Order, Response, StdResult, Storage, Uint128,
+};
+use cw_storage_plus::Bound;
+use kujira::{KujiraMsg, KujiraQuery};
+
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
+
+/// Records the assets of a deposit request once they are in the base denom of its outpost
+pub fn record_deposit_assets(
+    storage: &mut dyn Storage,
+    owner: &Addr,
+    id: u64,
+    assets: Uint128,
+) -> StdResult<()> {
+    if let Some(mut request) = REQUESTS.may_load(storage, (owner, id))? {
+        request.assets = assets;
+        REQUESTS.save(storage, (owner, id), &request)?;
+    }
+    Ok(())
+}
+
+/// Adds the receipts minted for a deposit request, which is marked as priced once `settled`.
+/// Returns whether the receipts are credited to the owner right away, otherwise they are held
+/// until the owner claims them.
+pub fn credit_deposit_request(
+    storage: &mut dyn Storage,
+    owner: &Addr,
+    id: u64,
+    shares: Uint128,
+    settled: bool,
+) -> StdResult<bool> {
+    let Some(mut request) = REQUESTS.may_load(storage, (owner, id))? else {
+        return Ok(true);
+    };
+
+    request.shares += shares;
+    if settled {
+        request.status = if request.auto_claim {
+            RequestStatus::Claimed
+        } else {
+            RequestStatus::Claimable
+        };
+    }
+    REQUESTS.save(storage, (owner, id), &request)?;
+    Ok(request.auto_claim)
+}
+
+/// Marks a deposit request as priced. Returns whether the receipts are credited to the owner right
+/// away, otherwise they are held until the owner claims them.
+pub fn settle_deposit_request(
+    storage: &mut dyn Storage,
+    owner: &Addr,
+    id: u64,
+    shares: Uint128,
+) -> StdResult<bool> {
+    credit_deposit_request(storage, owner, id, shares, true)
+}
+
+/// Marks a leg of a redemption as delivered, or as arrived on Kujira for local payouts.
+/// Once every leg is, the request moves to `status`, or to `Claimable` if it waits for a claim.
+/// Returns whether the funds of the leg are paid out right away, otherwise they are held until
+/// the owner claims them.
+pub fn settle_redeem_leg(
+    storage: &mut dyn Storage,
+    owner: &Addr,
+    request_id: u64,
+    status: RequestStatus,
+) -> StdResult<bool> {
+    let Some(mut request) = REQUESTS.may_load(storage, (owner, request_id))? else {
+        return Ok(true);
+    };
+
+    request.legs = request.legs.saturating_sub(1);
+    if request.legs == 0 {
+        request.status = if request.auto_claim {
+            status
+        } else {
+            RequestStatus::Claimable
+        };
+    }
+    REQUESTS.save(storage, (owner, request_id), &request)?;
+    Ok(request.auto_claim)
+}
+
+/// Records the outposts and assets of a redeem request once its epoch priced it
+pub fn price_redeem_request(
+    storage: &mut dyn Storage,
+    owner: &Addr,
+    id: u64,
+    outpost: &Outpost,
+    assets: Uint128,
+    legs: u32,
+) -> StdResult<()> {
+    if let Some(mut request) = REQUESTS.may_load(storage, (owner, id))? {
+        request.destination = outpost.mars_red_bank_contract.clone();
+        request.denom = outpost.base_denom.clone();
+        request.assets = assets;
+        request.legs = legs;
+        REQUESTS.save(storage, (owner, id), &request)?;
+    }
+    Ok(())
+}
+
+/// Takes the unfilled part of a redemption leg out of its request while the remainder waits in the
+/// withdrawal queue. The queued remainder counts as a leg of its own until it is retried.
+pub fn queue_redeem_remainder(
+    storage: &mut dyn Storage,
+    owner: &Addr,
+    id: u64,
+    unfilled: Uint128,
+) -> StdResult<()> {
+    if let Some(mut request) = REQUESTS.may_load(storage, (owner, id))? {
+        request.assets = request.assets.saturating_sub(unfilled);
+        request.legs += 1;
+        REQUESTS.save(storage, (owner, id), &request)?;
+    }
+    Ok(())
+}
+
+/// Takes the unfilled part of a redemption leg that is too small to be queued out of its request
+pub fn drop_redeem_remainder(
+    storage: &mut dyn Storage,
+    owner: &Addr,
+    id: u64,
+    unfilled: Uint128,
+) -> StdResult<()> {
+    if let Some(mut request) = REQUESTS.may_load(storage, (owner, id))? {
+        request.assets = request.assets.saturating_sub(unfilled);
+        REQUESTS.save(storage, (owner, id), &request)?;
+    }
+    Ok(())
+}
+
+/// Replaces the queued leg of a redemption by the legs its retry was routed to
+pub fn add_redeem_legs(
+    storage: &mut dyn Storage,
+    owner: &Addr,
+    id: u64,
+    assets: Uint128,
+    legs: u32,
+) -> StdResult<()> {
+    if let Some(mut request) = REQUESTS.may_load(storage, (owner, id))? {
+        request.assets += assets;
+        request.legs = request.legs + legs - 1;
+        REQUESTS.save(storage, (owner, id), &request)?;
+    }
+    Ok(())
+}
+
+/// Moves a request to a final status once its funds were delivered or refunded
+pub fn close_request(
+    storage: &mut dyn Storage,
+    owner: &Addr,
+    id: u64,
+    status: RequestStatus,
+) -> StdResult<()> {
+    if let Some(mut request) = REQUESTS.may_load(storage, (owner, id))? {
+        request.status = status;
+        REQUESTS.save(storage, (owner, id), &request)?;
+    }
+    Ok(())
+}
+
+/// Closes a deposit request whose bridged funds were refunded. Receipts already minted for the part
+/// kept in the liquidity buffer are still delivered, the request is cancelled otherwise.
+pub fn refund_deposit_request(storage: &mut dyn Storage, owner: &Addr, id: u64) -> StdResult<()> {
+    match REQUESTS.may_load(storage, (owner, id))? {
+        Some(request) if !request.shares.is_zero() => {
+            settle_deposit_request(storage, owner, id, Uint128::zero())?;
+            Ok(())
+        }
+        _ => close_request(storage, owner, id, RequestStatus::Cancelled),
+    }
+}
+
+/// Takes back the receipts minted for the bridged part of a deposit whose pricing is undone, and
+/// marks its request as pending again. Receipts credited to the owner are burned from their balance.
+/// Returns false, changing nothing, when the owner no longer holds them.
+pub fn revoke_deposit_shares(
+    storage: &mut dyn Storage,
+    owner: &Addr,
+    id: u64,
+    shares: Uint128,
+) -> StdResult<bool> {
+    let request = REQUESTS.may_load(storage, (owner, id))?;
+    let held = request
+        .as_ref()
+        .is_some_and(|request| request.status == RequestStatus::Claimable);
+    if !held {
+        let balance = VIRTUAL_RECEIPTS
+            .may_load(storage, owner.clone())?
+            .unwrap_or_default();
+        if balance < shares {
+            return Ok(false);
+        }
+        VIRTUAL_RECEIPTS.save(storage, owner.clone(), &(balance - shares))?;
+    }
+
+    if let Some(mut request) = request {
+        request.shares = request.shares.saturating_sub(shares);
+        request.status = RequestStatus::Pending;
+        REQUESTS.save(storage, (owner, id), &request)?;
+    }
+    Ok(true)
+}
+
+/// Takes a redemption leg whose receipts were given back out of its request.
+/// The request is cancelled when no other leg paid anything out.
+pub fn return_redeem_leg(
+    storage: &mut dyn Storage,
+    owner: &Addr,
+    id: u64,
+    shares: Uint128,
+    assets: Uint128,
+) -> StdResult<()> {
+    let Some(mut request) = REQUESTS.may_load(storage, (owner, id))? else {
+        return Ok(());
+    };
+    request.shares = request.shares.saturating_sub(shares);
+    request.assets = request.assets.saturating_sub(assets);
+    REQUESTS.save(storage, (owner, id), &request)?;
+
+    settle_redeem_leg(storage, owner, id, RequestStatus::Claimed)?;
+    if request.assets.is_zero() {
+        close_request(storage, owner, id, RequestStatus::Cancelled)?;
+    }
+    Ok(())
+}
+
+/// Whether the owner cancelled the deposit request before it was priced
+pub fn is_cancelled(storage: &dyn Storage, owner: &Addr, id: u64) -> StdResult<bool> {
+    Ok(REQUESTS
+        .may_load(storage, (owner, id))?
+        .is_some_and(|request| request.status == RequestStatus::Cancelled))
+}
+
+/// Delivers the result of a claimable request: credits the receipts of a deposit or pays out
+/// the assets of a redemption on Kujira.
+pub fn try_claim(
+    storage: &mut dyn Storage,
+    info: &MessageInfo,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let owner = &info.sender;
+    let mut request = REQUESTS
+        .may_load(storage, (owner, id))?
+        .ok_or(ContractError::RequestNotFound { id })?;
+    ensure!(
+        request.status == RequestStatus::Claimable,
+        ContractError::RequestNotClaimable { id }
+    );
+
+    request.status = RequestStatus::Claimed;
+    REQUESTS.save(storage, (owner, id), &request)?;
+
+    let response = Response::new()
+        .add_attribute("method", "claim")
+        .add_attribute("request_id", id.to_string())
+        .add_attribute("owner", owner.to_string());
+
+    match request.kind {
+        RequestKind::Deposit => {
+            VIRTUAL_RECEIPTS.update(storage, owner.clone(), |existing| {
+                Ok::<_, ContractError>(existing.unwrap_or_default() + request.shares)
+            })?;
+            Ok(response.add_attribute("shares", request.shares))
+        }
+        RequestKind::Redeem => {
+            let payout = BankMsg::Send {
+                to_address: owner.to_string(),
+                amount: vec![Coin {
+                    denom: request.denom,
+                    amount: request.assets,
+                }],
+            };
+            Ok(response
+                .add_message(payout)
+                .add_attribute("amount", request.assets))
+        }
+    }
+}
+
+/// Cancels a deposit request that was not priced yet. The funds are refunded to the owner once
+/// they reach the outpost. Redemptions burn their receipts when requested and cannot be cancelled,
+/// neither can deposits partly kept in the liquidity buffer.
+pub fn try_cancel_request(
+    storage: &mut dyn Storage,
+    info: &MessageInfo,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let owner = &info.sender;
+    let mut request = REQUESTS
+        .may_load(storage, (owner, id))?
+        .ok_or(ContractError::RequestNotFound { id })?;
+    ensure!(
+        request.kind == RequestKind::Deposit
+            && request.status == RequestStatus::Pending
+            && request.shares.is_zero(),
+        ContractError::RequestNotCancellable { id }
+    );
+
+    request.status = RequestStatus::Cancelled;
+    REQUESTS.save(storage, (owner, id), &request)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "cancel_request")
+        .add_attribute("request_id", id.to_string())
+        .add_attribute("owner", owner.to_string()))
+}
+
+pub fn query_request(
+    deps: Deps<KujiraQuery>,
+    owner: String,
+    id: u64,
+) -> Result<RequestResponse, ContractError> {
+    let owner = deps.api.addr_validate(&owner)?;
+    let request = REQUESTS
+        .may_load(deps.storage, (&owner, id))?
+        .ok_or(ContractError::RequestNotFound { id })?;
+    Ok(RequestResponse::new(id, request))
+}
+
+/// Lists the pending and claimable requests of `owner`, oldest first
+pub fn query_requests(
+    deps: Deps<KujiraQuery>,
+    owner: String,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> Result<RequestsResponse, ContractError> {
+    let owner = deps.api.addr_validate(&owner)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+
+    let requests = REQUESTS
+        .prefix(&owner)
+        .range(
+            deps.storage,
+            start_after.map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .filter(|item| item.as_ref().map_or(true, |(_, request)| request.is_open()))
+        .take(limit)
+        .map(|item| item.map(|(id, request)| RequestResponse::new(id, request)))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(RequestsResponse { requests })
+}
@@ -0,0 +1,431 @@
+use crate::{
+    config::Config,
+    error::ContractError,
+    state::{
+        next_operation_id, FailedOperation, FailedOperationResponse, FailedOperationsResponse,
+        State, FAILED_MEMOS, FAILED_OPERATIONS,
+    },
+};
+use cosmwasm_std::{
+    ensure, Addr, CosmosMsg, Deps, Env, MessageInfo, Order, QueryRequest, Response, StdResult,
+    Storage, WasmMsg,
+};
+use cw_storage_plus::Bound;
+use kujira::{KujiraMsg, KujiraQuery};
+
+use super::{
+    callback::expire_deposit,
+    epoch::expire_epoch_batch,
+    ica::{execute_ica, IcaMemo},
+    rebalance::{expire_move, expire_move_lend, expire_move_relend},
+    withdraw::{expire_payout, expire_relend},
+};
+
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
+
+/// Operations that hold the funds or receipts of a user or a slot on an outpost and are rolled back
+/// once they expire. All others stay retryable until they succeed. Refunds are not recorded here,
+/// they stay pending until their funds arrive and can be sent again after their transfer timed out.
+fn expires(memo: &str) -> bool {
+    matches!(
+        IcaMemo::parse(memo),
+        Ok(IcaMemo::Deposit(_)
+            | IcaMemo::Lend(_)
+            | IcaMemo::Payout(_)
+            | IcaMemo::Move(_)
+            | IcaMemo::MoveLend(_)
+            | IcaMemo::MoveRelend(_)
+            | IcaMemo::Relend(_)
+            | IcaMemo::Epoch { .. })
+    )
+}
+
+/// Records an ICA transaction the host chain answered with an error, along with the messages it is
+/// retried with. A retry that fails again updates the record and keeps its deadline.
+#[allow(clippy::too_many_arguments)]
+pub fn record_failure(
+    storage: &mut dyn Storage,
+    env: &Env,
+    config: &Config,
+    controller: &Addr,
+    memo: &str,
+    messages: Vec<CosmosMsg>,
+    queries: Vec<QueryRequest>,
+    error: String,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let (id, mut failed) = match FAILED_MEMOS.may_load(storage, memo)? {
+        Some(id) => (id, FAILED_OPERATIONS.load(storage, id)?),
+        None => {
+            let failed = FailedOperation {
+                controller: controller.to_string(),
+                memo: memo.to_string(),
+                messages,
+                queries,
+                error: String::new(),
+                retries: 0,
+                retrying: false,
+                failed_at: env.block.time,
+                deadline: config
+                    .operation_expiry
+                    .filter(|_| expires(memo))
+                    .map(|expiry| env.block.time.plus_seconds(expiry)),
+            };
+            (next_operation_id(storage)?, failed)
+        }
+    };
+    failed.error = error;
+    failed.retrying = false;
+    FAILED_OPERATIONS.save(storage, id, &failed)?;
+    FAILED_MEMOS.save(storage, memo, &id)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "operation_failed")
+        .add_attribute("failed_operation_id", id.to_string())
+        .add_attribute("memo", memo)
+        .add_attribute("error", failed.error))
+}
+
+/// Forgets the failed operation of a memo once a retry of it was acknowledged successfully
+pub fn clear_failure(storage: &mut dyn Storage, memo: &str) -> StdResult<()> {
+    if let Some(id) = FAILED_MEMOS.may_load(storage, memo)? {
+        FAILED_MEMOS.remove(storage, memo);
+        FAILED_OPERATIONS.remove(storage, id);
+    }
+    Ok(())
+}
+
+/// Whether the failed operation of `memo` waits for a retry
+pub fn awaits_retry(storage: &dyn Storage, memo: &str) -> StdResult<bool> {
+    let Some(id) = FAILED_MEMOS.may_load(storage, memo)? else {
+        return Ok(false);
+    };
+    Ok(!FAILED_OPERATIONS.load(storage, id)?.retrying)
+}
+
+/// Retries the failed operation of `memo` right away with `messages` in place of the ones it was
+/// recorded with. A later retry sends them as well. Without messages left there is nothing to
+/// retry and the failure is forgotten.
+pub fn resend_failure(
+    storage: &mut dyn Storage,
+    config: &Config,
+    memo: &str,
+    messages: Vec<CosmosMsg>,
+) -> Result<Option<WasmMsg>, ContractError> {
+    let Some(id) = FAILED_MEMOS.may_load(storage, memo)? else {
+        return Ok(None);
+    };
+    if messages.is_empty() {
+        clear_failure(storage, memo)?;
+        return Ok(None);
+    }
+
+    let mut failed = FAILED_OPERATIONS.load(storage, id)?;
+    failed.messages = messages;
+    failed.retrying = true;
+    failed.retries += 1;
+    FAILED_OPERATIONS.save(storage, id, &failed)?;
+
+    let timeout_seconds = IcaMemo::parse(memo).ok().and_then(|memo| {
+        config
+            .find_controller_outpost(&failed.controller)?
+            .ica_timeout(memo.operation())
+    });
+    Ok(Some(execute_ica(
+        failed.controller,
+        Some(failed.memo),
+        failed.messages,
+        failed.queries,
+        timeout_seconds,
+    )?))
+}
+
+/// Sends the messages of a failed operation again through the same ICA controller and memo,
+/// so its acknowledgement is handled like the original one. Only callable by the owner or keeper.
+/// The attempt uses the ICA timeout of the outpost for the operation unless `timeout_seconds` is set.
+pub fn try_retry_operation(
+    storage: &mut dyn Storage,
+    info: &MessageInfo,
+    config: &Config,
+    id: u64,
+    timeout_seconds: Option<u64>,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    ensure!(
+        info.sender == config.owner || config.keeper.as_ref() == Some(&info.sender),
+        ContractError::Unauthorized {}
+    );
+    let mut failed = FAILED_OPERATIONS
+        .may_load(storage, id)?
+        .ok_or(ContractError::OperationNotFound { id })?;
+    ensure!(!failed.retrying, ContractError::OperationRetrying { id });
+    ensure!(timeout_seconds != Some(0), ContractError::InvalidTimeout {});
+    let timeout_seconds = timeout_seconds.or_else(|| {
+        let operation = IcaMemo::parse(&failed.memo).ok()?.operation();
+        config
+            .find_controller_outpost(&failed.controller)?
+            .ica_timeout(operation)
+    });
+
+    failed.retrying = true;
+    failed.retries += 1;
+    FAILED_OPERATIONS.save(storage, id, &failed)?;
+
+    let msg = execute_ica(
+        failed.controller,
+        Some(failed.memo.clone()),
+        failed.messages,
+        failed.queries,
+        timeout_seconds,
+    )?;
+
+    Ok(Response::new()
+        .add_message(msg)
+        .add_attribute("method", "retry_operation")
+        .add_attribute("failed_operation_id", id.to_string())
+        .add_attribute("memo", failed.memo)
+        .add_attribute("retries", failed.retries.to_string()))
+}
+
+/// Rolls back up to `limit` failed operations that passed their deadline without a retry in flight.
+/// Deposits are refunded to their owner, payouts are lent again and give the receipts back, moves
+/// put their value back on the outpost they were leaving and epoch batches restore their requests.
+/// Funds whose lend failed stay idle on the outpost. Returns the number of operations expired.
+pub fn expire_operations(
+    storage: &mut dyn Storage,
+    env: &Env,
+    config: &Config,
+    state: &mut State,
+    limit: u32,
+) -> Result<(Response<KujiraMsg>, u32), ContractError> {
+    let due = FAILED_OPERATIONS
+        .range(storage, None, None, Order::Ascending)
+        .filter(|item| {
+            item.as_ref().map_or(true, |(_, failed)| {
+                !failed.retrying
+                    && failed
+                        .deadline
+                        .is_some_and(|deadline| deadline <= env.block.time)
+            })
+        })
+        .take(limit as usize)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let mut response = Response::new().add_attribute("method", "expire_operations");
+    let mut expired = 0;
+    for (id, mut failed) in due {
+        let rolled_back = match IcaMemo::parse(&failed.memo) {
+            Ok(IcaMemo::Deposit(deposit_id) | IcaMemo::Lend(deposit_id)) => {
+                expire_deposit(storage, env, config, state, deposit_id)?
+            }
+            Ok(IcaMemo::Payout(withdrawal_id)) => {
+                Some(expire_payout(storage, config, state, withdrawal_id)?)
+            }
+            Ok(IcaMemo::Move(move_id)) => Some(expire_move(storage, state, move_id)?),
+            Ok(IcaMemo::MoveLend(move_id)) => Some(expire_move_lend(storage, state, move_id)?),
+            Ok(IcaMemo::MoveRelend(move_id)) => Some(expire_move_relend(storage, state, move_id)?),
+            Ok(IcaMemo::Relend(withdrawal_id)) => {
+                Some(expire_relend(storage, state, withdrawal_id)?)
+            }
+            Ok(IcaMemo::Epoch { epoch, destination }) => Some(expire_epoch_batch(
+                storage,
+                env,
+                config,
+                state,
+                epoch,
+                destination,
+            )?),
+            _ => None,
+        };
+
+        // Operations that cannot be rolled back stay retryable
+        let Some(rolled_back) = rolled_back else {
+            failed.deadline = None;
+            FAILED_OPERATIONS.save(storage, id, &failed)?;
+            continue;
+        };
+        FAILED_OPERATIONS.remove(storage, id);
+        FAILED_MEMOS.remove(storage, &failed.memo);
+        response = response
+            .add_submessages(rolled_back.messages)
+            .add_attributes(rolled_back.attributes)
+            .add_attribute("expired", id.to_string());
+        expired += 1;
+    }
+
+    Ok((response, expired))
+}
+
+/// Lists the failed operations waiting for a retry, oldest first
+pub fn query_failed_operations(
+    deps: Deps<KujiraQuery>,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> Result<FailedOperationsResponse, ContractError> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let operations = FAILED_OPERATIONS
+        .range(
+            deps.storage,
+            start_after.map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+        .map(|item| item.map(|(id, operation)| FailedOperationResponse { id, operation }))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(FailedOperationsResponse { operations })
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::{message_info, mock_env, MockStorage};
+    use cosmwasm_std::{BankMsg, Timestamp, Uint128};
+
+    use super::*;
+    use crate::state::{MoveStage, OutpostNav, PendingMove, OUTPOST_NAV, PENDING_MOVES};
+    use crate::testing::{fail, mock_config, mock_outpost, save_nav};
+
+    #[test]
+    fn test_record_failure_deadlines() {
+        let mut storage = MockStorage::new();
+        let mut env = mock_env();
+        let config = mock_config(vec![
+            mock_outpost("a", "osmosis-1", "uusdc"),
+            mock_outpost("b", "neutron-1", "uusdc"),
+        ]);
+
+        let payout = fail(&mut storage, &env, &config, "payout/1");
+        let refresh = fail(&mut storage, &env, &config, "refresh_nav/1/a");
+        let deadline = env.block.time.plus_seconds(60);
+        assert_eq!(
+            FAILED_OPERATIONS.load(&storage, payout).unwrap().deadline,
+            Some(deadline)
+        );
+        assert_eq!(
+            FAILED_OPERATIONS.load(&storage, refresh).unwrap().deadline,
+            None
+        );
+
+        // Failing again updates the record and keeps its deadline
+        env.block.time = env.block.time.plus_seconds(30);
+        assert_eq!(fail(&mut storage, &env, &config, "payout/1"), payout);
+        assert_eq!(
+            FAILED_OPERATIONS.load(&storage, payout).unwrap().deadline,
+            Some(deadline)
+        );
+
+        clear_failure(&mut storage, "payout/1").unwrap();
+        assert!(!FAILED_OPERATIONS.has(&storage, payout));
+        assert!(!FAILED_MEMOS.has(&storage, "payout/1"));
+    }
+
+    #[test]
+    fn test_retry_operation() {
+        let mut storage = MockStorage::new();
+        let env = mock_env();
+        let config = mock_config(vec![
+            mock_outpost("a", "osmosis-1", "uusdc"),
+            mock_outpost("b", "neutron-1", "uusdc"),
+        ]);
+        let id = fail(&mut storage, &env, &config, "payout/1");
+
+        let stranger = message_info(&Addr::unchecked("stranger"), &[]);
+        let err = try_retry_operation(&mut storage, &stranger, &config, id, None).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        let keeper = message_info(&Addr::unchecked("keeper"), &[]);
+        let response = try_retry_operation(&mut storage, &keeper, &config, id, None).unwrap();
+        assert_eq!(response.messages.len(), 1);
+        let failed = FAILED_OPERATIONS.load(&storage, id).unwrap();
+        assert!(failed.retrying);
+        assert_eq!(failed.retries, 1);
+        assert!(!awaits_retry(&storage, "payout/1").unwrap());
+
+        // Only one retry can be in flight
+        let err = try_retry_operation(&mut storage, &keeper, &config, id, None).unwrap_err();
+        assert!(matches!(err, ContractError::OperationRetrying { .. }));
+    }
+
+    #[test]
+    fn test_resend_failure() {
+        let mut storage = MockStorage::new();
+        let env = mock_env();
+        let config = mock_config(vec![
+            mock_outpost("a", "osmosis-1", "uusdc"),
+            mock_outpost("b", "neutron-1", "uusdc"),
+        ]);
+        let id = fail(&mut storage, &env, &config, "epoch/1/a");
+        assert!(awaits_retry(&storage, "epoch/1/a").unwrap());
+
+        let send: CosmosMsg = BankMsg::Send {
+            to_address: "bob".to_string(),
+            amount: vec![],
+        }
+        .into();
+        let msg = resend_failure(&mut storage, &config, "epoch/1/a", vec![send.clone()]).unwrap();
+        assert!(msg.is_some());
+        let failed = FAILED_OPERATIONS.load(&storage, id).unwrap();
+        assert_eq!(failed.messages, vec![send]);
+        assert!(failed.retrying);
+
+        // Nothing left to send forgets the failure
+        fail(&mut storage, &env, &config, "epoch/1/a");
+        let msg = resend_failure(&mut storage, &config, "epoch/1/a", vec![]).unwrap();
+        assert!(msg.is_none());
+        assert!(!FAILED_MEMOS.has(&storage, "epoch/1/a"));
+    }
+
+    #[test]
+    fn test_expire_operations_rolls_back_moves() {
+        let mut storage = MockStorage::new();
+        let mut env = mock_env();
+        let config = mock_config(vec![
+            mock_outpost("a", "osmosis-1", "uusdc"),
+            mock_outpost("b", "neutron-1", "uusdc"),
+        ]);
+        let mut state = State::default();
+        save_nav(&mut storage, "a", 0, 900);
+        OUTPOST_NAV
+            .save(
+                &mut storage,
+                "b",
+                &OutpostNav {
+                    pending: Uint128::new(100),
+                    ..OutpostNav::default()
+                },
+            )
+            .unwrap();
+        let pending = PendingMove {
+            from: "a".to_string(),
+            to: "b".to_string(),
+            amount: Uint128::new(100),
+            value: Uint128::new(100),
+            stage: MoveStage::Returning,
+            created_at: Timestamp::from_seconds(0),
+            expires_at: None,
+        };
+        PENDING_MOVES.save(&mut storage, 7, &pending).unwrap();
+        let moved = fail(&mut storage, &env, &config, "move/7");
+        let retrying = fail(&mut storage, &env, &config, "payout/1");
+        let keeper = message_info(&Addr::unchecked("keeper"), &[]);
+        try_retry_operation(&mut storage, &keeper, &config, retrying, None).unwrap();
+
+        // Nothing is due before the deadline
+        let (_, expired) = expire_operations(&mut storage, &env, &config, &mut state, 10).unwrap();
+        assert_eq!(expired, 0);
+
+        // The move puts its value back on the outpost it was leaving, a retry in flight waits
+        env.block.time = env.block.time.plus_seconds(60);
+        let (_, expired) = expire_operations(&mut storage, &env, &config, &mut state, 10).unwrap();
+        assert_eq!(expired, 1);
+        assert!(!FAILED_OPERATIONS.has(&storage, moved));
+        assert!(FAILED_OPERATIONS.has(&storage, retrying));
+        assert!(!PENDING_MOVES.has(&storage, 7));
+        assert_eq!(
+            OUTPOST_NAV.load(&storage, "a").unwrap().lent,
+            Uint128::new(1_000)
+        );
+        assert!(OUTPOST_NAV.load(&storage, "b").unwrap().pending.is_zero());
+        assert_eq!(state.total_stables, Uint128::new(1_000));
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    config::Config,
+    error::ContractError,
+    msg::Outpost,
+    oracle::usd_value,
+    state::{OUTPOST_MARKETS, OUTPOST_NAV},
+};
+use cosmwasm_std::{Coin, Decimal, QuerierWrapper, Storage, Uint128};
+use kujira::KujiraQuery;
+
+/// Share of a withdrawal paid out by a single outpost
+pub struct Leg<'a> {
+    pub outpost: &'a Outpost,
+    /// USD value paid out by the outpost
+    pub value: Uint128,
+    /// Part of the value reclaimed from the lent funds, the rest is idle
+    pub reclaimed_value: Uint128,
+}
+
+/// Value an outpost can pay out of right now
+struct Source<'a> {
+    outpost: &'a Outpost,
+    idle: Uint128,
+    /// Lent value that its market has the liquidity to return
+    reclaimable: Uint128,
+    /// Rate lost on reclaimed funds, unknown markets are used last
+    liquidity_rate: Option<Decimal>,
+}
+
+/// Splits a withdrawal of `value` across the active outposts, optionally limited to `chain`.
+/// Idle funds are used first since they earn nothing. Lent funds are then reclaimed from the
+/// outposts with the lowest lending rate, limited by the liquidity their market has available
+/// in the cached red bank data. All legs pay out in the same base denom.
+/// `flows` reports the value an outpost receives and pays out in the same batch,
+/// outposts `accepts` refuses are not used.
+pub fn route_withdrawal<'a>(
+    storage: &dyn Storage,
+    querier: &QuerierWrapper<KujiraQuery>,
+    config: &'a Config,
+    value: Uint128,
+    chain: Option<&str>,
+    flows: impl Fn(&Outpost) -> (Uint128, Uint128),
+    accepts: impl Fn(&Outpost) -> bool,
+) -> Result<Vec<Leg<'a>>, ContractError> {
+    let mut groups: BTreeMap<&str, Vec<Source>> = BTreeMap::new();
+    for outpost in config.active_outposts() {
+        if chain.is_some_and(|chain| outpost.chain != chain) || !accepts(outpost) {
+            continue;
+        }
+        let source = source(storage, querier, config, outpost, flows(outpost))?;
+        groups
+            .entry(outpost.base_denom.as_str())
+            .or_default()
+            .push(source);
+    }
+
+    // Use the base denom with the most value available that can cover the withdrawal
+    let capacity =
+        |sources: &[Source]| -> Uint128 { sources.iter().map(|s| s.idle + s.reclaimable).sum() };
+    let sources = groups
+        .into_values()
+        .filter(|sources| capacity(sources) >= value)
+        .max_by_key(|sources| capacity(sources))
+        .ok_or(ContractError::InsufficientLiquidity { amount: value })?;
+
+    Ok(allocate(sources, value))
+}
+
+fn source<'a>(
+    storage: &dyn Storage,
+    querier: &QuerierWrapper<KujiraQuery>,
+    config: &Config,
+    outpost: &'a Outpost,
+    (inflow, outflow): (Uint128, Uint128),
+) -> Result<Source<'a>, ContractError> {
+    let destination = outpost.mars_red_bank_contract.as_str();
+    let nav = OUTPOST_NAV
+        .may_load(storage, destination)?
+        .unwrap_or_default();
+
+    // Outflows of the batch are paid from the inflows and idle funds before any lent funds
+    let idle = nav.idle + inflow;
+    let lent = nav.lent.saturating_sub(outflow.saturating_sub(idle));
+    let idle = idle.saturating_sub(outflow);
+
+    let (reclaimable, liquidity_rate) = match OUTPOST_MARKETS.may_load(storage, destination)? {
+        Some(market) => {
+            let liquidity = usd_value(
+                querier,
+                config,
+                &Coin {
+                    denom: outpost.host_denom.clone(),
+                    amount: market.available_liquidity,
+                },
+            )?;
+            (lent.min(liquidity), Some(market.liquidity_rate))
+        }
+        None => (lent, None),
+    };
+
+    Ok(Source {
+        outpost,
+        idle,
+        reclaimable,
+        liquidity_rate,
+    })
+}
+
+/// Takes the idle funds of the outposts holding the most first, then reclaims lent funds from
+/// the lowest lending rate up. Legs of the same outpost are merged.
+fn allocate(mut sources: Vec<Source>, value: Uint128) -> Vec<Leg> {
+    let mut legs: Vec<Leg> = vec![];
+    let mut remaining = value;
+
+    sources.sort_by(|a, b| b.idle.cmp(&a.idle));
+    for source in &sources {
+        if remaining.is_zero() {
+            break;
+        }
+        let take = remaining.min(source.idle);
+        if !take.is_zero() {
+            legs.push(Leg {
+                outpost: source.outpost,
+                value: take,
+                reclaimed_value: Uint128::zero(),
+            });
+            remaining -= take;
+        }
+    }
+
+    sources.sort_by_key(|source| source.liquidity_rate.unwrap_or(Decimal::MAX));
+    for source in &sources {
+        if remaining.is_zero() {
+            break;
+        }
+        let take = remaining.min(source.reclaimable);
+        if take.is_zero() {
+            continue;
+        }
+        remaining -= take;
+        match legs
+            .iter_mut()
+            .find(|leg| leg.outpost.mars_red_bank_contract == source.outpost.mars_red_bank_contract)
+        {
+            Some(leg) => {
+                leg.value += take;
+                leg.reclaimed_value += take;
+            }
+            None => legs.push(Leg {
+                outpost: source.outpost,
+                value: take,
+                reclaimed_value: take,
+            }),
+        }
+    }
+
+    legs
+}
+
+/// No batched flows, for withdrawals that are routed on their own
+pub fn no_flows(_: &Outpost) -> (Uint128, Uint128) {
+    (Uint128::zero(), Uint128::zero())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::mock_outpost;
+
+    fn source(outpost: &Outpost, idle: u128, reclaimable: u128, rate: Option<&str>) -> Source<'_> {
+        Source {
+            outpost,
+            idle: Uint128::new(idle),
+            reclaimable: Uint128::new(reclaimable),
+            liquidity_rate: rate.map(|rate| rate.parse().unwrap()),
+        }
+    }
+
+    fn split(legs: &[Leg]) -> Vec<(&str, u128, u128)> {
+        legs.iter()
+            .map(|leg| {
+                (
+                    leg.outpost.mars_red_bank_contract.as_str(),
+                    leg.value.u128(),
+                    leg.reclaimed_value.u128(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_allocate_idle_first() {
+        let a = mock_outpost("a", "osmosis-1", "uusdc");
+        let b = mock_outpost("b", "neutron-1", "uusdc");
+        let sources = vec![
+            source(&a, 100, 500, Some("0.05")),
+            source(&b, 300, 500, Some("0.02")),
+        ];
+
+        // The outpost holding the most idle funds pays first, nothing is reclaimed
+        let legs = allocate(sources, Uint128::new(350));
+        assert_eq!(split(&legs), vec![("b", 300, 0), ("a", 50, 0)]);
+    }
+
+    #[test]
+    fn test_allocate_reclaims_lowest_rate_first() {
+        let a = mock_outpost("a", "osmosis-1", "uusdc");
+        let b = mock_outpost("b", "neutron-1", "uusdc");
+        let c = mock_outpost("c", "pion-1", "uusdc");
+        let sources = vec![
+            source(&a, 100, 500, Some("0.05")),
+            source(&b, 0, 200, Some("0.02")),
+            source(&c, 0, 1_000, None),
+        ];
+
+        // Idle funds of a, then the lowest rate, then a again merged into its idle leg
+        let legs = allocate(sources, Uint128::new(450));
+        assert_eq!(split(&legs), vec![("a", 250, 150), ("b", 200, 200)]);
+    }
+
+    #[test]
+    fn test_allocate_unknown_market_last() {
+        let a = mock_outpost("a", "osmosis-1", "uusdc");
+        let c = mock_outpost("c", "pion-1", "uusdc");
+        let sources = vec![source(&c, 0, 1_000, None), source(&a, 0, 10, Some("0.30"))];
+
+        let legs = allocate(sources, Uint128::new(11));
+        assert_eq!(split(&legs), vec![("a", 10, 10), ("c", 1, 1)]);
+    }
+
+    #[test]
+    fn test_allocate_splits_without_dust() {
+        let a = mock_outpost("a", "osmosis-1", "uusdc");
+        let b = mock_outpost("b", "neutron-1", "uusdc");
+        let c = mock_outpost("c", "pion-1", "uusdc");
+        let sources = vec![
+            source(&a, 7, 13, Some("0.01")),
+            source(&b, 3, 17, Some("0.02")),
+            source(&c, 1, 19, Some("0.03")),
+        ];
+
+        // Odd amounts spread over every source still add up to the withdrawal exactly
+        let value = Uint128::new(47);
+        let legs = allocate(sources, value);
+        let total: Uint128 = legs.iter().map(|leg| leg.value).sum();
+        assert_eq!(total, value);
+        assert_eq!(
+            split(&legs),
+            vec![("a", 20, 13), ("b", 20, 17), ("c", 7, 6)]
+        );
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    config::Config,
+    error::ContractError,
+    oracle::{amount_for_usd, usd_value},
+    state::{
+        AllocationSimulation, OutpostNav, OutpostWeight, Projection, RebalanceSimulation,
+        SimulatedMove, TransferKind, LAST_REBALANCE, NEXT_OPERATION_ID, OUTPOST_MARKETS,
+        OUTPOST_NAV, STATE,
+    },
+};
+use cosmwasm_std::{ensure, Coin, Decimal, Deps, Env, Order, StdResult, Storage, Uint128};
+use kujira::KujiraQuery;
+
+use super::{
+    allocate::{allocate_deposit, ensure_exposure, AUTO_DESTINATION},
+    buffer::buffer_shortfall,
+    ica::lend_msg,
+    rebalance::{move_ica_msg, move_msgs, plan_rebalance},
+    swap::swap_msg,
+    transfer::{outpost_transfer, transfer_msg},
+};
+
+/// Known positions of the active outposts and of any other outpost that still holds value
+fn outpost_navs(storage: &dyn Storage, config: &Config) -> StdResult<BTreeMap<String, OutpostNav>> {
+    let mut navs: BTreeMap<String, OutpostNav> = config
+        .active_outposts()
+        .map(|outpost| {
+            (
+                outpost.mars_red_bank_contract.clone(),
+                OutpostNav::default(),
+            )
+        })
+        .collect();
+    for item in OUTPOST_NAV.range(storage, None, None, Order::Ascending) {
+        let (destination, nav) = item?;
+        navs.insert(destination, nav);
+    }
+    Ok(navs)
+}
+
+/// Weights of the outposts in a TVL of `tvl` and the rate it earns, given their positions
+fn project(
+    storage: &dyn Storage,
+    config: &Config,
+    tvl: Uint128,
+    navs: &BTreeMap<String, OutpostNav>,
+) -> StdResult<Projection> {
+    let mut weights = vec![];
+    let mut blended_apy = Decimal::zero();
+    for (destination, nav) in navs {
+        let value = nav.total();
+        let liquidity_rate = OUTPOST_MARKETS
+            .may_load(storage, destination)?
+            .map(|market| market.liquidity_rate);
+        if !tvl.is_zero() {
+            blended_apy += Decimal::from_ratio(nav.lent, tvl) * liquidity_rate.unwrap_or_default();
+        }
+
+        weights.push(OutpostWeight {
+            destination: destination.clone(),
+            value,
+            weight: if tvl.is_zero() {
+                Decimal::zero()
+            } else {
+                Decimal::from_ratio(value, tvl)
+            },
+            target_weight: config
+                .find_destination_outpost(destination)
+                .and_then(|outpost| outpost.target_weight),
+            liquidity_rate,
+        });
+    }
+
+    Ok(Projection {
+        tvl,
+        weights,
+        blended_apy,
+    })
+}
+
+/// Previews a deposit of `amount` without executing it: the outpost it would be routed to,
+/// the messages sent on Kujira and by the ICA, and the weights of the outposts once it is lent.
+/// Without a destination, or with `auto`, the deposit is routed by the allocation engine.
+pub fn query_simulate_allocation(
+    deps: Deps<KujiraQuery>,
+    env: &Env,
+    amount: Coin,
+    destination: Option<String>,
+) -> Result<AllocationSimulation, ContractError> {
+    let config = Config::load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+    ensure!(!amount.amount.is_zero(), ContractError::InvalidAmount {});
+
+    let value = usd_value(&deps.querier, &config, &amount)?;
+    let outpost = match destination.as_deref() {
+        None | Some(AUTO_DESTINATION) => {
+            allocate_deposit(deps.storage, &config, &state, &amount.denom, value)?
+        }
+        Some(destination) => {
+            let outpost = config.find_destination_outpost(destination).ok_or(
+                ContractError::DestinationNotFound {
+                    destination: destination.to_string(),
+                },
+            )?;
+            ensure_exposure(deps.storage, &state, outpost, value)?;
+            outpost
+        }
+    };
+    if outpost.account_id.is_none() {
+        return Err(ContractError::NoCreditAccount {});
+    }
+
+    let mut messages = vec![];
+    let mut swap_pair = None;
+    let mut base_amount = amount.amount;
+    if amount.denom != outpost.base_denom {
+        let (pair, msg) = swap_msg(&config, &amount, &outpost.base_denom)?;
+        messages.push(msg.into());
+        swap_pair = Some(pair);
+        base_amount = amount_for_usd(&deps.querier, &config, &outpost.base_denom, value)?;
+    }
+
+    let buffered_value = buffer_shortfall(deps.storage, &config, &state, value)?;
+    // A deposit the oracle prices at nothing has nothing buffered either
+    let buffered = if buffered_value.is_zero() {
+        Uint128::zero()
+    } else {
+        base_amount.multiply_ratio(buffered_value, value)
+    };
+    let bridged = base_amount - buffered;
+
+    let mut ica_messages = vec![];
+    if !bridged.is_zero() {
+        let id = NEXT_OPERATION_ID
+            .may_load(deps.storage)?
+            .unwrap_or_default();
+        let transfer = outpost_transfer(
+            outpost,
+            Coin {
+                denom: outpost.base_denom.clone(),
+                amount: bridged,
+            },
+            TransferKind::Deposit,
+            id,
+            env.contract.address.clone(),
+        )?;
+        messages.push(transfer_msg(env, &transfer)?.into());
+        ica_messages.push(lend_msg(
+            outpost,
+            Coin {
+                denom: outpost.host_denom.clone(),
+                amount: bridged,
+            },
+        )?);
+    }
+
+    let mut navs = outpost_navs(deps.storage, &config)?;
+    let current = project(deps.storage, &config, state.total_stables, &navs)?;
+    navs.entry(outpost.mars_red_bank_contract.clone())
+        .or_default()
+        .lent += value - buffered_value;
+    let projected = project(deps.storage, &config, state.total_stables + value, &navs)?;
+
+    Ok(AllocationSimulation {
+        destination: outpost.mars_red_bank_contract.clone(),
+        value,
+        buffered,
+        bridged,
+        swap_pair,
+        messages,
+        ica_messages,
+        current,
+        projected,
+    })
+}
+
+/// Previews the moves a rebalance would make right now, the messages it would send and the
+/// weights of the outposts once the moved funds are lent again. The cooldown is not enforced,
+/// `ready_at` reports when the next rebalance can be executed.
+pub fn query_simulate_rebalance(
+    deps: Deps<KujiraQuery>,
+    env: &Env,
+) -> Result<RebalanceSimulation, ContractError> {
+    let config = Config::load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+    let params = config
+        .rebalance
+        .as_ref()
+        .ok_or(ContractError::RebalanceDisabled {})?;
+    let ready_at = LAST_REBALANCE
+        .may_load(deps.storage)?
+        .map(|last| last.plus_seconds(params.cooldown))
+        .filter(|ready_at| *ready_at > env.block.time);
+
+    let mut navs = outpost_navs(deps.storage, &config)?;
+    let current = project(deps.storage, &config, state.total_stables, &navs)?;
+
+    // Moves are tracked under consecutive operation ids
+    let next_id = NEXT_OPERATION_ID
+        .may_load(deps.storage)?
+        .unwrap_or_default();
+    let mut moves = vec![];
+    let mut messages = vec![];
+    let planned = plan_rebalance(deps.storage, &deps.querier, &config, &state, params)?;
+    for (id, planned) in (next_id..).zip(planned) {
+        let from = planned.from.mars_red_bank_contract.clone();
+        let to = planned.to.mars_red_bank_contract.clone();
+        let nav = navs.entry(from.clone()).or_default();
+        nav.lent = nav.lent.saturating_sub(planned.value);
+        navs.entry(to.clone()).or_default().lent += planned.value;
+
+        messages.push(move_ica_msg(env, planned.from, id, planned.amount)?.into());
+        moves.push(SimulatedMove {
+            id,
+            from,
+            to,
+            value: planned.value,
+            amount: planned.amount,
+            spread: planned.spread,
+            reclaim_messages: move_msgs(env, planned.from, id, planned.amount)?,
+            lend_messages: vec![lend_msg(
+                planned.to,
+                Coin {
+                    denom: planned.to.host_denom.clone(),
+                    amount: planned.amount,
+                },
+            )?],
+        });
+    }
+    let projected = project(deps.storage, &config, state.total_stables, &navs)?;
+
+    Ok(RebalanceSimulation {
+        moves,
+        messages,
+        ready_at,
+        current,
+        projected,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::{mock_env, MockApi, MockStorage};
+    use cosmwasm_std::QuerierWrapper;
+
+    use super::*;
+    use crate::config::CONFIG;
+    use crate::msg::RebalanceParams;
+    use crate::state::OutpostMarket;
+    use crate::testing::{mock_config, mock_oracle, mock_outpost, mock_state, save_nav};
+
+    fn save_state(storage: &mut MockStorage, config: &Config, receipts: u128) {
+        CONFIG.save(storage, config).unwrap();
+        let state = mock_state(storage, receipts);
+        STATE.save(storage, &state).unwrap();
+    }
+
+    #[test]
+    fn test_simulate_allocation_fills_buffer() {
+        let mut storage = MockStorage::new();
+        let api = MockApi::default();
+        let oracle = mock_oracle(Decimal::one());
+        let env = mock_env();
+        let config = Config {
+            buffer_ratio: Some(Decimal::percent(10)),
+            ..mock_config(vec![mock_outpost("a", "osmosis-1", "uusdc")])
+        };
+        save_nav(&mut storage, "a", 0, 1_000);
+        save_state(&mut storage, &config, 1_000);
+        let deps = Deps {
+            storage: &storage,
+            api: &api,
+            querier: QuerierWrapper::new(&oracle),
+        };
+
+        // The buffer is topped up to 10% of the TVL of 2000, the rest is lent on a
+        let simulation = query_simulate_allocation(
+            deps,
+            &env,
+            Coin::new(1_000u128, "uusdc"),
+            Some("a".to_string()),
+        )
+        .unwrap();
+        assert_eq!(simulation.destination, "a");
+        assert_eq!(simulation.value, Uint128::new(1_000));
+        assert_eq!(simulation.buffered, Uint128::new(200));
+        assert_eq!(simulation.bridged, Uint128::new(800));
+        assert_eq!(simulation.swap_pair, None);
+        assert_eq!(simulation.messages.len(), 1);
+        assert_eq!(simulation.ica_messages.len(), 1);
+        assert_eq!(simulation.current.tvl, Uint128::new(1_000));
+        assert_eq!(simulation.projected.tvl, Uint128::new(2_000));
+        assert_eq!(simulation.projected.weights[0].value, Uint128::new(1_800));
+    }
+
+    #[test]
+    fn test_simulate_allocation_worthless_deposit() {
+        let mut storage = MockStorage::new();
+        let api = MockApi::default();
+        let oracle = mock_oracle(Decimal::zero());
+        let env = mock_env();
+        let config = Config {
+            buffer_ratio: Some(Decimal::percent(10)),
+            ..mock_config(vec![mock_outpost("a", "osmosis-1", "uusdc")])
+        };
+        save_nav(&mut storage, "a", 0, 1_000);
+        save_state(&mut storage, &config, 1_000);
+        let deps = Deps {
+            storage: &storage,
+            api: &api,
+            querier: QuerierWrapper::new(&oracle),
+        };
+
+        // Priced at nothing, the whole deposit is bridged instead of dividing by its value
+        let simulation = query_simulate_allocation(
+            deps,
+            &env,
+            Coin::new(1_000u128, "uusdc"),
+            Some("a".to_string()),
+        )
+        .unwrap();
+        assert_eq!(simulation.value, Uint128::zero());
+        assert_eq!(simulation.buffered, Uint128::zero());
+        assert_eq!(simulation.bridged, Uint128::new(1_000));
+    }
+
+    #[test]
+    fn test_simulate_rebalance_projects_moves() {
+        let mut storage = MockStorage::new();
+        let api = MockApi::default();
+        let oracle = mock_oracle(Decimal::one());
+        let env = mock_env();
+        let config = Config {
+            rebalance: Some(RebalanceParams {
+                min_spread: Decimal::percent(1),
+                max_move: Uint128::new(500),
+                cooldown: 60,
+            }),
+            ..mock_config(vec![
+                mock_outpost("a", "osmosis-1", "uusdc"),
+                mock_outpost("c", "pion-1", "uusdc"),
+            ])
+        };
+        for (destination, rate) in [("a", 2), ("c", 9)] {
+            save_nav(&mut storage, destination, 0, 1_000);
+            let market = OutpostMarket {
+                available_liquidity: Uint128::new(10_000),
+                liquidity_rate: Decimal::percent(rate),
+                updated_at: env.block.time,
+            };
+            OUTPOST_MARKETS
+                .save(&mut storage, destination, &market)
+                .unwrap();
+        }
+        save_state(&mut storage, &config, 2_000);
+        NEXT_OPERATION_ID.save(&mut storage, &5).unwrap();
+        LAST_REBALANCE
+            .save(&mut storage, &env.block.time.minus_seconds(10))
+            .unwrap();
+        let deps = Deps {
+            storage: &storage,
+            api: &api,
+            querier: QuerierWrapper::new(&oracle),
+        };
+
+        // The cooldown is reported but not enforced, 500 moves from a to c
+        let simulation = query_simulate_rebalance(deps, &env).unwrap();
+        assert_eq!(simulation.ready_at, Some(env.block.time.plus_seconds(50)));
+        assert_eq!(simulation.moves.len(), 1);
+        let planned = &simulation.moves[0];
+        assert_eq!(
+            (planned.id, planned.from.as_str(), planned.to.as_str()),
+            (5, "a", "c")
+        );
+        assert_eq!(planned.value, Uint128::new(500));
+        assert_eq!(simulation.messages.len(), 1);
+
+        let values = |projection: &Projection| -> Vec<Uint128> {
+            projection
+                .weights
+                .iter()
+                .map(|weight| weight.value)
+                .collect()
+        };
+        assert_eq!(values(&simulation.current), vec![Uint128::new(1_000); 2]);
+        assert_eq!(
+            values(&simulation.projected),
+            vec![Uint128::new(500), Uint128::new(1_500)]
+        );
+        assert!(simulation.projected.blended_apy > simulation.current.blended_apy);
+    }
+}
@@ -0,0 +1,129 @@
+use crate::{
+    config::Config,
+    error::ContractError,
+    state::{PendingDeposit, PendingSwap, STATE, SWAP_IN_DISPATCH},
+};
+use cosmwasm_std::{
+    ensure, wasm_execute, Coin, DepsMut, Env, Reply, Response, StdError, SubMsg, WasmMsg,
+};
+use kujira::{fin::ExecuteMsg as FinExecuteMsg, KujiraMsg, KujiraQuery};
+
+use super::deposit::create_deposit;
+
+/// Reply id of FIN swaps of deposits into the base denom of their outpost
+pub const SWAP_REPLY_ID: u64 = 2;
+
+/// Swaps a deposit in another whitelisted denom into the base denom of its outpost through FIN.
+/// The pending deposit is only created once the swap returned at least `min_return`.
+pub fn swap_deposit(
+    deps: DepsMut<KujiraQuery>,
+    env: &Env,
+    config: &Config,
+    swap: PendingSwap,
+    base_denom: &str,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let (pair, msg) = swap_msg(config, &swap.offer, base_denom)?;
+
+    let response = Response::new()
+        .add_submessage(SubMsg::reply_on_success(msg, SWAP_REPLY_ID))
+        .add_attribute("method", "swap_deposit")
+        .add_attribute("owner", swap.owner.to_string())
+        .add_attribute("offer", swap.offer.to_string())
+        .add_attribute("pair", pair.clone());
+
+    let swap = PendingSwap {
+        balance_before: deps
+            .querier
+            .query_balance(&env.contract.address, base_denom)?
+            .amount,
+        ..swap
+    };
+    SWAP_IN_DISPATCH.save(deps.storage, &swap)?;
+
+    Ok(response)
+}
+
+/// The FIN pair `offer` is swapped into `base_denom` through and the swap message
+pub fn swap_msg(
+    config: &Config,
+    offer: &Coin,
+    base_denom: &str,
+) -> Result<(String, WasmMsg), ContractError> {
+    config.find_denom(&offer.denom)?;
+    let pair = config.find_fin_pair(&offer.denom, base_denom)?;
+
+    let msg = wasm_execute(
+        pair.pair.clone(),
+        &FinExecuteMsg::Swap {
+            offer_asset: None,
+            belief_price: None,
+            max_spread: None,
+            to: None,
+            callback: None,
+        },
+        vec![offer.clone()],
+    )?;
+    Ok((pair.pair.clone(), msg))
+}
+
+/// Measures the base denom returned by the swap and continues the deposit with it.
+/// A return below the minimum of the user reverts the swap together with the deposit.
+pub fn handle_swap_reply(
+    deps: DepsMut<KujiraQuery>,
+    env: &Env,
+    reply: Reply,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    reply.result.into_result().map_err(StdError::generic_err)?;
+
+    let swap = SWAP_IN_DISPATCH.load(deps.storage)?;
+    SWAP_IN_DISPATCH.remove(deps.storage);
+
+    let config = Config::load(deps.storage)?;
+    let outpost = config.find_destination_outpost(&swap.destination).ok_or(
+        ContractError::DestinationNotFound {
+            destination: swap.destination.clone(),
+        },
+    )?;
+
+    let balance = deps
+        .querier
+        .query_balance(&env.contract.address, &outpost.base_denom)?
+        .amount;
+    let returned = balance.saturating_sub(swap.balance_before);
+    let min_return = swap.min_return.unwrap_or_default();
+    ensure!(
+        !returned.is_zero() && returned >= min_return,
+        ContractError::MinReturnNotMet {
+            min_return,
+            returned,
+        }
+    );
+
+    let coin = Coin {
+        denom: outpost.base_denom.clone(),
+        amount: returned,
+    };
+    let deposit = PendingDeposit::new(
+        swap.owner,
+        coin,
+        outpost,
+        swap.min_shares_out,
+        env.block.time,
+    );
+    let mut state = STATE.load(deps.storage)?;
+    let response = create_deposit(
+        deps.storage,
+        &deps.querier,
+        env,
+        &config,
+        &mut state,
+        outpost,
+        swap.id,
+        deposit,
+    )?;
+    STATE.save(deps.storage, &state)?;
+
+    Ok(response
+        .add_attribute("offer", swap.offer.to_string())
+        .add_attribute("returned", returned))
+}
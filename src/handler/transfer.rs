@@ -0,0 +1,378 @@
+use crate::{
+    config::Config,
+    error::ContractError,
+    msg::{Forward, ForwardMemo, IBCLifecycleComplete, Outpost, RemoteChain},
+    state::{
+        PendingTransfer, RequestStatus, TransferKind, PENDING_DEPOSITS, PENDING_WITHDRAWALS,
+        TRANSFERS, TRANSFERS_IN_DISPATCH,
+    },
+};
+use anybuf::Anybuf;
+use cosmwasm_std::{
+    to_json_string, Addr, BankMsg, Coin, CosmosMsg, Env, IbcMsg, IbcTimeout, Reply, Response,
+    StdError, Storage, SubMsg, SubMsgResponse, Timestamp,
+};
+use kujira::KujiraMsg;
+use serde::Serialize;
+
+use super::{
+    deposit::deposit_transferred,
+    in_flight::{end_operation, OperationKind},
+    rebalance::{move_to_buffer, move_transferred},
+    request::{refund_deposit_request, settle_redeem_leg},
+};
+
+/// Reply id of outgoing ICS-20 transfers, used to learn their packet sequence
+pub const TRANSFER_REPLY_ID: u64 = 1;
+
+/// Maximum number of times a timed out transfer is sent again before its funds are refunded
+pub const MAX_TRANSFER_RETRIES: u32 = 2;
+
+/// Builds an ICS-20 transfer of the funds of a pending deposit or move to the ICA of its outpost.
+pub fn transfer_to_outpost(
+    storage: &mut dyn Storage,
+    env: &Env,
+    outpost: &Outpost,
+    coin: Coin,
+    kind: TransferKind,
+    operation_id: u64,
+    refund_address: Addr,
+) -> Result<SubMsg<KujiraMsg>, ContractError> {
+    let transfer = outpost_transfer(outpost, coin, kind, operation_id, refund_address)?;
+    ibc_transfer(storage, env, transfer)
+}
+
+/// The transfer of `coin` from Kujira to the ICA of the outpost
+pub fn outpost_transfer(
+    outpost: &Outpost,
+    coin: Coin,
+    kind: TransferKind,
+    operation_id: u64,
+    refund_address: Addr,
+) -> Result<PendingTransfer, ContractError> {
+    let ica_address = outpost
+        .ica_address
+        .clone()
+        .ok_or(ContractError::NoIcaAddress {
+            destination: outpost.mars_red_bank_contract.clone(),
+        })?;
+
+    Ok(PendingTransfer {
+        kind,
+        operation_id,
+        channel: outpost.transfer_channel.clone(),
+        to_address: ica_address,
+        coin,
+        forward: None,
+        timeout: outpost.transfer_timeout,
+        refund_address,
+        retries: 0,
+    })
+}
+
+/// Builds an ICS-20 transfer paying out a withdrawal to `recipient` on a remote chain.
+/// Chains without a direct channel are reached through packet-forward-middleware.
+/// If the transfer fails, the funds are refunded to the owner on Kujira.
+pub fn transfer_to_remote(
+    storage: &mut dyn Storage,
+    env: &Env,
+    remote_chain: &RemoteChain,
+    recipient: String,
+    coin: Coin,
+    operation_id: u64,
+    refund_address: Addr,
+) -> Result<SubMsg<KujiraMsg>, ContractError> {
+    let timeout = remote_chain.timeout;
+    let mut receiver = recipient;
+    let mut forward: Option<ForwardMemo> = None;
+    for channel in remote_chain.hops.iter().rev() {
+        forward = Some(ForwardMemo {
+            forward: Forward {
+                receiver,
+                port: "transfer".to_string(),
+                channel: channel.clone(),
+                timeout: format!("{}s", timeout),
+                retries: 2,
+                next: forward.map(Box::new),
+            },
+        });
+        // Intermediate chains only forward the funds, the receiver is not used
+        receiver = "pfm".to_string();
+    }
+
+    ibc_transfer(
+        storage,
+        env,
+        PendingTransfer {
+            kind: TransferKind::Withdrawal,
+            operation_id,
+            channel: remote_chain.channel.clone(),
+            to_address: receiver,
+            coin,
+            forward,
+            timeout,
+            refund_address,
+            retries: 0,
+        },
+    )
+}
+
+/// Builds an ICS-20 transfer that asks ibc-hooks to report its ack or timeout back to this contract.
+/// The transfer is correlated to its operation once the reply reveals the packet sequence.
+pub fn ibc_transfer(
+    storage: &mut dyn Storage,
+    env: &Env,
+    transfer: PendingTransfer,
+) -> Result<SubMsg<KujiraMsg>, ContractError> {
+    let msg = transfer_msg(env, &transfer)?;
+    TRANSFERS_IN_DISPATCH.push_back(storage, &transfer)?;
+
+    Ok(SubMsg::reply_on_success(msg, TRANSFER_REPLY_ID))
+}
+
+/// The ICS-20 transfer message of `transfer`, with the ibc-hooks callback memo
+pub fn transfer_msg(env: &Env, transfer: &PendingTransfer) -> Result<IbcMsg, ContractError> {
+    Ok(IbcMsg::Transfer {
+        channel_id: transfer.channel.clone(),
+        to_address: transfer.to_address.clone(),
+        amount: transfer.coin.clone(),
+        timeout: IbcTimeout::with_timestamp(env.block.time.plus_seconds(transfer.timeout)),
+        memo: Some(callback_memo(env, transfer.forward.as_ref())?),
+    })
+}
+
+/// Builds a `MsgTransfer` for the ICA of the outpost, sending `coin` from the host chain
+/// back to `receiver` on Kujira. The message is executed by the ICA, so it is encoded as protobuf.
+pub fn ica_transfer_msg(
+    outpost: &Outpost,
+    coin: &Coin,
+    receiver: String,
+    timeout: Timestamp,
+    memo: String,
+) -> Result<CosmosMsg, ContractError> {
+    let sender = outpost
+        .ica_address
+        .clone()
+        .ok_or(ContractError::NoIcaAddress {
+            destination: outpost.mars_red_bank_contract.clone(),
+        })?;
+
+    let token = Anybuf::new()
+        .append_string(1, &coin.denom)
+        .append_string(2, coin.amount.to_string());
+    let value = Anybuf::new()
+        .append_string(1, "transfer")
+        .append_string(2, &outpost.host_transfer_channel)
+        .append_message(3, &token)
+        .append_string(4, sender)
+        .append_string(5, receiver)
+        .append_uint64(7, timeout.nanos())
+        .append_string(8, memo);
+
+    #[allow(deprecated)]
+    Ok(CosmosMsg::Stargate {
+        type_url: "/ibc.applications.transfer.v1.MsgTransfer".to_string(),
+        value: value.into_vec().into(),
+    })
+}
+
+/// Memo asking ibc-hooks on Kujira to execute `msg` on this contract with the received funds
+pub fn wasm_hook_memo(env: &Env, msg: &impl Serialize) -> Result<String, ContractError> {
+    Ok(format!(
+        r#"{{"wasm":{{"contract":"{}","msg":{}}}}}"#,
+        env.contract.address,
+        to_json_string(msg)?
+    ))
+}
+
+#[derive(Serialize)]
+struct TransferMemo<'a> {
+    ibc_callback: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    forward: Option<&'a Forward>,
+}
+
+/// Memo asking ibc-hooks to send the transfer lifecycle to this contract,
+/// along with the packet forwarding instructions of the transfer if any.
+fn callback_memo(env: &Env, forward: Option<&ForwardMemo>) -> Result<String, ContractError> {
+    Ok(to_json_string(&TransferMemo {
+        ibc_callback: env.contract.address.as_str(),
+        forward: forward.map(|memo| &memo.forward),
+    })?)
+}
+
+/// Records the packet sequence of the transfer that was just dispatched
+pub fn handle_transfer_reply(
+    storage: &mut dyn Storage,
+    reply: Reply,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let response = reply.result.into_result().map_err(StdError::generic_err)?;
+    let (channel, sequence) = parse_send_packet(&response)?;
+
+    // Submessages are dispatched in order, so replies arrive in the order the transfers were built
+    let transfer = TRANSFERS_IN_DISPATCH
+        .pop_front(storage)?
+        .ok_or_else(|| StdError::generic_err("no transfer in dispatch"))?;
+    TRANSFERS.save(storage, (&channel, sequence), &transfer)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "transfer_reply")
+        .add_attribute("operation_id", transfer.operation_id.to_string())
+        .add_attribute("channel", channel)
+        .add_attribute("sequence", sequence.to_string()))
+}
+
+/// Reads the source channel and sequence of the packet from the `send_packet` event
+fn parse_send_packet(response: &SubMsgResponse) -> Result<(String, u64), ContractError> {
+    let event = response
+        .events
+        .iter()
+        .find(|event| event.ty == "send_packet")
+        .ok_or_else(|| StdError::generic_err("missing send_packet event"))?;
+
+    let attribute = |key: &str| {
+        event
+            .attributes
+            .iter()
+            .find(|attr| attr.key == key)
+            .map(|attr| attr.value.clone())
+            .ok_or_else(|| StdError::generic_err(format!("missing {key} attribute")))
+    };
+
+    let channel = attribute("packet_src_channel")?;
+    let sequence = attribute("packet_sequence")?
+        .parse::<u64>()
+        .map_err(|_| StdError::generic_err("invalid packet_sequence"))?;
+
+    Ok((channel, sequence))
+}
+
+/// Handles the ack or timeout of a transfer sent by this contract.
+/// A successful deposit transfer continues with pricing the deposit on its outpost and
+/// a successful withdrawal payout completes the withdrawal.
+/// Timed out deposit and move transfers are sent again up to `MAX_TRANSFER_RETRIES` times,
+/// failed ones are refunded.
+pub fn transfer_lifecycle_complete(
+    storage: &mut dyn Storage,
+    env: &Env,
+    config: &Config,
+    msg: IBCLifecycleComplete,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let (channel, sequence, success, timed_out) = match msg {
+        IBCLifecycleComplete::IBCAck {
+            channel,
+            sequence,
+            success,
+            ..
+        } => (channel, sequence, success, false),
+        IBCLifecycleComplete::IBCTimeout { channel, sequence } => (channel, sequence, false, true),
+    };
+
+    // Transfers that were not sent through `ibc_transfer` are not tracked
+    let Some(mut transfer) = TRANSFERS.may_load(storage, (&channel, sequence))? else {
+        return Ok(Response::new()
+            .add_attribute("method", "transfer_lifecycle_complete")
+            .add_attribute("ignored", format!("{}/{}", channel, sequence)));
+    };
+    TRANSFERS.remove(storage, (&channel, sequence));
+
+    let response = Response::new()
+        .add_attribute("method", "transfer_lifecycle_complete")
+        .add_attribute("operation_id", transfer.operation_id.to_string())
+        .add_attribute("channel", &channel)
+        .add_attribute("sequence", sequence.to_string());
+
+    if success {
+        return match transfer.kind {
+            TransferKind::Deposit => {
+                Ok(deposit_transferred(storage, config, transfer.operation_id)?
+                    .add_attributes(response.attributes))
+            }
+            TransferKind::Withdrawal => {
+                complete_withdrawal(storage, transfer.operation_id)?;
+                Ok(response.add_attribute("status", "paid_out"))
+            }
+            TransferKind::Move => Ok(move_transferred(storage, config, transfer.operation_id)?
+                .add_attributes(response.attributes)),
+        };
+    }
+
+    // ICS-20 returned the funds to this contract, so they can be sent again or refunded.
+    // Withdrawal payouts are refunded to the owner on Kujira right away.
+    if timed_out
+        && matches!(transfer.kind, TransferKind::Deposit | TransferKind::Move)
+        && transfer.retries < MAX_TRANSFER_RETRIES
+    {
+        transfer.retries += 1;
+        let retry = ibc_transfer(storage, env, transfer)?;
+        return Ok(response
+            .add_submessage(retry)
+            .add_attribute("status", "retried"));
+    }
+
+    // Refunded deposits are cancelled, refunded withdrawals still delivered the assets to the owner
+    match transfer.kind {
+        TransferKind::Deposit => {
+            if let Some(deposit) = PENDING_DEPOSITS.may_load(storage, transfer.operation_id)? {
+                PENDING_DEPOSITS.remove(storage, transfer.operation_id);
+                end_operation(storage, &deposit.destination, OperationKind::Deposit)?;
+            }
+            refund_deposit_request(storage, &transfer.refund_address, transfer.operation_id)?;
+        }
+        TransferKind::Withdrawal => complete_withdrawal(storage, transfer.operation_id)?,
+        // The funds of the vault stay on Kujira and join the liquidity buffer
+        TransferKind::Move => {
+            move_to_buffer(storage, transfer.operation_id, &transfer.coin)?;
+            return Ok(response.add_attribute("status", "buffered"));
+        }
+    }
+
+    let refund = BankMsg::Send {
+        to_address: transfer.refund_address.to_string(),
+        amount: vec![transfer.coin],
+    };
+
+    Ok(response
+        .add_message(refund)
+        .add_attribute("status", "refunded"))
+}
+
+/// Removes a withdrawal whose funds reached the owner and settles its leg of the redeem request
+fn complete_withdrawal(storage: &mut dyn Storage, id: u64) -> Result<(), ContractError> {
+    if let Some(withdrawal) = PENDING_WITHDRAWALS.may_load(storage, id)? {
+        PENDING_WITHDRAWALS.remove(storage, id);
+        settle_redeem_leg(
+            storage,
+            &withdrawal.owner,
+            withdrawal.request_id,
+            RequestStatus::Claimed,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::Event;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_send_packet() {
+        #[allow(deprecated)]
+        let response = SubMsgResponse {
+            events: vec![
+                Event::new("ibc_transfer").add_attribute("sender", "kujira1contract"),
+                Event::new("send_packet")
+                    .add_attribute("packet_src_channel", "channel-3")
+                    .add_attribute("packet_sequence", "42"),
+            ],
+            data: None,
+            msg_responses: vec![],
+        };
+
+        let (channel, sequence) = parse_send_packet(&response).unwrap();
+        assert_eq!(channel, "channel-3");
+        assert_eq!(sequence, 42);
+    }
+}
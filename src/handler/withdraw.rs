@@ -1,14 +1,1282 @@
-use crate::{error::ContractError, msg::WithdrawMsg};
-use cosmwasm_std::Response;
-use kujira::KujiraMsg;
+use crate::{
+    config::Config,
+    error::ContractError,
+    helpers::{ensure_min_request, ensure_outpost_hook},
+    msg::{ExecuteMsg, Outpost, WithdrawMsg},
+    oracle::amount_for_usd,
+    state::{
+        next_operation_id, Payout, PendingWithdrawal, QueuedWithdrawal, Request, RequestKind,
+        RequestStatus, State, WithdrawStage, WithdrawalQueueResponse, BUFFERS, EPOCH,
+        EPOCH_WITHDRAWALS, OUTPOST_NAV, PENDING_WITHDRAWALS, REQUESTS, VIRTUAL_RECEIPTS,
+        WITHDRAWAL_QUEUE,
+    },
+};
+use cosmwasm_std::{
+    ensure, wasm_execute, Addr, Api, BankMsg, Coin, CosmosMsg, Deps, DepsMut, Empty, Env,
+    MessageInfo, QuerierWrapper, Response, StdResult, Storage, Timestamp, Uint128, WasmMsg,
+};
+use cw_ica_controller::types::query_msg::IcaQueryResult;
+use kujira::{KujiraMsg, KujiraQuery};
+use mars_types::credit_manager::{Action, ActionCoin, ExecuteMsg as CreditManagerExecuteMsg};
+
+use super::{
+    buffer::{pay_from_buffer, receive_top_up},
+    ica::{
+        available_liquidity, execute_ica, lend_ica_msg, market_query, parse_market, query_ica,
+        IcaOperation,
+    },
+    in_flight::{begin_operation, can_accept, end_operation, OperationKind},
+    request::{
+        add_redeem_legs, drop_redeem_remainder, queue_redeem_remainder, return_redeem_leg,
+        settle_redeem_leg,
+    },
+    route::{no_flows, route_withdrawal},
+    transfer::{ica_transfer_msg, transfer_to_remote, wasm_hook_memo},
+};
+
+const DEFAULT_QUEUE_LIMIT: u32 = 10;
+const MAX_QUEUE_LIMIT: u32 = 30;
+/// Attempts at paying out a withdrawal whose transfer timed out before its funds are lent again
+const MAX_PAYOUT_RETRIES: u32 = 2;
 
 /// Responsible for withdrawing the user's funds from the target chain lending contract and sending it back to the user.
-/// Validates the user's right to withdraw.
-/// Then prepares the red_bank message and calls the corresponding CW ICA Controller to execute the deposit on the target chain.
-/// The users virtual receipt tokens are then burned.
-pub fn try_withdraw(_msg: WithdrawMsg) -> Result<Response<KujiraMsg>, ContractError> {
+/// Validates the user's right to withdraw and burns the virtual receipt tokens at the current NAV.
+/// Withdrawals worth less than the minimum request value are refused.
+/// The USD value of the receipts is converted to the host denom through the Kujira oracle.
+/// The withdrawal is split across the outposts that can supply the liquidity, see `route_withdrawal`.
+/// Then prepares the red_bank message and calls the corresponding CW ICA Controller to reclaim the funds
+/// on each target chain. Payouts on the outpost chain are sent by the ICA directly, all others are
+/// transferred back to this contract, which pays the user once they arrive.
+/// Only the part the outposts actually reclaim is paid out, the remainder waits in the withdrawal queue.
+/// With epochs enabled the withdrawal is queued and priced once its epoch is processed instead.
+pub fn try_withdraw(
+    deps: DepsMut<KujiraQuery>,
+    env: &Env,
+    info: &MessageInfo,
+    config: &Config,
+    state: &mut State,
+    msg: WithdrawMsg,
+    auto_claim: bool,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let storage = deps.storage;
+
     // Check if the user has the right amount of virtual receipt tokens.
+    let available = VIRTUAL_RECEIPTS
+        .may_load(storage, info.sender.clone())?
+        .unwrap_or_default();
+    ensure!(
+        !msg.amount.is_zero() && msg.amount <= available,
+        ContractError::InsufficientReceipts {
+            requested: msg.amount,
+            available,
+        }
+    );
+    ensure_min_request(config, state.assets_for(msg.amount))?;
+
+    let payout = resolve_payout(config, msg.recipient, msg.remote_chain)?;
+    if config.epoch_length.is_some() {
+        return queue_withdrawal(
+            storage,
+            env,
+            info,
+            msg.amount,
+            msg.min_assets_out,
+            payout,
+            auto_claim,
+        );
+    }
+
+    if payout == Payout::Local {
+        if let Some(response) = pay_from_buffer(
+            storage,
+            &deps.querier,
+            env,
+            config,
+            state,
+            &info.sender,
+            msg.amount,
+            msg.min_assets_out,
+            auto_claim,
+        )? {
+            VIRTUAL_RECEIPTS.save(storage, info.sender.clone(), &(available - msg.amount))?;
+            return Ok(response);
+        }
+    }
+
+    let request_id = next_operation_id(storage)?;
+    let routed = route_and_reclaim(
+        storage,
+        &deps.querier,
+        env,
+        config,
+        &info.sender,
+        request_id,
+        msg.amount,
+        state.assets_for(msg.amount),
+        &payout,
+    )?;
+    if let Some(min_assets_out) = msg.min_assets_out {
+        ensure!(
+            routed.amount >= min_assets_out,
+            ContractError::MinAssetsNotMet {
+                min_assets_out,
+                amount: routed.amount,
+            }
+        );
+    }
+
+    VIRTUAL_RECEIPTS.save(storage, info.sender.clone(), &(available - msg.amount))?;
+    state.virtual_receipt -= msg.amount;
+    state.sync_nav(storage)?;
+    REQUESTS.save(
+        storage,
+        (&info.sender, request_id),
+        &Request {
+            kind: RequestKind::Redeem,
+            status: RequestStatus::Pending,
+            auto_claim,
+            destination: routed.destination,
+            denom: routed.denom,
+            assets: routed.amount,
+            shares: msg.amount,
+            legs: routed.legs,
+            created_at: env.block.time,
+        },
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("method", "withdraw")
+        .add_attribute("withdrawal_id", request_id.to_string())
+        .add_attribute("owner", info.sender.to_string())
+        .add_attribute("shares", msg.amount)
+        .add_submessages(routed.response.messages)
+        .add_attributes(routed.response.attributes))
+}
+
+/// A withdrawal split across the outposts, with a reclaim sent to each of them
+pub struct Routed {
+    pub amount: Uint128,
+    pub legs: u32,
+    /// Outpost of the first leg and the denom all legs are paid out in
+    pub destination: String,
+    pub denom: String,
+    pub response: Response<KujiraMsg>,
+}
+
+/// Splits `value`, redeemed for `shares`, across the outposts that can supply the liquidity,
+/// see `route_withdrawal`. Each leg reclaims its part of the funds on mars.
+#[allow(clippy::too_many_arguments)]
+pub fn route_and_reclaim(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper<KujiraQuery>,
+    env: &Env,
+    config: &Config,
+    owner: &Addr,
+    request_id: u64,
+    shares: Uint128,
+    value: Uint128,
+    payout: &Payout,
+) -> Result<Routed, ContractError> {
+    // Receipts worth less than a unit of the base asset have nothing to reclaim
+    ensure!(!value.is_zero(), ContractError::InvalidAmount {});
+    let chain = match payout {
+        Payout::Host { chain, .. } => Some(chain.as_str()),
+        _ => None,
+    };
+    // Outposts at their limit of operations in flight cannot take a leg
+    let view: &dyn Storage = storage;
+    let legs = route_withdrawal(view, querier, config, value, chain, no_flows, |outpost| {
+        can_accept(view, outpost, OperationKind::Withdrawal)
+    })?;
+    let amounts = legs
+        .iter()
+        .map(|leg| amount_for_usd(querier, config, &leg.outpost.host_denom, leg.value))
+        .collect::<Result<Vec<_>, _>>()?;
+    let amount: Uint128 = amounts.iter().sum();
+    let first = legs.first().ok_or(ContractError::InvalidAmount {})?;
+
+    let mut routed = Routed {
+        amount,
+        legs: legs.len() as u32,
+        destination: first.outpost.mars_red_bank_contract.clone(),
+        denom: first.outpost.base_denom.clone(),
+        response: Response::new()
+            .add_attribute("value", value)
+            .add_attribute("amount", amount),
+    };
+
+    for (leg, amount) in legs.into_iter().zip(amounts) {
+        let id = next_operation_id(storage)?;
+        let destination = leg.outpost.mars_red_bank_contract.clone();
+
+        // Reclaimed value comes out of the lent funds, the rest is already idle in the credit account
+        let mut nav = OUTPOST_NAV
+            .may_load(storage, &destination)?
+            .unwrap_or_default();
+        nav.lent = nav.lent.saturating_sub(leg.reclaimed_value);
+        nav.idle = nav.idle.saturating_sub(leg.value - leg.reclaimed_value);
+        OUTPOST_NAV.save(storage, &destination, &nav)?;
+        let reclaim = amount.multiply_ratio(leg.reclaimed_value, leg.value);
+
+        begin_operation(storage, leg.outpost, OperationKind::Withdrawal)?;
+        PENDING_WITHDRAWALS.save(
+            storage,
+            id,
+            &PendingWithdrawal {
+                owner: owner.clone(),
+                request_id,
+                shares: shares.multiply_ratio(leg.value, value),
+                amount,
+                value: leg.value,
+                reclaim,
+                destination: destination.clone(),
+                payout: payout.clone(),
+                min_assets_out: None,
+                stage: WithdrawStage::Reclaiming,
+                epoch: None,
+                created_at: env.block.time,
+                expires_at: None,
+                retries: 0,
+            },
+        )?;
+
+        routed.response = routed
+            .response
+            .add_message(withdraw_ica_msg(leg.outpost, id, amount, reclaim)?)
+            .add_attribute("leg", format!("{}/{}/{}", id, destination, amount));
+    }
+
+    Ok(routed)
+}
+
+/// Retries the withdrawals waiting in the queue, oldest first, until one of them still cannot be
+/// routed or `limit` of them were retried. The escrowed receipts are burned at the current NAV.
+pub fn try_process_withdrawal_queue(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper<KujiraQuery>,
+    env: &Env,
+    config: &Config,
+    state: &mut State,
+    limit: Option<u32>,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let limit = limit.unwrap_or(DEFAULT_QUEUE_LIMIT).min(MAX_QUEUE_LIMIT);
+    let mut response = Response::new().add_attribute("method", "process_withdrawal_queue");
+
+    let mut retried = 0;
+    while retried < limit {
+        let Some(queued) = WITHDRAWAL_QUEUE.front(storage)? else {
+            break;
+        };
+        let routed = match route_and_reclaim(
+            storage,
+            querier,
+            env,
+            config,
+            &queued.owner,
+            queued.request_id,
+            queued.shares,
+            state.assets_for(queued.shares),
+            &queued.payout,
+        ) {
+            Ok(routed) => routed,
+            // The head of the queue blocks the rest until the outposts have liquidity again,
+            // or can take another operation
+            Err(
+                ContractError::InsufficientLiquidity { .. }
+                | ContractError::OutpostBusy { .. }
+                | ContractError::ConflictingOperation { .. },
+            ) => break,
+            // Receipts that are no longer worth anything to reclaim go back to their owner
+            Err(ContractError::InvalidAmount {}) => {
+                WITHDRAWAL_QUEUE.pop_front(storage)?;
+                VIRTUAL_RECEIPTS.update(storage, queued.owner.clone(), |existing| {
+                    Ok::<_, ContractError>(existing.unwrap_or_default() + queued.shares)
+                })?;
+                return_redeem_leg(
+                    storage,
+                    &queued.owner,
+                    queued.request_id,
+                    queued.shares,
+                    Uint128::zero(),
+                )?;
+                response = response
+                    .add_attribute("returned", queued.request_id.to_string())
+                    .add_attribute("shares", queued.shares);
+                retried += 1;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        WITHDRAWAL_QUEUE.pop_front(storage)?;
+        state.virtual_receipt -= queued.shares;
+        state.sync_nav(storage)?;
+        add_redeem_legs(
+            storage,
+            &queued.owner,
+            queued.request_id,
+            routed.amount,
+            routed.legs,
+        )?;
+
+        response = response
+            .add_submessages(routed.response.messages)
+            .add_attribute("withdrawal_id", queued.request_id.to_string())
+            .add_attribute("shares", queued.shares)
+            .add_attribute("amount", routed.amount);
+        retried += 1;
+    }
+
+    Ok(response
+        .add_attribute("retried", retried.to_string())
+        .add_attribute("remaining", WITHDRAWAL_QUEUE.len(storage)?.to_string()))
+}
+
+/// Escrows the receipts of a withdrawal until its epoch is processed.
+/// The receipts stay part of the supply, so the withdrawal is priced at the NAV the epoch ends with.
+fn queue_withdrawal(
+    storage: &mut dyn Storage,
+    env: &Env,
+    info: &MessageInfo,
+    shares: Uint128,
+    min_assets_out: Option<Uint128>,
+    payout: Payout,
+    auto_claim: bool,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    VIRTUAL_RECEIPTS.update(storage, info.sender.clone(), |existing| {
+        Ok::<_, ContractError>(existing.unwrap_or_default() - shares)
+    })?;
+
+    let epoch = EPOCH.load(storage)?.open_id();
+    let id = next_operation_id(storage)?;
+    REQUESTS.save(
+        storage,
+        (&info.sender, id),
+        &Request {
+            kind: RequestKind::Redeem,
+            status: RequestStatus::Pending,
+            auto_claim,
+            destination: String::new(),
+            denom: String::new(),
+            assets: Uint128::zero(),
+            shares,
+            legs: 0,
+            created_at: env.block.time,
+        },
+    )?;
+    PENDING_WITHDRAWALS.save(
+        storage,
+        id,
+        &PendingWithdrawal {
+            owner: info.sender.clone(),
+            request_id: id,
+            shares,
+            amount: Uint128::zero(),
+            value: Uint128::zero(),
+            reclaim: Uint128::zero(),
+            destination: String::new(),
+            payout,
+            min_assets_out,
+            stage: WithdrawStage::Queued,
+            epoch: Some(epoch),
+            created_at: env.block.time,
+            expires_at: None,
+            retries: 0,
+        },
+    )?;
+    EPOCH_WITHDRAWALS.save(storage, (epoch, id), &Empty {})?;
+
+    Ok(Response::new()
+        .add_attribute("method", "withdraw")
+        .add_attribute("withdrawal_id", id.to_string())
+        .add_attribute("epoch", epoch.to_string())
+        .add_attribute("owner", info.sender.to_string())
+        .add_attribute("shares", shares))
+}
+
+/// Decides where a withdrawal is paid out. Without a remote chain the owner is paid on Kujira.
+fn resolve_payout(
+    config: &Config,
+    recipient: Option<String>,
+    remote_chain: Option<String>,
+) -> Result<Payout, ContractError> {
+    let (recipient, chain) = match (recipient, remote_chain) {
+        (None, None) => return Ok(Payout::Local),
+        (Some(recipient), Some(chain)) => (recipient, chain),
+        _ => return Err(ContractError::InvalidRecipient {}),
+    };
+
+    if config
+        .active_outposts()
+        .any(|outpost| outpost.chain == chain)
+    {
+        return Ok(Payout::Host { chain, recipient });
+    }
+
+    ensure!(
+        config.find_remote_chain(&chain).is_some(),
+        ContractError::UnknownChain { chain }
+    );
+    Ok(Payout::Remote { chain, recipient })
+}
+
+/// Builds the ICA transaction that reclaims the funds on mars and withdraws them to the ICA.
+/// Both amounts are exact, a market that cannot supply the reclaim fails the whole transaction.
+fn withdraw_ica_msg(
+    outpost: &Outpost,
+    id: u64,
+    amount: Uint128,
+    reclaim: Uint128,
+) -> Result<WasmMsg, ContractError> {
+    let mut actions = vec![];
+    if !reclaim.is_zero() {
+        actions.push(Action::Reclaim(ActionCoin::from(&Coin {
+            denom: outpost.host_denom.clone(),
+            amount: reclaim,
+        })));
+    }
+    actions.push(Action::Withdraw(ActionCoin::from(&Coin {
+        denom: outpost.host_denom.clone(),
+        amount,
+    })));
+
+    let update_credit_msg = wasm_execute(
+        outpost.mars_red_bank_contract.clone(),
+        &CreditManagerExecuteMsg::UpdateCreditAccount {
+            account_id: outpost.account_id.clone(),
+            account_kind: None,
+            actions,
+        },
+        vec![],
+    )?
+    .into();
+
+    execute_ica(
+        outpost.cw_ica_controller_contract.clone(),
+        Some(format!("withdraw/{}", id)),
+        vec![update_credit_msg],
+        vec![],
+        outpost.ica_timeout(IcaOperation::Withdraw),
+    )
+}
+
+/// Builds the ICA transaction that pays out the reclaimed funds. The ICA either sends them to the
+/// recipient on the outpost chain, or transfers them back to this contract with a wasm hook paying
+/// out the withdrawal, timing out at `expires_at`.
+fn payout_ica_msg(
+    env: &Env,
+    outpost: &Outpost,
+    id: u64,
+    coin: Coin,
+    payout: &Payout,
+    expires_at: Timestamp,
+) -> Result<WasmMsg, ContractError> {
+    let payout_msg: CosmosMsg = match payout {
+        Payout::Host { recipient, .. } => BankMsg::Send {
+            to_address: recipient.clone(),
+            amount: vec![coin],
+        }
+        .into(),
+        Payout::Local | Payout::Remote { .. } | Payout::Buffer => ica_transfer_msg(
+            outpost,
+            &coin,
+            env.contract.address.to_string(),
+            expires_at,
+            wasm_hook_memo(env, &ExecuteMsg::ReceiveWithdrawal { id })?,
+        )?,
+    };
+
+    execute_ica(
+        outpost.cw_ica_controller_contract.clone(),
+        Some(format!("payout/{}", id)),
+        vec![payout_msg],
+        vec![],
+        outpost.ica_timeout(IcaOperation::Payout),
+    )
+}
+
+/// Pays out a withdrawal once its reclaim was acknowledged. The reclaim is exact, so the
+/// acknowledgement means the whole amount is in the ICA.
+pub fn withdraw_callback(
+    storage: &mut dyn Storage,
+    env: &Env,
+    config: &Config,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let mut withdrawal = PENDING_WITHDRAWALS.load(storage, id)?;
+    let outpost = config
+        .find_destination_outpost(&withdrawal.destination)
+        .ok_or(ContractError::DestinationNotFound {
+            destination: withdrawal.destination.clone(),
+        })?;
+
+    let expires_at = env.block.time.plus_seconds(outpost.transfer_timeout);
+    if !matches!(withdrawal.payout, Payout::Host { .. }) {
+        withdrawal.expires_at = Some(expires_at);
+        PENDING_WITHDRAWALS.save(storage, id, &withdrawal)?;
+    }
+    let coin = Coin {
+        denom: outpost.host_denom.clone(),
+        amount: withdrawal.amount,
+    };
+    Ok(Response::new()
+        .add_message(payout_ica_msg(
+            env,
+            outpost,
+            id,
+            coin,
+            &withdrawal.payout,
+            expires_at,
+        )?)
+        .add_attribute("method", "withdraw_callback")
+        .add_attribute("withdrawal_id", id.to_string())
+        .add_attribute("amount", withdrawal.amount))
+}
+
+/// Completes payouts on the outpost chain and marks all others as returning
+/// once the payout transaction was acknowledged.
+pub fn payout_callback(
+    storage: &mut dyn Storage,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let mut withdrawal = PENDING_WITHDRAWALS.load(storage, id)?;
+    if let Payout::Host { .. } = withdrawal.payout {
+        PENDING_WITHDRAWALS.remove(storage, id);
+        end_operation(storage, &withdrawal.destination, OperationKind::Withdrawal)?;
+        settle_redeem_leg(
+            storage,
+            &withdrawal.owner,
+            withdrawal.request_id,
+            RequestStatus::Claimed,
+        )?;
+    } else if withdrawal.stage == WithdrawStage::Reclaiming {
+        withdrawal.stage = WithdrawStage::Returning;
+        PENDING_WITHDRAWALS.save(storage, id, &withdrawal)?;
+    }
+
+    Ok(Response::new()
+        .add_attribute("method", "payout_callback")
+        .add_attribute("withdrawal_id", id.to_string()))
+}
+
+/// Sends the payout of a withdrawal that did not arrive on Kujira before its transfer timed out
+/// again. Once the timeout passed the funds are either paid out or back on the ICA. After
+/// `MAX_PAYOUT_RETRIES` attempts the funds are lent again and the owner gets the receipts back
+/// instead, see `expire_payout`. A leg of an epoch is paid out on its own from then on.
+/// Callable by the owner of the withdrawal, the contract owner or the keeper.
+pub fn try_retry_payout(
+    storage: &mut dyn Storage,
+    env: &Env,
+    info: &MessageInfo,
+    config: &Config,
+    state: &mut State,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let mut withdrawal = PENDING_WITHDRAWALS
+        .may_load(storage, id)?
+        .ok_or(ContractError::WithdrawalNotReturning { id })?;
+    ensure!(
+        info.sender == withdrawal.owner
+            || info.sender == config.owner
+            || config.keeper.as_ref() == Some(&info.sender),
+        ContractError::Unauthorized {}
+    );
+    let expires_at = match withdrawal.expires_at {
+        Some(expires_at) if withdrawal.stage == WithdrawStage::Returning => expires_at,
+        _ => return Err(ContractError::WithdrawalNotReturning { id }),
+    };
+    ensure!(
+        expires_at <= env.block.time,
+        ContractError::PayoutInFlight { id, expires_at }
+    );
+
+    if let Some(epoch) = withdrawal.epoch {
+        EPOCH_WITHDRAWALS.remove(storage, (epoch, id));
+    }
+    if withdrawal.retries >= MAX_PAYOUT_RETRIES {
+        return expire_payout(storage, config, state, id);
+    }
+
+    let outpost = config
+        .find_destination_outpost(&withdrawal.destination)
+        .ok_or(ContractError::DestinationNotFound {
+            destination: withdrawal.destination.clone(),
+        })?;
+    let expires_at = env.block.time.plus_seconds(outpost.transfer_timeout);
+    withdrawal.expires_at = Some(expires_at);
+    withdrawal.retries += 1;
+    PENDING_WITHDRAWALS.save(storage, id, &withdrawal)?;
+
+    let coin = Coin {
+        denom: outpost.host_denom.clone(),
+        amount: withdrawal.amount,
+    };
+    Ok(Response::new()
+        .add_message(payout_ica_msg(
+            env,
+            outpost,
+            id,
+            coin,
+            &withdrawal.payout,
+            expires_at,
+        )?)
+        .add_attribute("method", "retry_payout")
+        .add_attribute("withdrawal_id", id.to_string())
+        .add_attribute("retries", withdrawal.retries.to_string()))
+}
+
+/// Gives the receipts of a withdrawal back to its owner, or drops the part of a buffer top-up it
+/// was bringing in
+pub fn return_receipts(
+    storage: &mut dyn Storage,
+    config: &Config,
+    state: &mut State,
+    withdrawal: &PendingWithdrawal,
+) -> Result<(), ContractError> {
+    if withdrawal.payout == Payout::Buffer {
+        let outpost = config
+            .find_destination_outpost(&withdrawal.destination)
+            .ok_or(ContractError::DestinationNotFound {
+                destination: withdrawal.destination.clone(),
+            })?;
+        let mut buffer = BUFFERS.load(storage, &outpost.base_denom)?;
+        buffer.incoming = buffer.incoming.saturating_sub(withdrawal.value);
+        BUFFERS.save(storage, &outpost.base_denom, &buffer)?;
+        return Ok(());
+    }
+
+    VIRTUAL_RECEIPTS.update(storage, withdrawal.owner.clone(), |existing| {
+        Ok::<_, ContractError>(existing.unwrap_or_default() + withdrawal.shares)
+    })?;
+    state.virtual_receipt += withdrawal.shares;
+    return_redeem_leg(
+        storage,
+        &withdrawal.owner,
+        withdrawal.request_id,
+        withdrawal.shares,
+        withdrawal.amount,
+    )?;
+    Ok(())
+}
+
+/// Rolls back a withdrawal whose reclaim was answered with an error acknowledgement.
+/// Nothing left the outpost, so its value counts as lent there again and the receipts go back
+/// to the owner.
+pub fn rollback_withdrawal(
+    storage: &mut dyn Storage,
+    config: &Config,
+    state: &mut State,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let Some(withdrawal) = PENDING_WITHDRAWALS.may_load(storage, id)? else {
+        return Ok(Response::new());
+    };
+    PENDING_WITHDRAWALS.remove(storage, id);
+    end_operation(storage, &withdrawal.destination, OperationKind::Withdrawal)?;
+
+    // The reclaimed part is still lent on the outpost, the rest is still idle there
+    let lent = withdrawal
+        .value
+        .multiply_ratio(withdrawal.reclaim, withdrawal.amount);
+    let mut nav = OUTPOST_NAV
+        .may_load(storage, &withdrawal.destination)?
+        .unwrap_or_default();
+    nav.lent += lent;
+    nav.idle += withdrawal.value - lent;
+    OUTPOST_NAV.save(storage, &withdrawal.destination, &nav)?;
+    return_receipts(storage, config, state, &withdrawal)?;
+    state.sync_nav(storage)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "rollback_withdrawal")
+        .add_attribute("withdrawal_id", id.to_string())
+        .add_attribute("owner", withdrawal.owner)
+        .add_attribute("shares", withdrawal.shares))
+}
+
+/// Handles a reclaim answered with an error acknowledgement. The red bank market of the outpost is
+/// queried for the liquidity it can supply, so the reclaim can be sized to it. Without a red bank
+/// to query the withdrawal is rolled back.
+pub fn size_withdrawal(
+    storage: &mut dyn Storage,
+    config: &Config,
+    state: &mut State,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let Some(mut withdrawal) = PENDING_WITHDRAWALS.may_load(storage, id)? else {
+        return Ok(Response::new());
+    };
+    let outpost = config
+        .find_destination_outpost(&withdrawal.destination)
+        .ok_or(ContractError::DestinationNotFound {
+            destination: withdrawal.destination.clone(),
+        })?;
+    let Some(query) = market_query(outpost)? else {
+        return rollback_withdrawal(storage, config, state, id);
+    };
+    if withdrawal.reclaim.is_zero() {
+        return rollback_withdrawal(storage, config, state, id);
+    }
+
+    withdrawal.stage = WithdrawStage::Sizing;
+    PENDING_WITHDRAWALS.save(storage, id, &withdrawal)?;
+
+    Ok(Response::new()
+        .add_message(query_ica(
+            outpost.cw_ica_controller_contract.clone(),
+            Some(format!("withdraw_market/{}", id)),
+            vec![query],
+            outpost.ica_timeout(IcaOperation::Query),
+        )?)
+        .add_attribute("method", "size_withdrawal")
+        .add_attribute("withdrawal_id", id.to_string()))
+}
+
+/// Sizes the reclaim of a failed withdrawal to the liquidity its red bank market can supply and
+/// sends it again. The part the market cannot supply is queued until the keeper retries it.
+/// A market with enough liquidity did not cause the failure, so the withdrawal is rolled back.
+/// Every retry reclaims strictly less, so a withdrawal is sized a bounded number of times.
+pub fn withdraw_market_callback(
+    storage: &mut dyn Storage,
+    env: &Env,
+    config: &Config,
+    state: &mut State,
+    id: u64,
+    query_result: Option<IcaQueryResult>,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let Some(mut withdrawal) = PENDING_WITHDRAWALS.may_load(storage, id)? else {
+        return Ok(Response::new());
+    };
+    let outpost = config
+        .find_destination_outpost(&withdrawal.destination)
+        .ok_or(ContractError::DestinationNotFound {
+            destination: withdrawal.destination.clone(),
+        })?;
+    let Some(market) = parse_market(&query_result) else {
+        return rollback_withdrawal(storage, config, state, id);
+    };
+    let liquidity = available_liquidity(&market);
+    if liquidity >= withdrawal.reclaim {
+        return rollback_withdrawal(storage, config, state, id);
+    }
+
+    let unfilled = withdrawal.reclaim - liquidity;
+    let queued_shares = requeue_unfilled(storage, env, state, outpost, &mut withdrawal, unfilled)?;
+    let mut response = Response::new()
+        .add_attribute("method", "withdraw_market_callback")
+        .add_attribute("withdrawal_id", id.to_string())
+        .add_attribute("available_liquidity", liquidity)
+        .add_attribute("unfilled", unfilled)
+        .add_attribute("queued_shares", queued_shares);
+
+    // Nothing can be withdrawn, the queued remainder replaces the withdrawal
+    if withdrawal.amount.is_zero() {
+        PENDING_WITHDRAWALS.remove(storage, id);
+        end_operation(storage, &withdrawal.destination, OperationKind::Withdrawal)?;
+        settle_redeem_leg(
+            storage,
+            &withdrawal.owner,
+            withdrawal.request_id,
+            RequestStatus::Claimed,
+        )?;
+        return Ok(response);
+    }
+
+    withdrawal.reclaim = liquidity;
+    withdrawal.stage = WithdrawStage::Reclaiming;
+    PENDING_WITHDRAWALS.save(storage, id, &withdrawal)?;
+    response = response.add_message(withdraw_ica_msg(
+        outpost,
+        id,
+        withdrawal.amount,
+        withdrawal.reclaim,
+    )?);
+    Ok(response)
+}
+
+/// Takes the part of a withdrawal the lent funds of its outpost cannot supply out of it. Its value
+/// counts as lent on the outpost again. Its receipts go back into the supply and are queued until
+/// the keeper retries them, the part of a buffer top-up is dropped instead. A part worth less than a
+/// receipt is not queued, the receipts of the filled part cover it.
+/// Returns the queued receipts.
+pub fn requeue_unfilled(
+    storage: &mut dyn Storage,
+    env: &Env,
+    state: &mut State,
+    outpost: &Outpost,
+    withdrawal: &mut PendingWithdrawal,
+    unfilled: Uint128,
+) -> Result<Uint128, ContractError> {
+    let shares = withdrawal
+        .shares
+        .multiply_ratio(unfilled, withdrawal.amount);
+    let value = withdrawal.value.multiply_ratio(unfilled, withdrawal.amount);
+
+    let mut nav = OUTPOST_NAV
+        .may_load(storage, &withdrawal.destination)?
+        .unwrap_or_default();
+    nav.lent += value;
+    OUTPOST_NAV.save(storage, &withdrawal.destination, &nav)?;
+
+    let queued_shares = if withdrawal.payout == Payout::Buffer {
+        // Top-ups are not retried, the keeper tops the buffer up again once it runs low
+        let mut buffer = BUFFERS.load(storage, &outpost.base_denom)?;
+        buffer.incoming = buffer.incoming.saturating_sub(value);
+        BUFFERS.save(storage, &outpost.base_denom, &buffer)?;
+        Uint128::zero()
+    } else if shares.is_zero() {
+        drop_redeem_remainder(storage, &withdrawal.owner, withdrawal.request_id, unfilled)?;
+        Uint128::zero()
+    } else {
+        state.virtual_receipt += shares;
+        WITHDRAWAL_QUEUE.push_back(
+            storage,
+            &QueuedWithdrawal {
+                owner: withdrawal.owner.clone(),
+                request_id: withdrawal.request_id,
+                shares,
+                payout: withdrawal.payout.clone(),
+                queued_at: env.block.time,
+            },
+        )?;
+        queue_redeem_remainder(storage, &withdrawal.owner, withdrawal.request_id, unfilled)?;
+        shares
+    };
+    state.sync_nav(storage)?;
+
+    withdrawal.shares -= shares;
+    withdrawal.value -= value;
+    withdrawal.amount -= unfilled;
+    Ok(queued_shares)
+}
+
+/// Lends the reclaimed funds of a withdrawal whose payout failed for good on the outpost again and
+/// gives the receipts back to the owner. The value counts as pending until the lend is acknowledged.
+pub fn expire_payout(
+    storage: &mut dyn Storage,
+    config: &Config,
+    state: &mut State,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let Some(mut withdrawal) = PENDING_WITHDRAWALS.may_load(storage, id)? else {
+        return Ok(Response::new());
+    };
+    let outpost = config
+        .find_destination_outpost(&withdrawal.destination)
+        .ok_or(ContractError::DestinationNotFound {
+            destination: withdrawal.destination.clone(),
+        })?;
+
+    let mut nav = OUTPOST_NAV
+        .may_load(storage, &withdrawal.destination)?
+        .unwrap_or_default();
+    nav.pending += withdrawal.value;
+    OUTPOST_NAV.save(storage, &withdrawal.destination, &nav)?;
+    return_receipts(storage, config, state, &withdrawal)?;
+    state.sync_nav(storage)?;
+
+    withdrawal.stage = WithdrawStage::Relending;
+    PENDING_WITHDRAWALS.save(storage, id, &withdrawal)?;
+
+    let coin = Coin {
+        denom: outpost.host_denom.clone(),
+        amount: withdrawal.amount,
+    };
+    Ok(Response::new()
+        .add_message(lend_ica_msg(outpost, coin, format!("relend/{}", id))?)
+        .add_attribute("method", "expire_payout")
+        .add_attribute("withdrawal_id", id.to_string())
+        .add_attribute("owner", withdrawal.owner)
+        .add_attribute("shares", withdrawal.shares))
+}
+
+/// Moves the value of an expired payout from pending to lent once it is lent again
+pub fn relend_callback(
+    storage: &mut dyn Storage,
+    state: &mut State,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let withdrawal = PENDING_WITHDRAWALS.load(storage, id)?;
+    PENDING_WITHDRAWALS.remove(storage, id);
+    end_operation(storage, &withdrawal.destination, OperationKind::Withdrawal)?;
+
+    let mut nav = OUTPOST_NAV
+        .may_load(storage, &withdrawal.destination)?
+        .unwrap_or_default();
+    nav.pending = nav.pending.saturating_sub(withdrawal.value);
+    nav.lent += withdrawal.value;
+    OUTPOST_NAV.save(storage, &withdrawal.destination, &nav)?;
+    state.sync_nav(storage)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "relend_callback")
+        .add_attribute("withdrawal_id", id.to_string()))
+}
+
+/// Keeps the funds of an expired payout whose lend failed for good idle in the ICA of its outpost
+pub fn expire_relend(
+    storage: &mut dyn Storage,
+    state: &mut State,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    let Some(withdrawal) = PENDING_WITHDRAWALS.may_load(storage, id)? else {
+        return Ok(Response::new());
+    };
+    PENDING_WITHDRAWALS.remove(storage, id);
+    end_operation(storage, &withdrawal.destination, OperationKind::Withdrawal)?;
+
+    let mut nav = OUTPOST_NAV
+        .may_load(storage, &withdrawal.destination)?
+        .unwrap_or_default();
+    nav.pending = nav.pending.saturating_sub(withdrawal.value);
+    nav.idle += withdrawal.value;
+    OUTPOST_NAV.save(storage, &withdrawal.destination, &nav)?;
+    state.sync_nav(storage)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "expire_relend")
+        .add_attribute("withdrawal_id", id.to_string()))
+}
+
+pub fn query_withdrawal_queue(
+    deps: Deps<KujiraQuery>,
+    start: Option<u32>,
+    limit: Option<u32>,
+) -> Result<WithdrawalQueueResponse, ContractError> {
+    let limit = limit.unwrap_or(DEFAULT_QUEUE_LIMIT).min(MAX_QUEUE_LIMIT) as usize;
+    let withdrawals = WITHDRAWAL_QUEUE
+        .iter(deps.storage)?
+        .skip(start.unwrap_or_default() as usize)
+        .take(limit)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(WithdrawalQueueResponse {
+        length: WITHDRAWAL_QUEUE.len(deps.storage)?,
+        withdrawals,
+    })
+}
+
+/// Pays out the withdrawal with the funds that arrived from the outpost,
+/// either to the owner on Kujira or by IBC transfer to the recipient on a remote chain.
+/// Redeem requests hold the funds on Kujira until they are claimed.
+/// Only the transfer from the ICA of the outpost carrying the withdrawn amount is accepted.
+pub fn receive_withdrawal(
+    storage: &mut dyn Storage,
+    api: &dyn Api,
+    env: &Env,
+    info: &MessageInfo,
+    config: &Config,
+    id: u64,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    // The funds can arrive before the acknowledgement of the ICA transaction
+    let withdrawal = PENDING_WITHDRAWALS.load(storage, id)?;
+    ensure!(
+        withdrawal.stage != WithdrawStage::PayingOut,
+        ContractError::WithdrawalPaidOut { id }
+    );
+
+    let outpost = config
+        .find_destination_outpost(&withdrawal.destination)
+        .ok_or(ContractError::DestinationNotFound {
+            destination: withdrawal.destination.clone(),
+        })?;
+
+    ensure_outpost_hook(api, info, outpost, withdrawal.amount)?;
+
+    let coin = Coin {
+        denom: outpost.base_denom.clone(),
+        amount: withdrawal.amount,
+    };
+    let response = Response::new()
+        .add_attribute("method", "receive_withdrawal")
+        .add_attribute("withdrawal_id", id.to_string())
+        .add_attribute("owner", withdrawal.owner.to_string())
+        .add_attribute("amount", withdrawal.amount);
+
+    let payout = pay_withdrawal(storage, env, config, id, withdrawal, coin)?;
+    Ok(response
+        .add_submessages(payout.messages)
+        .add_attributes(payout.attributes))
+}
+
+/// Pays out `coin` to the owner of a withdrawal on Kujira or to its recipient on a remote chain.
+/// Redeem requests keep the funds until the owner claims them.
+/// The funds are back on Kujira, so the withdrawal no longer holds a slot on its outpost.
+pub fn pay_withdrawal(
+    storage: &mut dyn Storage,
+    env: &Env,
+    config: &Config,
+    id: u64,
+    mut withdrawal: PendingWithdrawal,
+    coin: Coin,
+) -> Result<Response<KujiraMsg>, ContractError> {
+    end_operation(storage, &withdrawal.destination, OperationKind::Withdrawal)?;
+    match withdrawal.payout.clone() {
+        Payout::Remote { chain, recipient } => {
+            let remote_chain = config
+                .find_remote_chain(&chain)
+                .ok_or(ContractError::UnknownChain { chain })?;
+
+            // Kept until the transfer lifecycle reports the payout, refunded to the owner on failure
+            withdrawal.stage = WithdrawStage::PayingOut;
+            PENDING_WITHDRAWALS.save(storage, id, &withdrawal)?;
+
+            let transfer = transfer_to_remote(
+                storage,
+                env,
+                remote_chain,
+                recipient.clone(),
+                coin,
+                id,
+                withdrawal.owner,
+            )?;
+
+            Ok(Response::new()
+                .add_submessage(transfer)
+                .add_attribute("recipient", recipient))
+        }
+        Payout::Buffer => {
+            PENDING_WITHDRAWALS.remove(storage, id);
+            receive_top_up(storage, &withdrawal, &coin)?;
+            Ok(Response::new().add_attribute("status", "buffered"))
+        }
+        _ => {
+            PENDING_WITHDRAWALS.remove(storage, id);
+
+            // Redeem requests keep the funds until the owner claims them
+            if !settle_redeem_leg(
+                storage,
+                &withdrawal.owner,
+                withdrawal.request_id,
+                RequestStatus::Claimed,
+            )? {
+                return Ok(Response::new().add_attribute("status", "claimable"));
+            }
+
+            let payout = BankMsg::Send {
+                to_address: withdrawal.owner.to_string(),
+                amount: vec![coin],
+            };
+
+            Ok(Response::new().add_message(payout))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::{message_info, mock_env, MockStorage};
+    use cosmwasm_std::Decimal;
+
+    use super::*;
+    use crate::testing::{
+        mock_config, mock_oracle, mock_outpost, mock_state, mock_withdrawal, save_nav,
+    };
+
+    /// A withdrawal of 200 for 100 receipts, 150 reclaimed from the lent funds of `a` and 50 idle.
+    /// Before it the outpost held 100 idle and 900 lent against 500 receipts.
+    fn setup(storage: &mut dyn Storage) -> State {
+        save_nav(storage, "a", 50, 750);
+        let withdrawal = PendingWithdrawal {
+            reclaim: Uint128::new(150),
+            ..mock_withdrawal("a", 100, 200)
+        };
+        PENDING_WITHDRAWALS.save(storage, 1, &withdrawal).unwrap();
+        mock_state(storage, 400)
+    }
+
+    #[test]
+    fn test_size_withdrawal_without_red_bank_rolls_back() {
+        let mut storage = MockStorage::new();
+        let config = mock_config(vec![mock_outpost("a", "osmosis-1", "uusdc")]);
+        let mut state = setup(&mut storage);
+
+        size_withdrawal(&mut storage, &config, &mut state, 1).unwrap();
+
+        // The reclaimed part is lent again and the rest idle, the receipts are back with the owner
+        assert!(!PENDING_WITHDRAWALS.has(&storage, 1));
+        let nav = OUTPOST_NAV.load(&storage, "a").unwrap();
+        assert_eq!((nav.idle, nav.lent), (Uint128::new(100), Uint128::new(900)));
+        assert_eq!(
+            VIRTUAL_RECEIPTS
+                .load(&storage, Addr::unchecked("bob"))
+                .unwrap(),
+            Uint128::new(100)
+        );
+        assert_eq!(state.virtual_receipt, Uint128::new(500));
+        assert_eq!(state.total_stables, Uint128::new(1_000));
+    }
+
+    #[test]
+    fn test_size_withdrawal_queries_market() {
+        let mut storage = MockStorage::new();
+        let mut outpost = mock_outpost("a", "osmosis-1", "uusdc");
+        outpost.red_bank_contract = Some("red_bank".to_string());
+        let config = mock_config(vec![outpost]);
+        let mut state = setup(&mut storage);
+
+        let response = size_withdrawal(&mut storage, &config, &mut state, 1).unwrap();
+
+        // Nothing changes until the market answered
+        assert_eq!(response.messages.len(), 1);
+        let withdrawal = PENDING_WITHDRAWALS.load(&storage, 1).unwrap();
+        assert_eq!(withdrawal.stage, WithdrawStage::Sizing);
+        assert_eq!(withdrawal.amount, Uint128::new(200));
+        assert_eq!(state.virtual_receipt, Uint128::new(400));
+    }
+
+    #[test]
+    fn test_requeue_unfilled_part() {
+        let mut storage = MockStorage::new();
+        let env = mock_env();
+        let outpost = mock_outpost("a", "osmosis-1", "uusdc");
+        let mut state = setup(&mut storage);
+        let mut withdrawal = PENDING_WITHDRAWALS.load(&storage, 1).unwrap();
+
+        // The market can only supply 100 of the 150 reclaimed
+        let queued = requeue_unfilled(
+            &mut storage,
+            &env,
+            &mut state,
+            &outpost,
+            &mut withdrawal,
+            Uint128::new(50),
+        )
+        .unwrap();
+
+        assert_eq!(queued, Uint128::new(25));
+        assert_eq!(withdrawal.amount, Uint128::new(150));
+        assert_eq!(withdrawal.value, Uint128::new(150));
+        assert_eq!(withdrawal.shares, Uint128::new(75));
+        let nav = OUTPOST_NAV.load(&storage, "a").unwrap();
+        assert_eq!(nav.lent, Uint128::new(800));
+        let remainder = WITHDRAWAL_QUEUE.front(&storage).unwrap().unwrap();
+        assert_eq!(remainder.shares, Uint128::new(25));
+        assert_eq!(remainder.owner, Addr::unchecked("bob"));
+        assert_eq!(state.virtual_receipt, Uint128::new(425));
+    }
+
+    #[test]
+    fn test_requeue_unfilled_folds_dust() {
+        let mut storage = MockStorage::new();
+        let env = mock_env();
+        let outpost = mock_outpost("a", "osmosis-1", "uusdc");
+        let mut state = setup(&mut storage);
+        let mut withdrawal = PENDING_WITHDRAWALS.load(&storage, 1).unwrap();
+
+        // 1 of 200 is worth less than one of the 100 receipts
+        let queued = requeue_unfilled(
+            &mut storage,
+            &env,
+            &mut state,
+            &outpost,
+            &mut withdrawal,
+            Uint128::new(1),
+        )
+        .unwrap();
+
+        assert!(queued.is_zero());
+        assert!(WITHDRAWAL_QUEUE.is_empty(&storage).unwrap());
+        assert_eq!(withdrawal.shares, Uint128::new(100));
+        assert_eq!(withdrawal.amount, Uint128::new(199));
+        assert_eq!(withdrawal.value, Uint128::new(199));
+        let nav = OUTPOST_NAV.load(&storage, "a").unwrap();
+        assert_eq!(nav.lent, Uint128::new(751));
+        assert_eq!(state.virtual_receipt, Uint128::new(400));
+    }
+
+    #[test]
+    fn test_process_withdrawal_queue_returns_dust() {
+        let mut storage = MockStorage::new();
+        let oracle = mock_oracle(Decimal::one());
+        let querier = QuerierWrapper::<KujiraQuery>::new(&oracle);
+        let env = mock_env();
+        let config = mock_config(vec![mock_outpost("a", "osmosis-1", "uusdc")]);
+        // A receipt is worth 0.01, one of them redeems for nothing
+        save_nav(&mut storage, "a", 0, 10);
+        let mut state = mock_state(&storage, 1_000);
+        WITHDRAWAL_QUEUE
+            .push_back(
+                &mut storage,
+                &QueuedWithdrawal {
+                    owner: Addr::unchecked("bob"),
+                    request_id: 1,
+                    shares: Uint128::new(1),
+                    payout: Payout::Local,
+                    queued_at: env.block.time,
+                },
+            )
+            .unwrap();
+
+        let response =
+            try_process_withdrawal_queue(&mut storage, &querier, &env, &config, &mut state, None)
+                .unwrap();
+
+        // The head no longer blocks the queue, its receipts are back with the owner
+        assert!(response.messages.is_empty());
+        assert!(WITHDRAWAL_QUEUE.is_empty(&storage).unwrap());
+        assert_eq!(
+            VIRTUAL_RECEIPTS
+                .load(&storage, Addr::unchecked("bob"))
+                .unwrap(),
+            Uint128::new(1)
+        );
+        assert_eq!(state.virtual_receipt, Uint128::new(1_000));
+    }
+
+    #[test]
+    fn test_retry_payout_resends_then_relends() {
+        let mut storage = MockStorage::new();
+        let mut env = mock_env();
+        let config = mock_config(vec![mock_outpost("a", "osmosis-1", "uusdc")]);
+        let mut state = setup(&mut storage);
+        let expires_at = env.block.time.plus_seconds(600);
+        let withdrawal = PendingWithdrawal {
+            stage: WithdrawStage::Returning,
+            expires_at: Some(expires_at),
+            ..PENDING_WITHDRAWALS.load(&storage, 1).unwrap()
+        };
+        PENDING_WITHDRAWALS
+            .save(&mut storage, 1, &withdrawal)
+            .unwrap();
+        let info = message_info(&Addr::unchecked("keeper"), &[]);
+
+        let err = try_retry_payout(&mut storage, &env, &info, &config, &mut state, 1).unwrap_err();
+        assert!(matches!(err, ContractError::PayoutInFlight { id: 1, .. }));
+        let stranger = message_info(&Addr::unchecked("alice"), &[]);
+        env.block.time = expires_at;
+        let err =
+            try_retry_payout(&mut storage, &env, &stranger, &config, &mut state, 1).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        // The transfer is sent again until the retries run out
+        for retries in 1..=MAX_PAYOUT_RETRIES {
+            let response =
+                try_retry_payout(&mut storage, &env, &info, &config, &mut state, 1).unwrap();
+            assert_eq!(response.messages.len(), 1);
+            let withdrawal = PENDING_WITHDRAWALS.load(&storage, 1).unwrap();
+            assert_eq!(withdrawal.retries, retries);
+            env.block.time = withdrawal.expires_at.unwrap();
+        }
 
-    // Check the state where to withdraw the money from -> higher utilization outcome
-    unimplemented!()
+        // Then the funds are lent again and the receipts are back with the owner
+        let response = try_retry_payout(&mut storage, &env, &info, &config, &mut state, 1).unwrap();
+        assert_eq!(response.messages.len(), 1);
+        let withdrawal = PENDING_WITHDRAWALS.load(&storage, 1).unwrap();
+        assert_eq!(withdrawal.stage, WithdrawStage::Relending);
+        assert_eq!(
+            OUTPOST_NAV.load(&storage, "a").unwrap().pending,
+            Uint128::new(200)
+        );
+        assert_eq!(
+            VIRTUAL_RECEIPTS
+                .load(&storage, Addr::unchecked("bob"))
+                .unwrap(),
+            Uint128::new(100)
+        );
+        let err = try_retry_payout(&mut storage, &env, &info, &config, &mut state, 1).unwrap_err();
+        assert!(matches!(
+            err,
+            ContractError::WithdrawalNotReturning { id: 1 }
+        ));
+    }
 }
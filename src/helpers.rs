@@ -1,9 +1,24 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use cosmwasm_std::{to_json_binary, Addr, CosmosMsg, StdResult, WasmMsg};
+use cosmwasm_std::{
+    ensure, to_json_binary, Addr, Api, CanonicalAddr, CosmosMsg, Deps, MessageInfo, QuerierWrapper,
+    StdResult, Storage, Timestamp, Uint128, WasmMsg,
+};
+use cw_ica_controller::types::query_msg::IcaQueryResult;
+use cw_utils::must_pay;
+use kujira::KujiraQuery;
+use mars_types::credit_manager::Positions;
+use sha2::{Digest, Sha256};
 
-use crate::{msg::ExecuteMsg, state::State, ContractError};
+use crate::{
+    config::Config,
+    handler::ica::{available_liquidity, parse_market},
+    msg::{ExecuteMsg, Outpost},
+    oracle::usd_value,
+    state::{NavRefresh, OutpostMarket, State, IN_FLIGHT, OUTPOST_MARKETS, OUTPOST_NAV},
+    ContractError,
+};
 
 /// CwTemplateContract is a wrapper around Addr that provides a lot of helpers
 /// for working with this.
@@ -26,9 +41,243 @@ impl CwTemplateContract {
     }
 }
 
-/// Processes the result of a the ibc queries to get the current stable amt on all outposts
-pub fn callback_update_stable_amt(_state: &mut State) -> Result<(), ContractError> {
-    // get the stable amounts for all outposts
+/// Processes the result of a the ibc queries to get the current stable amt on all outposts.
+/// Applies all positions gathered by the refresh at once and recomputes the total and redemption rate.
+/// Outposts with operations in flight keep their NAV, see below.
+pub fn callback_update_stable_amt(
+    storage: &mut dyn Storage,
+    state: &mut State,
+    refresh: &mut NavRefresh,
+) -> Result<(), ContractError> {
+    for position in refresh.positions.drain(..) {
+        let mut nav = OUTPOST_NAV
+            .may_load(storage, &position.destination)?
+            .unwrap_or_default();
+        // Operations in flight adjust the NAV of their outpost as they go, a position the query saw
+        // before they completed would undo or double count those adjustments. The NAV is kept and
+        // only dated to the query.
+        if IN_FLIGHT.has(storage, &position.destination) {
+            nav.last_updated = position.time;
+            nav.last_updated_height = position.height;
+        } else {
+            nav.record_positions(position.idle, position.lent, position.time, position.height);
+        }
+        OUTPOST_NAV.save(storage, &position.destination, &nav)?;
+    }
+    refresh.awaiting.clear();
+
+    state.sync_nav(storage)?;
+    Ok(())
+}
+
+/// Splits the USD value of a mars credit account into idle deposits and lent funds.
+/// Every coin is priced through the oracle symbol of its whitelisted denom.
+pub fn positions_value(
+    querier: &QuerierWrapper<KujiraQuery>,
+    config: &Config,
+    positions: &Positions,
+) -> Result<(Uint128, Uint128), ContractError> {
+    let mut idle = Uint128::zero();
+    for coin in &positions.deposits {
+        idle += usd_value(querier, config, coin)?;
+    }
+    let mut lent = Uint128::zero();
+    for coin in &positions.lends {
+        lent += usd_value(querier, config, coin)?;
+    }
+    Ok((idle, lent))
+}
+
+/// Caches the red bank market an outpost answered to a market query
+pub fn record_market(
+    storage: &mut dyn Storage,
+    destination: &str,
+    query_result: &Option<IcaQueryResult>,
+    now: Timestamp,
+) -> StdResult<()> {
+    if let Some(market) = parse_market(query_result) {
+        let market = OutpostMarket {
+            available_liquidity: available_liquidity(&market),
+            liquidity_rate: market.market.liquidity_rate,
+            updated_at: now,
+        };
+        OUTPOST_MARKETS.save(storage, destination, &market)?;
+    }
+    Ok(())
+}
+
+/// Ensures that no outpost holding funds has a position older than its configured max nav age.
+/// Outposts without a recorded position have never received funds and are skipped.
+pub fn ensure_fresh_nav(
+    storage: &dyn Storage,
+    config: &Config,
+    now: Timestamp,
+) -> Result<(), ContractError> {
+    for outpost in &config.outposts {
+        let Some(max_age) = outpost.max_nav_age else {
+            continue;
+        };
+        let Some(nav) = OUTPOST_NAV.may_load(storage, &outpost.mars_red_bank_contract)? else {
+            continue;
+        };
+
+        let age = nav.age(now);
+        if age > max_age {
+            return Err(ContractError::StaleNav {
+                destination: outpost.mars_red_bank_contract.clone(),
+                age,
+                max_age,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Refuses a deposit or withdrawal worth less than the configured minimum request value
+pub fn ensure_min_request(config: &Config, value: Uint128) -> Result<(), ContractError> {
+    if let Some(min_request_value) = config.min_request_value {
+        ensure!(
+            value >= min_request_value,
+            ContractError::RequestTooSmall {
+                value,
+                min_request_value,
+            }
+        );
+    }
+    Ok(())
+}
 
-    unimplemented!()
+/// Resolves the owner of the receipts of a deposit.
+/// Deposits arriving through an ibc-hooks wasm memo are executed by an intermediate account derived
+/// from the channel and the remote sender. Nobody controls that account on Kujira, so such deposits
+/// must name a beneficiary.
+pub fn deposit_owner(
+    deps: Deps<KujiraQuery>,
+    sender: &Addr,
+    beneficiary: Option<String>,
+) -> Result<Addr, ContractError> {
+    if let Some(beneficiary) = beneficiary {
+        return Ok(deps.api.addr_validate(&beneficiary)?);
+    }
+
+    ensure!(
+        !is_hook_intermediary(deps, sender)?,
+        ContractError::MissingBeneficiary {}
+    );
+    Ok(sender.clone())
+}
+
+/// Intermediate accounts of ibc-hooks are 32 byte module addresses without a contract behind them.
+fn is_hook_intermediary(deps: Deps<KujiraQuery>, sender: &Addr) -> StdResult<bool> {
+    let canonical = deps.api.addr_canonicalize(sender.as_str())?;
+    Ok(canonical.len() == 32 && deps.querier.query_wasm_contract_info(sender).is_err())
+}
+
+/// Account ibc-hooks executes the wasm memo of a transfer with, derived from the Kujira channel
+/// the transfer arrived on and its sender on the other chain
+pub fn hook_intermediary(api: &dyn Api, channel: &str, sender: &str) -> StdResult<Addr> {
+    let prefix = Sha256::digest(b"ibc-wasm-hook-intermediary");
+    let hash = Sha256::new()
+        .chain_update(prefix)
+        .chain_update(format!("{}/{}", channel, sender))
+        .finalize();
+    api.addr_humanize(&CanonicalAddr::from(hash.as_slice()))
+}
+
+/// Checks that a wasm hook was executed by a transfer from the ICA of the outpost carrying exactly
+/// `owed` of its base denom. Anyone else could settle an operation of the outpost with their own
+/// funds ahead of the ICA, whose transfer would then bounce.
+pub fn ensure_outpost_hook(
+    api: &dyn Api,
+    info: &MessageInfo,
+    outpost: &Outpost,
+    owed: Uint128,
+) -> Result<(), ContractError> {
+    let ica_address = outpost
+        .ica_address
+        .as_ref()
+        .ok_or(ContractError::NoIcaAddress {
+            destination: outpost.mars_red_bank_contract.clone(),
+        })?;
+    ensure!(
+        info.sender == hook_intermediary(api, &outpost.transfer_channel, ica_address)?,
+        ContractError::Unauthorized {}
+    );
+
+    let received = must_pay(info, &outpost.base_denom)?;
+    ensure!(
+        received == owed,
+        ContractError::UnexpectedPayment {
+            expected: owed,
+            received,
+        }
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::{MockApi, MockStorage};
+
+    use super::*;
+    use crate::handler::in_flight::{track_operation, OperationKind};
+    use crate::state::OutpostPosition;
+    use crate::testing::{mock_state, save_nav};
+
+    #[test]
+    fn test_hook_intermediary() {
+        let api = MockApi::default();
+        let sender = hook_intermediary(&api, "channel-3", "osmo1ica").unwrap();
+        assert_eq!(
+            sender.as_str(),
+            "cosmwasm1ffauumesc5qrj6aqrwg08zr8cuhqlh3f95at3tzn2lel4c2surcq4788tq"
+        );
+
+        // The channel the transfer arrived on is part of the derivation
+        let other = hook_intermediary(&api, "channel-4", "osmo1ica").unwrap();
+        assert_ne!(sender, other);
+    }
+
+    #[test]
+    fn test_update_stable_amt_keeps_nav_in_flight() {
+        let mut storage = MockStorage::new();
+        save_nav(&mut storage, "a", 100, 1_000);
+        save_nav(&mut storage, "b", 100, 1_000);
+        let mut state = mock_state(&storage, 1_000);
+        // The epoch lending 500 on a is in flight, its NAV already holds the deposit as pending
+        let mut nav = OUTPOST_NAV.load(&storage, "a").unwrap();
+        nav.pending = Uint128::new(500);
+        OUTPOST_NAV.save(&mut storage, "a", &nav).unwrap();
+        track_operation(&mut storage, "a", OperationKind::Deposit).unwrap();
+
+        let position = |destination: &str| OutpostPosition {
+            destination: destination.to_string(),
+            idle: Uint128::zero(),
+            lent: Uint128::new(1_600),
+            time: Timestamp::from_seconds(100),
+            height: 7,
+        };
+        let mut refresh = NavRefresh {
+            id: 1,
+            deadline: Timestamp::from_seconds(200),
+            awaiting: vec![],
+            positions: vec![position("a"), position("b")],
+        };
+        callback_update_stable_amt(&mut storage, &mut state, &mut refresh).unwrap();
+
+        // The lend already executed on a, but it is not counted a second time
+        let a = OUTPOST_NAV.load(&storage, "a").unwrap();
+        assert_eq!(
+            (a.idle, a.lent, a.pending),
+            (Uint128::new(100), Uint128::new(1_000), Uint128::new(500))
+        );
+        assert_eq!(
+            (a.last_updated, a.last_updated_height),
+            (Timestamp::from_seconds(100), 7)
+        );
+        let b = OUTPOST_NAV.load(&storage, "b").unwrap();
+        assert_eq!((b.idle, b.lent), (Uint128::zero(), Uint128::new(1_600)));
+        assert_eq!(state.total_stables, Uint128::new(3_200));
+    }
 }
@@ -4,7 +4,10 @@ mod error;
 pub mod handler;
 pub mod helpers;
 pub mod msg;
+pub mod oracle;
 pub mod state;
+#[cfg(test)]
+mod testing;
 
 pub use crate::config::CONFIG;
 pub use crate::error::ContractError;
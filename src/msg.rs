@@ -1,9 +1,16 @@
 use crate::config::ConfigResponse;
 use crate::config::ConfigUpdate;
+use crate::state::{
+    AllocationSimulation, BountyPoolResponse, BufferResponse, ChannelsResponse, Epoch,
+    FailedOperationsResponse, InFlightResponse, NavResponse, PendingRefundsResponse,
+    RebalanceSimulation, RequestResponse, RequestsResponse, WithdrawalQueueResponse,
+};
 use cosmwasm_schema::{cw_serde, QueryResponses};
 use cosmwasm_std::to_json_binary;
 use cosmwasm_std::Addr;
 use cosmwasm_std::Binary;
+use cosmwasm_std::Coin;
+use cosmwasm_std::Decimal;
 use cosmwasm_std::StdResult;
 use cosmwasm_std::Uint128;
 use cw_ica_controller::types::callbacks::IcaControllerCallbackMsg;
@@ -14,44 +21,294 @@ pub struct InstantiateMsg {
     pub owner: Addr,
     pub outposts: Vec<Outpost>,
     pub cw_ica_controller_code_id: u64,
+    pub nav_refresh_timeout: u64,
+    pub remote_chains: Vec<RemoteChain>,
+    pub denoms: Vec<DenomInfo>,
+    pub fin_pairs: Vec<FinPair>,
+    pub epoch_length: Option<u64>,
+    pub buffer_ratio: Option<Decimal>,
+    pub rebalance: Option<RebalanceParams>,
+    pub crank_bounty: Option<Coin>,
+    pub keeper: Option<Addr>,
+    pub operation_expiry: Option<u64>,
+    pub min_request_value: Option<Uint128>,
+}
+
+/// Limits of the yield-driven rebalancer
+#[cw_serde]
+pub struct RebalanceParams {
+    /// Minimum difference between the liquidity rates of two outposts before funds are moved
+    pub min_spread: Decimal,
+    /// Maximum USD value moved by a single rebalance
+    pub max_move: Uint128,
+    /// Seconds to wait between two rebalances
+    pub cooldown: u64,
+}
+
+/// A denom the vault accepts or holds, priced through the Kujira oracle
+#[cw_serde]
+pub struct DenomInfo {
+    /// Denom on Kujira or on an outpost chain
+    pub denom: String,
+    pub decimals: u8,
+    /// Symbol of the denom in the Kujira oracle
+    pub oracle_symbol: String,
+}
+
+/// A Kujira FIN pair deposits in other whitelisted denoms are swapped through
+#[cw_serde]
+pub struct FinPair {
+    pub pair: String,
+    pub denoms: [String; 2],
 }
 
 #[cw_serde]
 pub struct Outpost {
+    /// Chain id of the host chain
+    pub chain: String,
     pub mars_red_bank_contract: String,
     pub cw_ica_controller_contract: String,
     pub channel_open_init_options: ChannelOpenInitOptions,
     pub account_id: Option<String>,
+    /// Interchain account address on the host chain, recorded when the ICA channel opens
+    pub ica_address: Option<String>,
+    /// Kujira channel used for ICS-20 transfers to the host chain
+    pub transfer_channel: String,
+    /// Host chain channel used for ICS-20 transfers back to Kujira
+    pub host_transfer_channel: String,
+    /// Timeout in seconds of ICS-20 transfers between Kujira and the host chain
+    pub transfer_timeout: u64,
+    /// Denom accepted for deposits on Kujira
+    pub base_denom: String,
+    /// Denom of the base asset on the host chain as lent on mars
+    pub host_denom: String,
+    /// Maximum age in seconds of the last position query before deposits and withdrawals are refused
+    pub max_nav_age: Option<u64>,
+    /// Red bank on the host chain queried for the market of the host denom on each NAV refresh
+    pub red_bank_contract: Option<String>,
+    /// Share of the TVL the allocation engine aims for, outposts without one get no `auto` deposits
+    pub target_weight: Option<Decimal>,
+    /// Maximum share of the TVL the outpost may hold after a deposit
+    pub max_exposure: Option<Decimal>,
+    /// Maximum number of deposits, withdrawal legs and moves in flight on the outpost at once
+    pub max_in_flight: Option<u32>,
+    /// Timeouts of the ICA transactions sent to the outpost, none uses the ICA controller's default
+    pub ica_timeouts: Option<IcaTimeouts>,
+}
+
+/// Timeouts in seconds of the ICA transactions sent to an outpost, by the operation they carry.
+/// Operations without a timeout of their own use `default`.
+#[cw_serde]
+#[derive(Default)]
+pub struct IcaTimeouts {
+    pub default: Option<u64>,
+    /// Position queries pricing deposits and refreshing the NAV
+    pub query: Option<u64>,
+    /// Lends of deposits, moved funds and expired payouts
+    pub lend: Option<u64>,
+    /// Reclaims of withdrawals
+    pub withdraw: Option<u64>,
+    /// Payouts of withdrawals and refunds of deposits
+    pub payout: Option<u64>,
+    /// Reclaims of funds moved to another outpost
+    pub move_funds: Option<u64>,
+    /// Batched deposits and withdrawals of an epoch
+    pub epoch: Option<u64>,
+}
+
+/// A chain withdrawals can be paid out to by IBC transfer from Kujira
+#[cw_serde]
+pub struct RemoteChain {
+    /// Chain id of the remote chain
+    pub chain: String,
+    /// Kujira channel of the first hop
+    pub channel: String,
+    /// Channels on the intermediate chains, forwarded through packet-forward-middleware
+    pub hops: Vec<String>,
+    /// Timeout in seconds of each hop
+    pub timeout: u64,
 }
 
 #[cw_serde]
 pub enum ExecuteMsg {
-    // Initiate a deposit from the user to a mars vault on an outpost. This msg will only dispatch an ica query.
+    // Initiate a deposit from the user to a mars vault on an outpost. The funds are transferred to the outpost ICA.
+    // Can also be called through an ibc-hooks wasm memo, in which case a beneficiary must be set.
     Deposit(DepositMsg),
-    // Initiate a withdraw from the user to a mars vault on an outpost. This msg will only dispatch an ica query.
+    // Initiate a withdraw from the user from a mars vault on an outpost. The funds are paid out on Kujira,
+    // on the outpost chain or on a remote chain once they were reclaimed.
     Withdraw(WithdrawMsg),
     // Create a new mars vault on an outpost using the credit-vault functionality.
     CreateVault {},
     // Create a new channel with the ICA controller on an outpost.
-    CreateChannel {},
+    CreateChannel {
+        destination: String,
+    },
     // Close a channel with the ICA controller on an outpost.
     CloseChannel {},
+    /// Adopt the interchain account a reopened channel of an outpost reported instead of the one
+    /// it had. Outposts with such a mismatch refuse new operations until it is resolved.
+    /// Only callable by the owner.
+    AcceptIcaAddress {
+        destination: String,
+    },
+    /// Accept the ownership of the ICA controller of an outpost once its owner transferred it to
+    /// this contract. Controllers instantiated by an earlier version of this contract are owned by
+    /// the owner and need it before their channel can be reopened. Only callable by the owner.
+    AcceptControllerOwnership {
+        destination: String,
+    },
     // Lend / Unlend assets in a mars vault on an outpost.
     MoveFunds(MoveFundsMsg),
     /// The callback message from the ICA controller contract.
     ReceiveIcaCallback(IcaControllerCallbackMsg),
     // Update the contract configuration by the owner
     UpdateConfig(ConfigUpdate),
+    /// Called by ibc-hooks when the funds of a withdrawal arrive back on Kujira. Pays out the owner.
+    ReceiveWithdrawal {
+        id: u64,
+    },
+    /// Query the positions of all active outposts and update the NAV once all of them answered.
+    /// Callable by anyone.
+    RefreshNav {},
+    /// Deposit like `Deposit`, but hold the receipts in a request until they are claimed.
+    RequestDeposit(DepositMsg),
+    /// Redeem receipts for funds on Kujira, held in a request until they are claimed.
+    RequestRedeem {
+        shares: Uint128,
+        min_assets_out: Option<Uint128>,
+    },
+    /// Deliver the receipts or funds of a claimable request to its owner.
+    Claim {
+        id: u64,
+    },
+    /// Cancel a deposit request that was not priced yet and refund its funds.
+    CancelRequest {
+        id: u64,
+    },
+    /// Process the deposits and withdrawals of the current epoch once it ended, a page of them per
+    /// call. Callable by anyone, usually a keeper.
+    ProcessEpoch {},
+    /// Called by ibc-hooks when the withdrawals of an epoch arrive back on Kujira. Pays out their owners.
+    ReceiveEpoch {
+        epoch: u64,
+        destination: String,
+    },
+    /// Retry the partially filled withdrawals waiting in the queue, oldest first.
+    /// Callable by anyone, usually a keeper.
+    ProcessWithdrawalQueue {
+        limit: Option<u32>,
+    },
+    /// Reclaim funds from the outposts until the liquidity buffer on Kujira reaches its target.
+    /// Callable by anyone, usually a keeper.
+    TopUpBuffer {},
+    /// Move lent funds from the outposts with the lowest red bank liquidity rate to those with
+    /// the highest. Callable by anyone, usually a keeper.
+    Rebalance {},
+    /// Run up to `limit` units of pending lifecycle work: processing an ended epoch, refreshing a
+    /// stale NAV, retrying queued withdrawals, settling an expired NAV refresh and rolling back
+    /// failed operations past their deadline.
+    /// Callable by anyone, the caller is paid the crank bounty from the bounty pool if any work was done.
+    Crank {
+        limit: Option<u32>,
+    },
+    /// Donate the funds sent to the pool crank bounties are paid from. Callable by anyone.
+    FundBountyPool {},
+    /// Send the ICA transaction of a failed operation again. Only callable by the owner or keeper.
+    /// `timeout_seconds` overrides the ICA timeout of the outpost for this attempt.
+    RetryOperation {
+        id: u64,
+        timeout_seconds: Option<u64>,
+    },
+    /// Called by ibc-hooks when the funds of a rebalance move arrive on Kujira. Sends them on to
+    /// the outpost they are moved to.
+    ReceiveMove {
+        id: u64,
+    },
+    /// Called by ibc-hooks when the refund of a deposit arrives back on Kujira. Pays out the owner.
+    ReceiveRefund {
+        id: u64,
+    },
+    /// Send a deposit refund that did not arrive before its transfer timed out again.
+    /// Callable by the owner of the deposit, the contract owner or the keeper.
+    RetryRefund {
+        id: u64,
+    },
+    /// Send the payout of a withdrawal that did not arrive before its transfer timed out again.
+    /// After a few attempts the funds are lent again and the owner gets the receipts back.
+    /// Callable by the owner of the withdrawal, the contract owner or the keeper.
+    RetryPayout {
+        id: u64,
+    },
+}
+
+/// Callbacks of the ibc-hooks middleware for ICS-20 transfers sent with an `ibc_callback` memo
+#[cw_serde]
+pub enum SudoMsg {
+    #[serde(rename = "ibc_lifecycle_complete")]
+    IBCLifecycleComplete(IBCLifecycleComplete),
+}
+
+#[cw_serde]
+pub enum IBCLifecycleComplete {
+    #[serde(rename = "ibc_ack")]
+    IBCAck {
+        /// The source channel of the transfer
+        channel: String,
+        /// The sequence number of the transfer packet
+        sequence: u64,
+        /// String encoded version of the ack as seen by OnAcknowledgementPacket(..)
+        ack: String,
+        /// Whether the ack is a success or a failure
+        success: bool,
+    },
+    #[serde(rename = "ibc_timeout")]
+    IBCTimeout {
+        /// The source channel of the transfer
+        channel: String,
+        /// The sequence number of the transfer packet
+        sequence: u64,
+    },
+}
+
+/// Packet-forward-middleware instructions for the next hop of a transfer
+#[cw_serde]
+pub struct Forward {
+    pub receiver: String,
+    pub port: String,
+    pub channel: String,
+    pub timeout: String,
+    pub retries: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<Box<ForwardMemo>>,
+}
+
+#[cw_serde]
+pub struct ForwardMemo {
+    pub forward: Forward,
 }
 
 #[cw_serde]
 pub struct DepositMsg {
+    /// Mars contract of the outpost to deposit to, or `auto` to let the allocation engine choose
     pub destination: String,
+    /// Kujira address credited with the receipts instead of the sender.
+    /// Required for deposits arriving through an ibc-hooks wasm memo.
+    pub beneficiary: Option<String>,
+    /// Minimum amount of the base denom received when the deposit is swapped through FIN
+    pub min_return: Option<Uint128>,
+    /// Minimum receipts credited once the deposit is priced, the deposit is refunded otherwise
+    pub min_shares_out: Option<Uint128>,
 }
 
 #[cw_serde]
 pub struct WithdrawMsg {
     pub amount: Uint128,
+    /// Address on `remote_chain` that receives the funds
+    pub recipient: Option<String>,
+    /// Chain id of an outpost chain or a configured remote chain to pay out to instead of Kujira
+    pub remote_chain: Option<String>,
+    /// Minimum amount of the base denom paid out for the receipts
+    pub min_assets_out: Option<Uint128>,
 }
 
 #[cw_serde]
@@ -85,11 +342,64 @@ impl CallbackType {
 pub enum QueryMsg {
     #[returns(ConfigResponse)]
     Config {},
-}
-
-#[cw_serde]
-pub struct Positions {
-    pub account_id: String,
+    /// The value held on each outpost and the age of its last position query
+    #[returns(NavResponse)]
+    Nav {},
+    /// The epoch deposits and withdrawals are currently collected in
+    #[returns(Epoch)]
+    Epoch {},
+    #[returns(RequestResponse)]
+    Request { owner: String, id: u64 },
+    /// The pending and claimable requests of an owner
+    #[returns(RequestsResponse)]
+    Requests {
+        owner: String,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// The funds kept idle on Kujira to pay withdrawals at once, and the value they are topped up to
+    #[returns(BufferResponse)]
+    Buffer {},
+    /// The partially filled withdrawals waiting for liquidity, oldest first.
+    /// `start` is the position in the queue to list from.
+    #[returns(WithdrawalQueueResponse)]
+    WithdrawalQueue {
+        start: Option<u32>,
+        limit: Option<u32>,
+    },
+    /// Previews a deposit of `amount`: the outpost it is routed to, the messages sent on Kujira and
+    /// by the ICA, and the weights and blended APY of the outposts before and after.
+    /// Without a destination the deposit is routed like an `auto` deposit.
+    #[returns(AllocationSimulation)]
+    SimulateAllocation {
+        amount: Coin,
+        destination: Option<String>,
+    },
+    /// Previews the moves of a rebalance, their messages and the weights and blended APY
+    /// of the outposts before and after
+    #[returns(RebalanceSimulation)]
+    SimulateRebalance {},
+    /// The ICA transactions the host chains failed, with their error, oldest first
+    #[returns(FailedOperationsResponse)]
+    FailedOperations {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// The ICA channel of each outpost, pending reopens and ICA address mismatches
+    #[returns(ChannelsResponse)]
+    Channels {},
+    /// Operations in flight on each outpost and the number it accepts at once
+    #[returns(InFlightResponse)]
+    InFlight {},
+    /// The funds left in the pool crank bounties are paid from
+    #[returns(BountyPoolResponse)]
+    BountyPool {},
+    /// Refunds of deposits that did not arrive on Kujira yet, by deposit id
+    #[returns(PendingRefundsResponse)]
+    PendingRefunds {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
 }
 
 // Create Credit Account
@@ -0,0 +1,74 @@
+use cosmwasm_std::{Coin, Decimal, QuerierWrapper, QueryRequest, StdResult, Uint128};
+use kujira::{ExchangeRateResponse, KujiraQuery, OracleQuery};
+
+use crate::{config::Config, error::ContractError};
+
+/// Decimals of the USD unit the vault accounts in
+pub const USD_DECIMALS: u32 = 6;
+
+/// Queries the USD price of one whole token of `symbol` from the Kujira oracle
+pub fn exchange_rate(querier: &QuerierWrapper<KujiraQuery>, symbol: &str) -> StdResult<Decimal> {
+    let response: ExchangeRateResponse = querier.query(&QueryRequest::Custom(
+        KujiraQuery::Oracle(OracleQuery::ExchangeRate {
+            denom: symbol.to_string(),
+        }),
+    ))?;
+    Ok(response.rate)
+}
+
+/// Converts `amount` base units of a token with `decimals` into the USD unit
+pub fn to_usd(amount: Uint128, rate: Decimal, decimals: u8) -> Uint128 {
+    let whole = Decimal::from_ratio(amount, 10u128.pow(decimals as u32));
+    Uint128::new(10u128.pow(USD_DECIMALS)) * (whole * rate)
+}
+
+/// Converts a value in the USD unit into base units of a token with `decimals`
+pub fn from_usd(value: Uint128, rate: Decimal, decimals: u8) -> Result<Uint128, ContractError> {
+    let usd = Decimal::from_ratio(value, 10u128.pow(USD_DECIMALS));
+    let whole = usd
+        .checked_div(rate)
+        .map_err(|_| ContractError::InvalidAmount {})?;
+    Ok(Uint128::new(10u128.pow(decimals as u32)) * whole)
+}
+
+/// Values `coin` in the USD unit through the oracle symbol of its whitelisted denom
+pub fn usd_value(
+    querier: &QuerierWrapper<KujiraQuery>,
+    config: &Config,
+    coin: &Coin,
+) -> Result<Uint128, ContractError> {
+    let info = config.find_denom(&coin.denom)?;
+    let rate = exchange_rate(querier, &info.oracle_symbol)?;
+    Ok(to_usd(coin.amount, rate, info.decimals))
+}
+
+/// Amount of the whitelisted `denom` worth `value` in the USD unit
+pub fn amount_for_usd(
+    querier: &QuerierWrapper<KujiraQuery>,
+    config: &Config,
+    denom: &str,
+    value: Uint128,
+) -> Result<Uint128, ContractError> {
+    let info = config.find_denom(denom)?;
+    let rate = exchange_rate(querier, &info.oracle_symbol)?;
+    from_usd(value, rate, info.decimals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usd_conversion() {
+        // 1.5 tokens with 18 decimals at $2 are worth $3
+        let amount = Uint128::new(1_500_000_000_000_000_000);
+        let rate = Decimal::percent(200);
+        let value = to_usd(amount, rate, 18);
+        assert_eq!(value, Uint128::new(3_000_000));
+        assert_eq!(from_usd(value, rate, 18).unwrap(), amount);
+
+        // Stables with 6 decimals at $1 map one to one
+        let value = to_usd(Uint128::new(1_234_567), Decimal::one(), 6);
+        assert_eq!(value, Uint128::new(1_234_567));
+    }
+}
@@ -1,6 +1,11 @@
 use cosmwasm_schema::cw_serde;
-use cosmwasm_std::{Addr, Decimal, Deps, DepsMut, Response, StdResult, Uint128};
-use cw_storage_plus::{Item, Map};
+use cosmwasm_std::{
+    Addr, Coin, CosmosMsg, Decimal, Deps, DepsMut, Empty, IbcOrder, Order, QueryRequest, Response,
+    StdResult, Storage, Timestamp, Uint128,
+};
+use cw_storage_plus::{Deque, Item, Map};
+
+use crate::msg::{ForwardMemo, Outpost};
 
 #[cw_serde]
 pub struct State {
@@ -12,9 +17,844 @@ pub struct State {
     pub deposit_redemption_rate: Decimal,
 }
 
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            total_stables: Uint128::zero(),
+            virtual_receipt: Uint128::zero(),
+            deposit_redemption_rate: Decimal::one(),
+        }
+    }
+}
+
+impl State {
+    /// Recomputes `total_stables` as the sum of all outpost values and the buffer on Kujira,
+    /// and refreshes the redemption rate.
+    pub fn sync_nav(&mut self, storage: &dyn Storage) -> StdResult<()> {
+        self.total_stables = OUTPOST_NAV
+            .range(storage, None, None, Order::Ascending)
+            .map(|item| item.map(|(_, nav)| nav.total()))
+            .sum::<StdResult<Uint128>>()?
+            + buffer_value(storage)?;
+        self.update_redemption_rate();
+        Ok(())
+    }
+
+    /// Receipt tokens worth `amount` at the current NAV. The first deposit is minted 1:1.
+    pub fn shares_for(&self, amount: Uint128) -> Uint128 {
+        if self.virtual_receipt.is_zero() || self.total_stables.is_zero() {
+            amount
+        } else {
+            amount.multiply_ratio(self.virtual_receipt, self.total_stables)
+        }
+    }
+
+    /// Amount of the base asset that `shares` receipt tokens redeem for at the current NAV.
+    pub fn assets_for(&self, shares: Uint128) -> Uint128 {
+        if self.virtual_receipt.is_zero() {
+            Uint128::zero()
+        } else {
+            shares.multiply_ratio(self.total_stables, self.virtual_receipt)
+        }
+    }
+
+    pub fn update_redemption_rate(&mut self) {
+        self.deposit_redemption_rate = if self.virtual_receipt.is_zero() {
+            Decimal::one()
+        } else {
+            Decimal::from_ratio(self.total_stables, self.virtual_receipt)
+        };
+    }
+}
+
 /// Storage for the State
 pub const STATE: Item<State> = Item::new("state");
 
+/// Value held by the vault on a single outpost in USD (6 decimals)
+#[cw_serde]
+#[derive(Default)]
+pub struct OutpostNav {
+    /// Funds deposited in the credit account but not lent out
+    pub idle: Uint128,
+    /// Funds lent to the red bank
+    pub lent: Uint128,
+    /// Funds on their way to or from the outpost that no position query has seen yet
+    pub pending: Uint128,
+    /// Block time of the last position query
+    pub last_updated: Timestamp,
+    /// Host chain height at which the last position query was answered
+    pub last_updated_height: u64,
+}
+
+impl OutpostNav {
+    pub fn total(&self) -> Uint128 {
+        self.idle + self.lent + self.pending
+    }
+
+    /// Replaces the known position of the outpost with the result of a fresh position query.
+    pub fn record_positions(&mut self, idle: Uint128, lent: Uint128, time: Timestamp, height: u64) {
+        self.idle = idle;
+        self.lent = lent;
+        self.last_updated = time;
+        self.last_updated_height = height;
+    }
+
+    pub fn age(&self, now: Timestamp) -> u64 {
+        now.seconds().saturating_sub(self.last_updated.seconds())
+    }
+}
+
+/// Map of outpost (mars contract address) to the value held there
+pub const OUTPOST_NAV: Map<&str, OutpostNav> = Map::new("outpost_nav");
+
+/// Funds kept idle in the controller on Kujira to pay withdrawals without a round trip
+#[cw_serde]
+#[derive(Default)]
+pub struct Buffer {
+    /// Amount of the denom held on Kujira
+    pub amount: Uint128,
+    /// USD value of `amount` when the buffer last changed
+    pub value: Uint128,
+    /// USD value reclaimed from the outposts for the buffer that has not arrived yet
+    pub incoming: Uint128,
+}
+
+/// Buffers keyed by the base denom they hold
+pub const BUFFERS: Map<&str, Buffer> = Map::new("buffers");
+
+/// USD value of all buffers, including top-ups on their way to Kujira
+pub fn buffer_value(storage: &dyn Storage) -> StdResult<Uint128> {
+    BUFFERS
+        .range(storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(_, buffer)| buffer.value + buffer.incoming))
+        .sum()
+}
+
+#[cw_serde]
+pub struct BufferResponse {
+    /// USD value the buffers are topped up to
+    pub target: Uint128,
+    /// USD value of all buffers, including top-ups on their way to Kujira
+    pub value: Uint128,
+    pub buffers: Vec<DenomBuffer>,
+}
+
+#[cw_serde]
+pub struct DenomBuffer {
+    pub denom: String,
+    pub buffer: Buffer,
+}
+
+/// Red bank market of the host denom of an outpost, cached from its latest market query
+#[cw_serde]
+pub struct OutpostMarket {
+    /// Amount of the host denom that can be reclaimed from the market right now
+    pub available_liquidity: Uint128,
+    /// Rate earned by lenders of the market
+    pub liquidity_rate: Decimal,
+    pub updated_at: Timestamp,
+}
+
+/// Map of outpost (mars contract address) to the market of its host denom
+pub const OUTPOST_MARKETS: Map<&str, OutpostMarket> = Map::new("outpost_markets");
+
+/// Positions reported by an outpost during a NAV refresh
+#[cw_serde]
+pub struct OutpostPosition {
+    pub destination: String,
+    pub idle: Uint128,
+    pub lent: Uint128,
+    pub time: Timestamp,
+    pub height: u64,
+}
+
+/// A NAV refresh across all active outposts
+#[cw_serde]
+pub struct NavRefresh {
+    pub id: u64,
+    /// Time after which the refresh settles with the positions received so far
+    pub deadline: Timestamp,
+    /// Outposts that have not answered yet
+    pub awaiting: Vec<String>,
+    /// Positions received so far, applied together once the refresh settles
+    pub positions: Vec<OutpostPosition>,
+}
+
+impl NavRefresh {
+    pub fn in_progress(&self) -> bool {
+        !self.awaiting.is_empty()
+    }
+
+    pub fn expired(&self, now: Timestamp) -> bool {
+        now > self.deadline
+    }
+}
+
+/// Storage for the latest NAV refresh
+pub const NAV_REFRESH: Item<NavRefresh> = Item::new("nav_refresh");
+
+#[cw_serde]
+pub struct OutpostNavResponse {
+    pub destination: String,
+    pub idle: Uint128,
+    pub lent: Uint128,
+    pub pending: Uint128,
+    pub total: Uint128,
+    pub last_updated: Timestamp,
+    pub last_updated_height: u64,
+    /// Seconds since the last position query
+    pub age: u64,
+}
+
+impl OutpostNavResponse {
+    pub fn new(destination: String, nav: OutpostNav, now: Timestamp) -> Self {
+        Self {
+            destination,
+            idle: nav.idle,
+            lent: nav.lent,
+            pending: nav.pending,
+            total: nav.total(),
+            last_updated: nav.last_updated,
+            last_updated_height: nav.last_updated_height,
+            age: nav.age(now),
+        }
+    }
+}
+
+#[cw_serde]
+pub struct NavResponse {
+    pub total_stables: Uint128,
+    pub virtual_receipt: Uint128,
+    pub deposit_redemption_rate: Decimal,
+    pub outposts: Vec<OutpostNavResponse>,
+}
+
+/// Counter for the ids of deposits and withdrawals in flight
+pub const NEXT_OPERATION_ID: Item<u64> = Item::new("next_operation_id");
+
+pub fn next_operation_id(storage: &mut dyn Storage) -> StdResult<u64> {
+    let id = NEXT_OPERATION_ID.may_load(storage)?.unwrap_or_default();
+    NEXT_OPERATION_ID.save(storage, &(id + 1))?;
+    Ok(id)
+}
+
+#[cw_serde]
+pub enum DepositStage {
+    /// The funds are being transferred to the ICA on the host chain
+    Transferring,
+    /// The funds sit in the ICA until the epoch they arrived in is processed
+    Queued,
+    /// The positions of the outpost are queried to price the deposit
+    Pricing,
+    /// The funds are being deposited and lent on mars
+    Lending,
+}
+
+#[cw_serde]
+pub struct PendingDeposit {
+    pub owner: Addr,
+    pub coin: Coin,
+    /// USD value of the coin, set once the deposit is priced
+    pub value: Uint128,
+    /// Receipts minted for the coin once it is priced
+    pub shares: Uint128,
+    pub destination: String,
+    pub min_shares_out: Option<Uint128>,
+    pub stage: DepositStage,
+    /// Epoch the deposit is batched in, if epochs are enabled
+    pub epoch: Option<u64>,
+    pub created_at: Timestamp,
+}
+
+impl PendingDeposit {
+    /// A deposit of `coin` in the base denom of `outpost` that is about to be transferred
+    pub fn new(
+        owner: Addr,
+        coin: Coin,
+        outpost: &Outpost,
+        min_shares_out: Option<Uint128>,
+        created_at: Timestamp,
+    ) -> Self {
+        Self {
+            owner,
+            coin,
+            value: Uint128::zero(),
+            shares: Uint128::zero(),
+            destination: outpost.mars_red_bank_contract.clone(),
+            min_shares_out,
+            stage: DepositStage::Transferring,
+            epoch: None,
+            created_at,
+        }
+    }
+}
+
+/// Deposits that have not been lent on their outpost yet
+pub const PENDING_DEPOSITS: Map<u64, PendingDeposit> = Map::new("pending_deposits");
+
+/// A deposit being swapped into the base denom of its outpost through FIN
+#[cw_serde]
+pub struct PendingSwap {
+    /// Operation id of the deposit the swap is part of
+    pub id: u64,
+    pub owner: Addr,
+    pub destination: String,
+    pub offer: Coin,
+    /// Balance of the base denom held by the contract before the swap
+    pub balance_before: Uint128,
+    pub min_return: Option<Uint128>,
+    pub min_shares_out: Option<Uint128>,
+}
+
+/// Swap currently being dispatched, until its reply creates the pending deposit
+pub const SWAP_IN_DISPATCH: Item<PendingSwap> = Item::new("swap_in_dispatch");
+
+#[cw_serde]
+pub enum WithdrawStage {
+    /// The receipts are escrowed until the epoch of the withdrawal is processed
+    Queued,
+    /// The funds are being reclaimed on mars and sent back by the ICA
+    Reclaiming,
+    /// The reclaim failed, the red bank market is queried for the liquidity it can supply
+    Sizing,
+    /// The funds are on their way back to Kujira
+    Returning,
+    /// The funds are being transferred to a remote chain
+    PayingOut,
+    /// The payout failed for good and the reclaimed funds are lent on the outpost again
+    Relending,
+}
+
+/// Where the funds of a withdrawal are paid out
+#[cw_serde]
+pub enum Payout {
+    /// Paid to the owner on Kujira
+    Local,
+    /// Sent by the ICA to an address on the outpost chain
+    Host { chain: String, recipient: String },
+    /// Transferred from Kujira to an address on a remote chain
+    Remote { chain: String, recipient: String },
+    /// Transferred back to Kujira to top up the buffer
+    Buffer,
+}
+
+#[cw_serde]
+pub struct PendingWithdrawal {
+    pub owner: Addr,
+    /// Id of the redeem request the withdrawal is a leg of
+    pub request_id: u64,
+    /// Receipt tokens burned for the withdrawal
+    pub shares: Uint128,
+    /// Amount of the base asset owed to the owner
+    pub amount: Uint128,
+    /// USD value taken out of the outpost NAV for the withdrawal
+    pub value: Uint128,
+    /// Part of the amount reclaimed from the lent funds, the rest is idle in the credit account
+    pub reclaim: Uint128,
+    pub destination: String,
+    pub payout: Payout,
+    pub min_assets_out: Option<Uint128>,
+    pub stage: WithdrawStage,
+    /// Epoch the withdrawal is batched in, if epochs are enabled
+    pub epoch: Option<u64>,
+    pub created_at: Timestamp,
+    /// Timeout of the transfer back to Kujira once it was sent, after it the funds either arrived
+    /// or are back on the ICA
+    pub expires_at: Option<Timestamp>,
+    /// Number of times the transfer was sent again
+    pub retries: u32,
+}
+
+/// Withdrawals that have not been paid out yet
+pub const PENDING_WITHDRAWALS: Map<u64, PendingWithdrawal> = Map::new("pending_withdrawals");
+
+/// The part of a withdrawal the outposts could not reclaim, retried once they have liquidity again
+#[cw_serde]
+pub struct QueuedWithdrawal {
+    pub owner: Addr,
+    /// Id of the redeem request the withdrawal is a leg of
+    pub request_id: u64,
+    /// Receipt tokens escrowed until the withdrawal is retried
+    pub shares: Uint128,
+    pub payout: Payout,
+    pub queued_at: Timestamp,
+}
+
+/// Partially filled withdrawals waiting for liquidity, oldest first
+pub const WITHDRAWAL_QUEUE: Deque<QueuedWithdrawal> = Deque::new("withdrawal_queue");
+
+#[cw_serde]
+pub struct WithdrawalQueueResponse {
+    /// Number of withdrawals in the queue
+    pub length: u32,
+    pub withdrawals: Vec<QueuedWithdrawal>,
+}
+
+#[cw_serde]
+pub enum TransferKind {
+    /// Funds of a pending deposit sent to the ICA of its outpost
+    Deposit,
+    /// Funds of a pending withdrawal paid out to another chain
+    Withdrawal,
+    /// Funds of a rebalance move sent to the ICA of the outpost they are moved to
+    Move,
+}
+
+/// An ICS-20 transfer sent by this contract with an ibc-hooks callback
+#[cw_serde]
+pub struct PendingTransfer {
+    pub kind: TransferKind,
+    pub operation_id: u64,
+    pub channel: String,
+    pub to_address: String,
+    pub coin: Coin,
+    /// Packet forwarding instructions for transfers to chains without a direct channel
+    pub forward: Option<ForwardMemo>,
+    /// Timeout of the transfer in seconds
+    pub timeout: u64,
+    /// Kujira address that receives the funds if the transfer fails for good
+    pub refund_address: Addr,
+    /// Number of times the transfer was sent again after a timeout
+    pub retries: u32,
+}
+
+/// Outgoing ICS-20 transfers keyed by (source channel, sequence)
+pub const TRANSFERS: Map<(&str, u64), PendingTransfer> = Map::new("transfers");
+
+/// Transfers being dispatched in order, until their replies report the packet sequence
+pub const TRANSFERS_IN_DISPATCH: Deque<PendingTransfer> = Deque::new("transfers_in_dispatch");
+
+/// A refunded deposit the ICA of its outpost transfers back to this contract. The owner is paid
+/// once the funds arrive through the `ReceiveRefund` hook.
+#[cw_serde]
+pub struct PendingRefund {
+    pub owner: Addr,
+    pub destination: String,
+    /// Amount of the base asset refunded
+    pub amount: Uint128,
+    /// Timeout of the transfer, after it the funds either arrived or are back on the ICA
+    pub expires_at: Timestamp,
+    /// Number of times the refund was sent again
+    pub retries: u32,
+}
+
+/// Deposit refunds on their way back to Kujira, keyed by deposit id
+pub const PENDING_REFUNDS: Map<u64, PendingRefund> = Map::new("pending_refunds");
+
+#[cw_serde]
+pub struct PendingRefundResponse {
+    pub id: u64,
+    pub refund: PendingRefund,
+}
+
+#[cw_serde]
+pub struct PendingRefundsResponse {
+    pub refunds: Vec<PendingRefundResponse>,
+}
+
+/// A period deposits and withdrawals are collected in before they are processed together
+#[cw_serde]
+pub struct Epoch {
+    pub id: u64,
+    pub started_at: Timestamp,
+    /// The NAV is being measured to process the epoch
+    pub closing: bool,
+}
+
+impl Epoch {
+    pub fn new(id: u64, started_at: Timestamp) -> Self {
+        Self {
+            id,
+            started_at,
+            closing: false,
+        }
+    }
+
+    /// Epoch that deposits and withdrawals arriving now are collected in
+    pub fn open_id(&self) -> u64 {
+        if self.closing {
+            self.id + 1
+        } else {
+            self.id
+        }
+    }
+
+    pub fn ended(&self, now: Timestamp, length: u64) -> bool {
+        now >= self.started_at.plus_seconds(length)
+    }
+}
+
+pub const EPOCH: Item<Epoch> = Item::new("epoch");
+
+/// Deposits batched in an epoch, keyed by (epoch, deposit id)
+pub const EPOCH_DEPOSITS: Map<(u64, u64), Empty> = Map::new("epoch_deposits");
+
+/// Progress of the settlement of a closing epoch, whose requests are priced a page at a time
+#[cw_serde]
+pub struct EpochSettlement {
+    /// NAV the epoch ended with, every request of the epoch is priced at it
+    pub nav: State,
+    /// Last deposit priced, deposits are priced before withdrawals as those can be paid out of them
+    pub last_deposit: Option<u64>,
+    pub deposits_done: bool,
+    /// Last withdrawal priced
+    pub last_withdrawal: Option<u64>,
+    /// Receipts minted for the deposits and burned for the withdrawals priced so far, booked into
+    /// the NAV together with the flows once every request is priced
+    pub minted: Uint128,
+    pub burned: Uint128,
+}
+
+pub const EPOCH_SETTLEMENT: Item<EpochSettlement> = Item::new("epoch_settlement");
+
+/// Flows of a single outpost within an epoch
+#[cw_serde]
+#[derive(Default)]
+pub struct EpochFlows {
+    /// USD value and amount of the deposits that arrived in the ICA
+    pub deposited_value: Uint128,
+    pub deposited: Uint128,
+    /// USD value and amount of the withdrawals paid out of the outpost
+    pub withdrawn_value: Uint128,
+    pub withdrawn: Uint128,
+    /// Cancelled deposits by id, refunded to their owners on Kujira
+    pub refunds: Vec<(u64, Addr, Uint128)>,
+    /// Withdrawals paid to recipients on the outpost chain
+    pub host_payouts: Vec<(String, Uint128)>,
+    /// Withdrawals transferred back to this contract
+    pub returning: Uint128,
+    /// Amount reclaimed from the lent funds to cover a net outflow
+    pub reclaim: Uint128,
+}
+
+/// Flows of the outposts collected while a closing epoch is settled
+pub const EPOCH_FLOWS: Map<&str, EpochFlows> = Map::new("epoch_flows");
+
+/// An outpost transaction of a processed epoch awaiting its acknowledgement
+#[cw_serde]
+pub struct EpochBatch {
+    /// Value of the net inflow lent on mars, pending until acknowledged
+    pub lent_value: Uint128,
+    /// Value of the net outflow reclaimed from the lent funds
+    pub reclaimed_value: Uint128,
+    /// Value of the net outflow taken from the idle funds
+    pub idle_value: Uint128,
+    /// Amount of the host denom reclaimed from the lent funds
+    pub reclaim: Uint128,
+    /// Deposits of the epoch refunded by the transaction
+    pub refunds: Vec<u64>,
+}
+
+/// ICA transactions of processed epochs keyed by (epoch, destination)
+pub const EPOCH_BATCHES: Map<(u64, &str), EpochBatch> = Map::new("epoch_batches");
+
+/// Withdrawals batched in an epoch, keyed by (epoch, withdrawal id)
+pub const EPOCH_WITHDRAWALS: Map<(u64, u64), Empty> = Map::new("epoch_withdrawals");
+
+#[cw_serde]
+pub enum MoveStage {
+    /// Reclaimed on the outpost the funds leave and on their way to Kujira
+    Returning,
+    /// Transferred from Kujira to the ICA of the outpost they are moved to
+    Transferring,
+    /// Being deposited and lent on mars
+    Lending,
+    /// Back in the ICA of the outpost they left after the transfer to Kujira timed out,
+    /// being lent there again
+    Relending,
+}
+
+/// Lent funds the rebalancer moves from one outpost to another
+#[cw_serde]
+pub struct PendingMove {
+    pub from: String,
+    pub to: String,
+    /// Amount of the base asset moved
+    pub amount: Uint128,
+    /// USD value moved, counted as pending on the outpost it is moved to
+    pub value: Uint128,
+    pub stage: MoveStage,
+    pub created_at: Timestamp,
+    /// Timeout of the transfer to Kujira once the reclaim was acknowledged, after it the funds
+    /// either arrived or are back on the ICA of the outpost they left
+    pub expires_at: Option<Timestamp>,
+}
+
+pub const PENDING_MOVES: Map<u64, PendingMove> = Map::new("pending_moves");
+
+/// Block time of the last rebalance
+pub const LAST_REBALANCE: Item<Timestamp> = Item::new("last_rebalance");
+
+/// An ICA transaction the host chain answered with an error acknowledgement.
+/// It is kept with its messages until it is retried successfully or expires.
+#[cw_serde]
+pub struct FailedOperation {
+    /// ICA controller the transaction was sent through
+    pub controller: String,
+    pub memo: String,
+    pub messages: Vec<CosmosMsg>,
+    pub queries: Vec<QueryRequest>,
+    /// Error of the last failed attempt
+    pub error: String,
+    pub retries: u32,
+    /// A retry was sent and awaits its acknowledgement
+    pub retrying: bool,
+    pub failed_at: Timestamp,
+    /// Time after which the operation is rolled back, none keeps it until it is retried
+    pub deadline: Option<Timestamp>,
+}
+
+pub const FAILED_OPERATIONS: Map<u64, FailedOperation> = Map::new("failed_operations");
+
+/// Id of the failed operation recorded for a packet memo, so a retry that fails again updates it
+pub const FAILED_MEMOS: Map<&str, u64> = Map::new("failed_memos");
+
+#[cw_serde]
+pub struct FailedOperationResponse {
+    pub id: u64,
+    pub operation: FailedOperation,
+}
+
+#[cw_serde]
+pub struct FailedOperationsResponse {
+    pub operations: Vec<FailedOperationResponse>,
+}
+
+/// Operations holding a slot on an outpost from the moment they are sent to it until their last
+/// acknowledgement from it was handled
+#[cw_serde]
+#[derive(Default)]
+pub struct InFlight {
+    pub deposits: u32,
+    /// Withdrawal legs routed to the outpost
+    pub withdrawals: u32,
+    /// Moves leaving or joining the outpost
+    pub moves: u32,
+}
+
+impl InFlight {
+    pub fn total(&self) -> u32 {
+        self.deposits + self.withdrawals + self.moves
+    }
+}
+
+/// Operations in flight by outpost destination
+pub const IN_FLIGHT: Map<&str, InFlight> = Map::new("in_flight");
+
+#[cw_serde]
+pub struct OutpostInFlight {
+    pub destination: String,
+    pub in_flight: InFlight,
+    /// Maximum number of operations the outpost accepts at once, none for no limit
+    pub limit: Option<u32>,
+}
+
+#[cw_serde]
+pub struct InFlightResponse {
+    pub outposts: Vec<OutpostInFlight>,
+}
+
+/// The ICA channel the controller of an outpost last opened
+#[cw_serde]
+pub struct IcaChannel {
+    pub channel_id: String,
+    pub connection_id: String,
+    pub version: String,
+    pub order: IbcOrder,
+}
+
+/// ICA channels by outpost destination
+pub const ICA_CHANNELS: Map<&str, IcaChannel> = Map::new("ica_channels");
+
+/// Block time a channel closed by a packet timeout was reopened, by outpost destination.
+/// Kept until the reopened channel is acknowledged, so it is only requested once.
+pub const CHANNEL_REOPENS: Map<&str, Timestamp> = Map::new("channel_reopens");
+
+/// A reopened channel that reported another interchain account than the one recorded for the
+/// outpost. The recorded address is kept until the outpost is reconfigured.
+#[cw_serde]
+pub struct IcaAddressMismatch {
+    pub expected: String,
+    pub actual: String,
+    pub channel_id: String,
+    pub detected_at: Timestamp,
+}
+
+/// ICA address mismatches by outpost destination
+pub const ICA_MISMATCHES: Map<&str, IcaAddressMismatch> = Map::new("ica_mismatches");
+
+#[cw_serde]
+pub struct OutpostChannel {
+    pub destination: String,
+    pub ica_address: Option<String>,
+    pub channel: Option<IcaChannel>,
+    pub reopen_requested_at: Option<Timestamp>,
+    pub mismatch: Option<IcaAddressMismatch>,
+}
+
+#[cw_serde]
+pub struct ChannelsResponse {
+    pub channels: Vec<OutpostChannel>,
+}
+
+/// Funds held on Kujira by denom that crank bounties are paid from. The vault charges no fees,
+/// the pool is only filled by `FundBountyPool` donations.
+pub const BOUNTY_POOL: Map<&str, Uint128> = Map::new("bounty_pool");
+
+#[cw_serde]
+pub struct BountyPoolResponse {
+    pub funds: Vec<Coin>,
+}
+
+/// Share of the TVL held by an outpost
+#[cw_serde]
+pub struct OutpostWeight {
+    pub destination: String,
+    /// USD value held on the outpost
+    pub value: Uint128,
+    pub weight: Decimal,
+    pub target_weight: Option<Decimal>,
+    /// Cached liquidity rate of its red bank market
+    pub liquidity_rate: Option<Decimal>,
+}
+
+/// Distribution of the TVL over the outposts and the rate it earns
+#[cw_serde]
+pub struct Projection {
+    pub tvl: Uint128,
+    pub weights: Vec<OutpostWeight>,
+    /// Liquidity rates of the outposts weighted by the value lent on each, over the whole TVL
+    pub blended_apy: Decimal,
+}
+
+#[cw_serde]
+pub struct AllocationSimulation {
+    /// Outpost the deposit would be routed to
+    pub destination: String,
+    /// USD value of the deposit
+    pub value: Uint128,
+    /// Part of the deposit kept in the liquidity buffer on Kujira
+    pub buffered: Uint128,
+    /// Amount of the base denom bridged to the outpost, estimated from the oracle after a swap
+    pub bridged: Uint128,
+    /// FIN pair a deposit in another denom is swapped through
+    pub swap_pair: Option<String>,
+    /// Messages the controller sends on Kujira
+    pub messages: Vec<CosmosMsg>,
+    /// Messages the ICA executes on the host chain once the funds arrived
+    pub ica_messages: Vec<CosmosMsg>,
+    pub current: Projection,
+    pub projected: Projection,
+}
+
+#[cw_serde]
+pub struct SimulatedMove {
+    /// Operation id the move would be tracked under
+    pub id: u64,
+    pub from: String,
+    pub to: String,
+    pub value: Uint128,
+    pub amount: Uint128,
+    pub spread: Decimal,
+    /// Messages the ICA of the outpost funds leave executes
+    pub reclaim_messages: Vec<CosmosMsg>,
+    /// Messages the ICA of the outpost funds are moved to executes once they arrived
+    pub lend_messages: Vec<CosmosMsg>,
+}
+
+#[cw_serde]
+pub struct RebalanceSimulation {
+    pub moves: Vec<SimulatedMove>,
+    /// Messages the controller sends on Kujira
+    pub messages: Vec<CosmosMsg>,
+    /// Time the cooldown of the last rebalance ends, if it has not yet
+    pub ready_at: Option<Timestamp>,
+    pub current: Projection,
+    pub projected: Projection,
+}
+
+#[cw_serde]
+pub enum RequestKind {
+    Deposit,
+    Redeem,
+}
+
+#[cw_serde]
+pub enum RequestStatus {
+    /// The deposit or withdrawal is still travelling between Kujira and its outpost
+    Pending,
+    /// Settled, the receipts or assets can be claimed by the owner
+    Claimable,
+    /// The receipts or assets were delivered to the owner
+    Claimed,
+    /// Cancelled by the owner or failed, the funds are refunded
+    Cancelled,
+}
+
+/// A deposit or redemption tracked from its request until its result is delivered.
+/// The request id is the operation id of the underlying deposit or withdrawal.
+#[cw_serde]
+pub struct Request {
+    pub kind: RequestKind,
+    pub status: RequestStatus,
+    /// Requests of plain deposits and withdrawals deliver their result without a claim
+    pub auto_claim: bool,
+    pub destination: String,
+    /// Denom of the assets deposited or redeemed on Kujira
+    pub denom: String,
+    /// Assets deposited, or owed for the redemption
+    pub assets: Uint128,
+    /// Receipts credited for the deposit, or burned for the redemption
+    pub shares: Uint128,
+    /// Withdrawals the redemption is split into that were not delivered yet
+    pub legs: u32,
+    pub created_at: Timestamp,
+}
+
+impl Request {
+    pub fn is_open(&self) -> bool {
+        matches!(
+            self.status,
+            RequestStatus::Pending | RequestStatus::Claimable
+        )
+    }
+}
+
+/// Deposit and redeem requests keyed by (owner, request id)
+pub const REQUESTS: Map<(&Addr, u64), Request> = Map::new("requests");
+
+#[cw_serde]
+pub struct RequestResponse {
+    pub id: u64,
+    pub kind: RequestKind,
+    pub status: RequestStatus,
+    pub destination: String,
+    pub denom: String,
+    pub assets: Uint128,
+    pub shares: Uint128,
+    pub created_at: Timestamp,
+}
+
+impl RequestResponse {
+    pub fn new(id: u64, request: Request) -> Self {
+        Self {
+            id,
+            kind: request.kind,
+            status: request.status,
+            destination: request.destination,
+            denom: request.denom,
+            assets: request.assets,
+            shares: request.shares,
+            created_at: request.created_at,
+        }
+    }
+}
+
+#[cw_serde]
+pub struct RequestsResponse {
+    pub requests: Vec<RequestResponse>,
+}
+
 /// Map to hold the virtual receipts of each user to account for deposits.
 pub const VIRTUAL_RECEIPTS: Map<Addr, Uint128> = Map::new("virtual_receipts");
 
@@ -50,3 +890,45 @@ impl VirtualReceipts {
             .unwrap_or(Uint128::zero()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::MockStorage;
+
+    use super::*;
+
+    #[test]
+    fn test_sync_nav_sums_outposts() {
+        let mut storage = MockStorage::new();
+        let mut state = State::default();
+
+        for (destination, lent) in [("outpost_a", 100u128), ("outpost_b", 50u128)] {
+            let mut nav = OutpostNav::default();
+            nav.record_positions(
+                Uint128::new(10),
+                Uint128::new(lent),
+                Timestamp::from_seconds(1),
+                1,
+            );
+            OUTPOST_NAV.save(&mut storage, destination, &nav).unwrap();
+        }
+        state.virtual_receipt = Uint128::new(85);
+
+        state.sync_nav(&storage).unwrap();
+        assert_eq!(state.total_stables, Uint128::new(170));
+        assert_eq!(state.deposit_redemption_rate, Decimal::percent(200));
+
+        // A second query for the same outpost replaces its value instead of adding to it
+        let mut nav = OUTPOST_NAV.load(&storage, "outpost_a").unwrap();
+        nav.record_positions(
+            Uint128::new(10),
+            Uint128::new(100),
+            Timestamp::from_seconds(2),
+            2,
+        );
+        OUTPOST_NAV.save(&mut storage, "outpost_a", &nav).unwrap();
+
+        state.sync_nav(&storage).unwrap();
+        assert_eq!(state.total_stables, Uint128::new(170));
+    }
+}
@@ -0,0 +1,159 @@
+use cosmwasm_std::testing::MockQuerier;
+use cosmwasm_std::{
+    to_json_binary, Addr, ContractResult, Decimal, Env, IbcEndpoint, IbcPacket, IbcTimeout,
+    Storage, SystemResult, Timestamp, Uint128,
+};
+use cw_ica_controller::types::msg::options::ChannelOpenInitOptions;
+use kujira::{ExchangeRateResponse, KujiraQuery};
+
+use crate::config::Config;
+use crate::handler::ica::IcaPacketData;
+use crate::handler::retry::record_failure;
+use crate::msg::{DenomInfo, Outpost};
+use crate::state::{
+    OutpostNav, Payout, PendingWithdrawal, State, WithdrawStage, FAILED_MEMOS, OUTPOST_NAV,
+};
+
+/// Active outpost on `chain` paying out in `base_denom`
+pub fn mock_outpost(destination: &str, chain: &str, base_denom: &str) -> Outpost {
+    Outpost {
+        chain: chain.to_string(),
+        mars_red_bank_contract: destination.to_string(),
+        cw_ica_controller_contract: format!("{}_controller", destination),
+        channel_open_init_options: ChannelOpenInitOptions {
+            connection_id: "connection-0".to_string(),
+            counterparty_connection_id: "connection-1".to_string(),
+            counterparty_port_id: None,
+            channel_ordering: None,
+        },
+        account_id: Some("1".to_string()),
+        ica_address: Some(format!("{}_ica", destination)),
+        transfer_channel: "channel-0".to_string(),
+        host_transfer_channel: "channel-1".to_string(),
+        transfer_timeout: 600,
+        base_denom: base_denom.to_string(),
+        host_denom: format!("ibc/{}", base_denom),
+        max_nav_age: None,
+        red_bank_contract: None,
+        target_weight: None,
+        max_exposure: None,
+        max_in_flight: None,
+        ica_timeouts: None,
+    }
+}
+
+/// Config with `outposts` and their base and host denoms whitelisted as 6 decimal stables.
+/// Epochs are off, `keeper` may retry and failed operations expire after a minute.
+pub fn mock_config(outposts: Vec<Outpost>) -> Config {
+    let denoms = outposts
+        .iter()
+        .flat_map(|outpost| [outpost.base_denom.clone(), outpost.host_denom.clone()])
+        .map(|denom| DenomInfo {
+            denom,
+            decimals: 6,
+            oracle_symbol: "USDC".to_string(),
+        })
+        .collect();
+    Config {
+        owner: Addr::unchecked("owner"),
+        outposts,
+        cw_ica_controller_code_id: 1,
+        nav_refresh_timeout: 600,
+        remote_chains: vec![],
+        denoms,
+        fin_pairs: vec![],
+        epoch_length: None,
+        buffer_ratio: None,
+        rebalance: None,
+        crank_bounty: None,
+        keeper: Some(Addr::unchecked("keeper")),
+        operation_expiry: Some(60),
+        min_request_value: None,
+    }
+}
+
+/// Querier answering every oracle query with `rate`
+pub fn mock_oracle(rate: Decimal) -> MockQuerier<KujiraQuery> {
+    MockQuerier::<KujiraQuery>::new(&[]).with_custom_handler(move |_| {
+        SystemResult::Ok(ContractResult::Ok(
+            to_json_binary(&ExchangeRateResponse { rate }).unwrap(),
+        ))
+    })
+}
+
+/// Saves the NAV of `destination` with `idle` and `lent` funds
+pub fn save_nav(storage: &mut dyn Storage, destination: &str, idle: u128, lent: u128) {
+    let nav = OutpostNav {
+        idle: Uint128::new(idle),
+        lent: Uint128::new(lent),
+        ..OutpostNav::default()
+    };
+    OUTPOST_NAV.save(storage, destination, &nav).unwrap();
+}
+
+/// State with `receipts` outstanding against the saved outpost NAVs and buffers
+pub fn mock_state(storage: &dyn Storage, receipts: u128) -> State {
+    let mut state = State {
+        virtual_receipt: Uint128::new(receipts),
+        ..State::default()
+    };
+    state.sync_nav(storage).unwrap();
+    state
+}
+
+/// Withdrawal of `amount` for `shares` of bob's receipts, reclaimed from `destination` and
+/// paid out locally
+pub fn mock_withdrawal(destination: &str, shares: u128, amount: u128) -> PendingWithdrawal {
+    PendingWithdrawal {
+        owner: Addr::unchecked("bob"),
+        request_id: 1,
+        shares: Uint128::new(shares),
+        amount: Uint128::new(amount),
+        value: Uint128::new(amount),
+        reclaim: Uint128::zero(),
+        destination: destination.to_string(),
+        payout: Payout::Local,
+        min_assets_out: None,
+        stage: WithdrawStage::Reclaiming,
+        epoch: None,
+        created_at: Timestamp::from_seconds(0),
+        expires_at: None,
+        retries: 0,
+    }
+}
+
+/// Records a failure of the operation sent with `memo` by the controller of `a`
+pub fn fail(storage: &mut dyn Storage, env: &Env, config: &Config, memo: &str) -> u64 {
+    record_failure(
+        storage,
+        env,
+        config,
+        &Addr::unchecked("a_controller"),
+        memo,
+        vec![],
+        vec![],
+        "error".to_string(),
+    )
+    .unwrap();
+    FAILED_MEMOS.load(storage, memo).unwrap()
+}
+
+/// ICA packet without messages sent with `memo`
+pub fn mock_packet(memo: &str) -> IbcPacket {
+    let data = IcaPacketData {
+        messages: vec![],
+        queries: vec![],
+        packet_memo: Some(memo.to_string()),
+    };
+    let endpoint = IbcEndpoint {
+        port_id: "port".to_string(),
+        channel_id: "channel".to_string(),
+    };
+    IbcPacket::new(
+        to_json_binary(&data).unwrap(),
+        endpoint.clone(),
+        endpoint,
+        1,
+        IbcTimeout::with_timestamp(Timestamp::from_seconds(0)),
+    )
+}